@@ -1,8 +1,10 @@
 #[cfg(feature = "parallel_sort")]
 /// parallel ordering extension traits.
+/// These are deprecated in favor of choosing the [`Parallelism`](crate::Parallelism)
+/// on the [`ExtsortConfig`](crate::ExtsortConfig).
 /// This module in only available when the `parallel_sort` feature is enabled
 pub mod parallel;
-/// sequential ordering extension traits
+/// ordering extension traits
 pub mod sequential;
 
 #[cfg(feature = "parallel_sort")]
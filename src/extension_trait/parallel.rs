@@ -1,61 +1,30 @@
 use std::{cmp::Ordering, io};
 
-use rayon::slice::ParallelSliceMut;
-
 use crate::{
-    orderer::{FuncOrderer, KeyOrderer, OrdOrderer, Orderer},
-    sorter::{
-        self, buffer_cleaner::threaded::MultithreadedBufferCleaner, result_iter::ResultIterator,
-        ExtsortConfig,
-    },
+    orderer::{FuncOrderer, KeyOrderer, OrdOrderer},
+    sorter::{self, result_iter::ResultIterator, ExtsortConfig},
+    Parallelism,
 };
 
-/// The specific iterator type returned by
-/// the parallel sorting implementations.
-pub struct ParallelResultIterator<T, O> {
-    inner: ResultIterator<T, O>,
-}
-
-impl<T, O> Iterator for ParallelResultIterator<T, O>
-where
-    O: Orderer<T>,
-{
-    type Item = T;
+/// The iterator type returned by the parallel sorting implementations.
+/// It is the same as the one returned by the sequential implementations.
+#[deprecated = "use ResultIterator instead"]
+pub type ParallelResultIterator<T, O> = ResultIterator<T, O>;
 
-    fn next(&mut self) -> Option<T> {
-        self.inner.next()
+/// sorts in parallel using rayon, unless another form of parallelism was chosen.
+/// This is what the parallel sort methods always did before the parallelism could be chosen.
+fn with_parallel_default(options: ExtsortConfig) -> ExtsortConfig {
+    if options.parallelism == Parallelism::Sequential {
+        options.parallelism(Parallelism::Rayon { threads: 0 })
+    } else {
+        options
     }
-
-    fn size_hint(&self) -> (usize, Option<usize>) {
-        self.inner.size_hint()
-    }
-}
-impl<T, O> ExactSizeIterator for ParallelResultIterator<T, O> where O: Orderer<T> {}
-
-fn buffer_sort<T, O>(orderer: &O, buffer: &mut [T])
-where
-    T: Send,
-    O: Orderer<T> + Sync,
-{
-    buffer.par_sort_unstable_by(|a, b| orderer.compare(a, b));
-}
-
-fn run<T, O>(
-    source: impl Iterator<Item = T>,
-    options: ExtsortConfig,
-    orderer: O,
-) -> io::Result<ParallelResultIterator<T, O>>
-where
-    O: Orderer<T> + Send + Sync,
-    T: Send,
-{
-    let cleaner = MultithreadedBufferCleaner::new(options, orderer, buffer_sort);
-    cleaner.run(move |cleaner_handle| {
-        let inner = sorter::ExtSorter::new().run(source, cleaner_handle)?;
-        Ok(ParallelResultIterator { inner })
-    })
 }
 
+/// The parallel versions of [`ExtSortOrdExtension`](crate::ExtSortOrdExtension).
+///
+/// If the config is left at [`Parallelism::Sequential`], these sort the buffers
+/// in parallel using the global rayon pool, as they did before the parallelism could be chosen.
 pub trait ParallelExtSortOrdExtension: Iterator
 where
     Self::Item: Send,
@@ -66,12 +35,17 @@ where
     /// This function may error if a sort file fails to be written.
     /// In this case the library will do its best to clean up the
     /// already written files, but no guarantee is made.
+    #[deprecated = "use external_sort with Parallelism::Rayon configured instead"]
     fn par_external_sort(
         self,
         options: ExtsortConfig,
-    ) -> io::Result<ParallelResultIterator<Self::Item, OrdOrderer>>;
+    ) -> io::Result<ResultIterator<Self::Item, OrdOrderer>>;
 }
 
+/// The parallel versions of [`ExtSortByExtension`](crate::ExtSortByExtension).
+///
+/// Like [`ParallelExtSortOrdExtension`], these sort the buffers in parallel using rayon
+/// if the config is left at [`Parallelism::Sequential`].
 pub trait ParallelExtSortExtension: Iterator
 where
     Self::Item: Send,
@@ -82,11 +56,12 @@ where
     /// This function may error if a sort file fails to be written.
     /// In this case the library will do its best to clean up the
     /// already written files, but no guarantee is made.
+    #[deprecated = "use external_sort_by with Parallelism::Rayon configured instead"]
     fn par_external_sort_by<F>(
         self,
         options: ExtsortConfig,
         comparator: F,
-    ) -> io::Result<ParallelResultIterator<Self::Item, FuncOrderer<F>>>
+    ) -> io::Result<ResultIterator<Self::Item, FuncOrderer<F>>>
     where
         F: Fn(&Self::Item, &Self::Item) -> Ordering + Send + Sync;
 
@@ -96,11 +71,12 @@ where
     /// This function may error if a sort file fails to be written.
     /// In this case the library will do its best to clean up the
     /// already written files, but no guarantee is made.
+    #[deprecated = "use external_sort_by_key with Parallelism::Rayon configured instead"]
     fn par_external_sort_by_key<F, K>(
         self,
         options: ExtsortConfig,
         key_extractor: F,
-    ) -> io::Result<ParallelResultIterator<Self::Item, KeyOrderer<F>>>
+    ) -> io::Result<ResultIterator<Self::Item, KeyOrderer<F>>>
     where
         F: Fn(&Self::Item) -> K + Send + Sync,
         K: Ord;
@@ -114,8 +90,8 @@ where
     fn par_external_sort(
         self,
         options: ExtsortConfig,
-    ) -> io::Result<ParallelResultIterator<Self::Item, OrdOrderer>> {
-        run(self, options, OrdOrderer::new())
+    ) -> io::Result<ResultIterator<Self::Item, OrdOrderer>> {
+        sorter::sort(self, with_parallel_default(options), OrdOrderer::new())
     }
 }
 
@@ -128,22 +104,30 @@ where
         self,
         options: ExtsortConfig,
         comparator: F,
-    ) -> io::Result<ParallelResultIterator<Self::Item, FuncOrderer<F>>>
+    ) -> io::Result<ResultIterator<Self::Item, FuncOrderer<F>>>
     where
         F: Fn(&Self::Item, &Self::Item) -> Ordering + Send + Sync,
     {
-        run(self, options, FuncOrderer::new(comparator))
+        sorter::sort(
+            self,
+            with_parallel_default(options),
+            FuncOrderer::new(comparator),
+        )
     }
 
     fn par_external_sort_by_key<F, K>(
         self,
         options: ExtsortConfig,
         key_extractor: F,
-    ) -> io::Result<ParallelResultIterator<Self::Item, KeyOrderer<F>>>
+    ) -> io::Result<ResultIterator<Self::Item, KeyOrderer<F>>>
     where
         F: Fn(&Self::Item) -> K + Send + Sync,
         K: Ord,
     {
-        run(self, options, KeyOrderer::new(key_extractor))
+        sorter::sort(
+            self,
+            with_parallel_default(options),
+            KeyOrderer::new(key_extractor),
+        )
     }
 }
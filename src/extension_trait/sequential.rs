@@ -1,20 +1,22 @@
-use std::{
-    cmp::Ordering,
-    io::{self, Read},
-};
+use std::{cmp::Ordering, io};
 
 use crate::{
-    orderer::{FuncOrderer, KeyOrderer, OrdOrderer, Orderer},
-    run::{file_run::ExternalRun, Run},
-    sorter::{
-        self, buffer_cleaner::sequential::SingleThreadedBufferCleaner, result_iter::ResultIterator,
-        ExtsortConfig,
+    orderer::{
+        radix::{RadixKey, RadixKeyOrderer, RadixOrderer},
+        FuncOrderer, KeyOrderer, KeyPrefixOrderer, OrdOrderer, Orderer, PrefixOrderer,
     },
+    run::{Run, TapeRun},
+    sorter::{self, result_iter::ResultIterator, ExtsortConfig},
 };
 
 pub trait ExtSortOrdExtension: Iterator {
     /// Sorts the provided Iterator according to the provided config
-    /// using the native ordering on the type to sort
+    /// using the native ordering on the type to sort.
+    ///
+    /// Items of at least 256 bytes are sorted through their indices when forming the runs,
+    /// unless the buffers are sorted using `Parallelism::Rayon`, so they are only moved once.
+    ///
+    /// The work is distributed across threads as chosen by [`ExtsortConfig::parallelism`].
     /// # Errors
     /// This function may error if a sort file fails to be written.
     /// In this case the library will do its best to clean up the
@@ -23,41 +25,92 @@ pub trait ExtSortOrdExtension: Iterator {
         self,
         options: ExtsortConfig,
     ) -> io::Result<ResultIterator<Self::Item, OrdOrderer>>;
-}
 
-fn buffer_sort<T>(orderer: &impl Orderer<T>, buffer: &mut [T]) {
-    buffer.sort_unstable_by(|a, b| orderer.compare(a, b));
-}
+    /// Sorts the provided Iterator according to the provided config
+    /// using the native ordering on the type to sort, speeding up comparisons
+    /// using a normalized key prefix of each item.
+    ///
+    /// The prefix must be consistent with the ordering: if the prefix of one item
+    /// is smaller than the prefix of another, the item must be smaller as well.
+    /// Items are only compared using their native ordering if their prefixes are equal,
+    /// so the more items it tells apart, the fewer full comparisons are needed.
+    /// A common choice are the first eight bytes of a string in big endian order.
+    ///
+    /// # Errors
+    /// This function may error if a sort file fails to be written.
+    /// In this case the library will do its best to clean up the
+    /// already written files, but no guarantee is made.
+    fn external_sort_with_prefix<P>(
+        self,
+        options: ExtsortConfig,
+        prefix: P,
+    ) -> io::Result<ResultIterator<Self::Item, PrefixOrderer<OrdOrderer, P>>>
+    where
+        P: Fn(&Self::Item) -> u64 + Send + Sync;
 
-fn run<T, O>(
-    source: impl Iterator<Item = T>,
-    options: ExtsortConfig,
-    orderer: O,
-) -> io::Result<ResultIterator<T, O>>
-where
-    O: Orderer<T>,
-{
-    let cleaner = SingleThreadedBufferCleaner::new(options, orderer, buffer_sort);
-    sorter::ExtSorter::new().run(source, cleaner)
+    /// Sorts the provided Iterator according to the provided config
+    /// using the native ordering on the type to sort, forming the runs
+    /// using a radix sort on the normalized keys instead of comparing the items.
+    /// If the normalized keys are at most 8 bytes long, like those of `u32`, `i64` or `f64`,
+    /// the last runs are merged using vectorized merge kernels.
+    ///
+    /// With [`Parallelism::Rayon`](crate::Parallelism::Rayon) configured,
+    /// the radix sort distributes the work across the rayon threads.
+    /// # Errors
+    /// This function may error if a sort file fails to be written.
+    /// In this case the library will do its best to clean up the
+    /// already written files, but no guarantee is made.
+    fn external_sort_radix(
+        self,
+        options: ExtsortConfig,
+    ) -> io::Result<ResultIterator<Self::Item, RadixOrderer>>
+    where
+        Self::Item: RadixKey;
 }
 
 impl<I, T> ExtSortOrdExtension for I
 where
     I: Iterator<Item = T>,
-    T: Ord,
+    T: Ord + Send,
 {
     fn external_sort(
         self,
         options: ExtsortConfig,
     ) -> io::Result<ResultIterator<Self::Item, OrdOrderer>> {
-        run(self, options, OrdOrderer::new())
+        sorter::sort(self, options, OrdOrderer::new())
+    }
+
+    fn external_sort_with_prefix<P>(
+        self,
+        options: ExtsortConfig,
+        prefix: P,
+    ) -> io::Result<ResultIterator<Self::Item, PrefixOrderer<OrdOrderer, P>>>
+    where
+        P: Fn(&Self::Item) -> u64 + Send + Sync,
+    {
+        sorter::sort(self, options, PrefixOrderer::new(OrdOrderer::new(), prefix))
+    }
+
+    fn external_sort_radix(
+        self,
+        options: ExtsortConfig,
+    ) -> io::Result<ResultIterator<Self::Item, RadixOrderer>>
+    where
+        Self::Item: RadixKey,
+    {
+        sorter::sort(self, options, RadixOrderer::new())
     }
 }
 
 pub trait ExtSortByExtension: Iterator {
     type Run: Run<Self::Item>;
     /// Sorts the provided Iterator according to the provided config
-    /// using a custom comparator function
+    /// using a custom comparator function.
+    ///
+    /// Like with [`external_sort`](ExtSortOrdExtension::external_sort),
+    /// items of at least 256 bytes are sorted through their indices when forming the runs.
+    ///
+    /// The work is distributed across threads as chosen by [`ExtsortConfig::parallelism`].
     /// # Errors
     /// This function may error if a sort file fails to be written.
     /// In this case the library will do its best to clean up the
@@ -68,10 +121,21 @@ pub trait ExtSortByExtension: Iterator {
         comparator: F,
     ) -> io::Result<ResultIterator<Self::Item, FuncOrderer<F>>>
     where
-        F: Fn(&Self::Item, &Self::Item) -> Ordering;
+        F: Fn(&Self::Item, &Self::Item) -> Ordering + Send + Sync;
 
     /// Sorts the provided Iterator according to the provided config
     /// using a key extraction function.
+    ///
+    /// When the items are much larger than their keys, the runs are formed by sorting
+    /// (key, index) pairs and moving every item only once. These pairs take up to an eighth
+    /// of the sort buffer on top of it while a buffer is sorted. This is not done
+    /// when the buffers are sorted using `Parallelism::Rayon`.
+    ///
+    /// The runs store the whole items, keys and payloads are not separated in the merge.
+    /// It compares the items at the heads of the runs in place, extracting their keys,
+    /// and moves each item once per merge pass.
+    ///
+    /// The work is distributed across threads as chosen by [`ExtsortConfig::parallelism`].
     /// # Errors
     /// This function may error if a sort file fails to be written.
     /// In this case the library will do its best to clean up the
@@ -82,15 +146,80 @@ pub trait ExtSortByExtension: Iterator {
         key_extractor: F,
     ) -> io::Result<ResultIterator<Self::Item, KeyOrderer<F>>>
     where
-        F: Fn(&Self::Item) -> K,
+        F: Fn(&Self::Item) -> K + Send + Sync,
         K: Ord;
+
+    /// Sorts the provided Iterator according to the provided config
+    /// using a key extraction function, speeding up comparisons
+    /// using a normalized prefix of the keys.
+    ///
+    /// The prefix must be consistent with the keys: if the prefix of one item
+    /// is smaller than the prefix of another, its key must be smaller as well.
+    /// Keys are only extracted and compared if the prefixes are equal,
+    /// see [`external_sort_with_prefix`](ExtSortOrdExtension::external_sort_with_prefix).
+    ///
+    /// # Errors
+    /// This function may error if a sort file fails to be written.
+    /// In this case the library will do its best to clean up the
+    /// already written files, but no guarantee is made.
+    fn external_sort_by_key_with_prefix<F, K, P>(
+        self,
+        options: ExtsortConfig,
+        key_extractor: F,
+        prefix: P,
+    ) -> io::Result<ResultIterator<Self::Item, KeyPrefixOrderer<F, P>>>
+    where
+        F: Fn(&Self::Item) -> K + Send + Sync,
+        K: Ord,
+        P: Fn(&Self::Item) -> u64 + Send + Sync;
+
+    /// Sorts the provided Iterator according to the provided config
+    /// using a key extraction function, forming the runs
+    /// using a radix sort on the normalized keys instead of comparing them.
+    /// The keys of a buffer are extracted once and kept in memory next to it
+    /// while the buffer is sorted.
+    ///
+    /// See [`external_sort_radix`](ExtSortOrdExtension::external_sort_radix).
+    /// # Errors
+    /// This function may error if a sort file fails to be written.
+    /// In this case the library will do its best to clean up the
+    /// already written files, but no guarantee is made.
+    fn external_sort_radix_by_key<F, K>(
+        self,
+        options: ExtsortConfig,
+        key_extractor: F,
+    ) -> io::Result<ResultIterator<Self::Item, RadixKeyOrderer<F>>>
+    where
+        F: Fn(&Self::Item) -> K + Send + Sync,
+        K: RadixKey;
+
+    /// Sorts the provided Iterator according to the provided config
+    /// using the provided [`Orderer`].
+    ///
+    /// Unlike a comparator function, the orderer can hold any state it needs to compare
+    /// the items, and it can speed up the sort using the optional methods of the trait.
+    /// Pass a reference to keep using the orderer after the sort.
+    ///
+    /// The work is distributed across threads as chosen by [`ExtsortConfig::parallelism`].
+    /// # Errors
+    /// This function may error if a sort file fails to be written.
+    /// In this case the library will do its best to clean up the
+    /// already written files, but no guarantee is made.
+    fn external_sort_with<O>(
+        self,
+        options: ExtsortConfig,
+        orderer: O,
+    ) -> io::Result<ResultIterator<Self::Item, O>>
+    where
+        O: Orderer<Self::Item> + Send + Sync;
 }
 
 impl<I, T> ExtSortByExtension for I
 where
     I: Iterator<Item = T>,
+    T: Send,
 {
-    type Run = ExternalRun<T, Box<dyn Read + Send>>;
+    type Run = TapeRun<T>;
 
     fn external_sort_by<F>(
         self,
@@ -98,9 +227,9 @@ where
         comparator: F,
     ) -> io::Result<ResultIterator<Self::Item, FuncOrderer<F>>>
     where
-        F: Fn(&Self::Item, &Self::Item) -> Ordering,
+        F: Fn(&Self::Item, &Self::Item) -> Ordering + Send + Sync,
     {
-        run(self, options, FuncOrderer::new(comparator))
+        sorter::sort(self, options, FuncOrderer::new(comparator))
     }
 
     fn external_sort_by_key<F, K>(
@@ -109,9 +238,47 @@ where
         key_extractor: F,
     ) -> io::Result<ResultIterator<Self::Item, KeyOrderer<F>>>
     where
-        F: Fn(&Self::Item) -> K,
+        F: Fn(&Self::Item) -> K + Send + Sync,
         K: Ord,
     {
-        run(self, options, KeyOrderer::new(key_extractor))
+        sorter::sort(self, options, KeyOrderer::new(key_extractor))
+    }
+
+    fn external_sort_by_key_with_prefix<F, K, P>(
+        self,
+        options: ExtsortConfig,
+        key_extractor: F,
+        prefix: P,
+    ) -> io::Result<ResultIterator<Self::Item, KeyPrefixOrderer<F, P>>>
+    where
+        F: Fn(&Self::Item) -> K + Send + Sync,
+        K: Ord,
+        P: Fn(&Self::Item) -> u64 + Send + Sync,
+    {
+        let orderer = PrefixOrderer::new(KeyOrderer::new(key_extractor), prefix);
+        sorter::sort(self, options, orderer)
+    }
+
+    fn external_sort_radix_by_key<F, K>(
+        self,
+        options: ExtsortConfig,
+        key_extractor: F,
+    ) -> io::Result<ResultIterator<Self::Item, RadixKeyOrderer<F>>>
+    where
+        F: Fn(&Self::Item) -> K + Send + Sync,
+        K: RadixKey,
+    {
+        sorter::sort(self, options, RadixKeyOrderer::new(key_extractor))
+    }
+
+    fn external_sort_with<O>(
+        self,
+        options: ExtsortConfig,
+        orderer: O,
+    ) -> io::Result<ResultIterator<Self::Item, O>>
+    where
+        O: Orderer<Self::Item> + Send + Sync,
+    {
+        sorter::sort(self, options, orderer)
     }
 }
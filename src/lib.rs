@@ -72,7 +72,9 @@ mod sorter;
 mod tape;
 
 pub use extension_trait::*;
-pub use sorter::ExtsortConfig;
+pub use orderer::{radix::RadixKey, Orderer};
+pub use sorter::{ExtsortConfig, Parallelism, WriterThreadBuilder, WriterThreadWrapper};
+pub use tape::{quota::TempLimitExceeded, throttle::IoRateLimiter};
 
 #[cfg(not(miri))]
 #[cfg(test)]
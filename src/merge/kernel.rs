@@ -0,0 +1,600 @@
+use std::ops::Range;
+
+use crate::run::Run;
+
+// This module contains the merge kernels used in place of the loser tree
+// when few runs remain and the normalized key prefixes decide the order on their own.
+// The runs are merged in batches: we take the prefixes of the items the runs hold in memory,
+// merge them along with the index of the run they come from, and then take
+// the items from the runs in the order of the merged run indices.
+
+/// the largest number of runs that are merged using the kernels instead of the loser tree.
+pub(super) const MAX_KERNEL_RUNS: usize = 8;
+
+/// the largest number of items taken from a single run for one batch.
+const BATCH_ITEMS: usize = 512;
+
+/// the instruction set used to merge the keys.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Isa {
+    Scalar,
+    #[cfg(target_arch = "x86_64")]
+    Sse42,
+    #[cfg(target_arch = "x86_64")]
+    Avx2,
+}
+
+impl Isa {
+    /// the best instruction set supported by the cpu we are running on.
+    fn detect() -> Self {
+        #[cfg(all(target_arch = "x86_64", not(miri)))]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return Isa::Avx2;
+            }
+            if is_x86_feature_detected!("sse4.2") {
+                return Isa::Sse42;
+            }
+        }
+        Isa::Scalar
+    }
+}
+
+/// Plans the order in which items are taken from the runs, a batch at a time.
+pub(super) struct MergeKernel {
+    isa: Isa,
+    /// the runs to take the next items from, in order
+    schedule: Vec<u64>,
+    next: usize,
+    // the keys and run indices of the batch being merged,
+    // with the ranges of the sorted segments and the first run they contain.
+    keys: Vec<u64>,
+    tags: Vec<u64>,
+    segments: Vec<(Range<usize>, u64)>,
+    merged_keys: Vec<u64>,
+    merged_segments: Vec<(Range<usize>, u64)>,
+}
+
+impl MergeKernel {
+    pub fn new() -> Self {
+        Self::with_isa(Isa::detect())
+    }
+
+    fn with_isa(isa: Isa) -> Self {
+        Self {
+            isa,
+            schedule: Vec::new(),
+            next: 0,
+            keys: Vec::new(),
+            tags: Vec::new(),
+            segments: Vec::new(),
+            merged_keys: Vec::new(),
+            merged_segments: Vec::new(),
+        }
+    }
+
+    /// returns true if all items of the current batch have been taken
+    pub fn is_done(&self) -> bool {
+        self.next >= self.schedule.len()
+    }
+
+    /// returns the index of the run to take the next item from,
+    /// or None if the batch is done.
+    pub fn next_source(&mut self) -> Option<usize> {
+        let source = *self.schedule.get(self.next)?;
+        self.next += 1;
+        Some(source as usize)
+    }
+
+    /// plans the next batch, using the provided function to get the normalized key of an item.
+    /// Items with equal keys must be equal according to the ordering of the runs.
+    ///
+    /// Returns false, with nothing planned, if an item of the batch has no key.
+    pub fn plan<T, R: Run<T>>(&mut self, runs: &[R], key: impl Fn(&T) -> Option<u64>) -> bool {
+        self.keys.clear();
+        self.tags.clear();
+        self.segments.clear();
+        self.schedule.clear();
+        self.next = 0;
+
+        // the items following a batch are at least as large as its last item,
+        // so every item up to the smallest of the last items can be merged right away.
+        let mut bound = None;
+        for last in runs.iter().filter_map(|run| batch(run).last()) {
+            let Some(last_key) = key(last) else {
+                return false;
+            };
+            bound = Some(bound.map_or(last_key, |bound: u64| bound.min(last_key)));
+        }
+        let Some(bound) = bound else {
+            return true;
+        };
+
+        for (idx, run) in runs.iter().enumerate() {
+            let start = self.keys.len();
+            for item in batch(run) {
+                let Some(item_key) = key(item) else {
+                    self.keys.clear();
+                    self.tags.clear();
+                    return false;
+                };
+                if item_key > bound {
+                    break;
+                }
+                self.keys.push(item_key);
+                self.tags.push(idx as u64);
+            }
+            self.segments.push((start..self.keys.len(), idx as u64));
+        }
+
+        // merge neighbouring segments until a single one remains.
+        // Every segment contains a contiguous range of runs, so the
+        // run indices tell which of two merged segments an item came from.
+        while self.segments.len() > 1 {
+            self.merged_keys.clear();
+            self.schedule.clear();
+            self.merged_segments.clear();
+            for pair in self.segments.chunks(2) {
+                let start = self.merged_keys.len();
+                let (left, first) = &pair[0];
+                match pair.get(1) {
+                    Some((right, boundary)) => merge(
+                        self.isa,
+                        (&self.keys[left.clone()], &self.tags[left.clone()]),
+                        (&self.keys[right.clone()], &self.tags[right.clone()]),
+                        *boundary,
+                        &mut self.merged_keys,
+                        &mut self.schedule,
+                    ),
+                    None => {
+                        self.merged_keys.extend_from_slice(&self.keys[left.clone()]);
+                        self.schedule.extend_from_slice(&self.tags[left.clone()]);
+                    }
+                }
+                self.merged_segments
+                    .push((start..self.merged_keys.len(), *first));
+            }
+            std::mem::swap(&mut self.keys, &mut self.merged_keys);
+            std::mem::swap(&mut self.tags, &mut self.schedule);
+            std::mem::swap(&mut self.segments, &mut self.merged_segments);
+        }
+        std::mem::swap(&mut self.schedule, &mut self.tags);
+        self.next = 0;
+        true
+    }
+}
+
+/// the items of the run we look at for a single batch
+fn batch<T>(run: &impl Run<T>) -> &[T] {
+    let items = run.buffered();
+    &items[..items.len().min(BATCH_ITEMS)]
+}
+
+/// merges the keys of the left and right segments along with their tags.
+/// The segments are sorted by key and then by tag, and all tags of the right segment
+/// are at least the boundary, while those of the left one are smaller.
+/// Sorting by the tags as well lets us tell which items of a segment have been merged
+/// just by counting them.
+fn merge(
+    isa: Isa,
+    (left_keys, left_tags): (&[u64], &[u64]),
+    (right_keys, right_tags): (&[u64], &[u64]),
+    boundary: u64,
+    out_keys: &mut Vec<u64>,
+    out_tags: &mut Vec<u64>,
+) {
+    let left = (left_keys, left_tags);
+    let right = (right_keys, right_tags);
+    // SAFETY: the instruction set has been detected on this cpu.
+    let (mut l, mut r) = match isa {
+        Isa::Scalar => (0, 0),
+        #[cfg(target_arch = "x86_64")]
+        Isa::Sse42 => unsafe {
+            merge_blocks::<x86::Sse42>(left, right, boundary, out_keys, out_tags)
+        },
+        #[cfg(target_arch = "x86_64")]
+        Isa::Avx2 => unsafe {
+            merge_blocks::<x86::Avx2>(left, right, boundary, out_keys, out_tags)
+        },
+    };
+
+    // merge the rest without branching on the comparison.
+    while l < left_keys.len() && r < right_keys.len() {
+        let take_right = right_keys[r] < left_keys[l];
+        let (key, tag) = if take_right {
+            (right_keys[r], right_tags[r])
+        } else {
+            (left_keys[l], left_tags[l])
+        };
+        out_keys.push(key);
+        out_tags.push(tag);
+        l += !take_right as usize;
+        r += take_right as usize;
+    }
+    out_keys.extend_from_slice(&left_keys[l..]);
+    out_tags.extend_from_slice(&left_tags[l..]);
+    out_keys.extend_from_slice(&right_keys[r..]);
+    out_tags.extend_from_slice(&right_tags[r..]);
+}
+
+/// A bitonic merge network.
+trait Network {
+    /// the number of keys in each half of the network
+    const LANES: usize;
+
+    /// merges the sorted halves keys[..LANES] and keys[LANES..2 * LANES]
+    /// into a single sequence sorted by key and tag, moving the tags along with their keys.
+    /// # Safety
+    /// the cpu must support the instructions used by the network.
+    unsafe fn merge(keys: &mut [u64; 8], tags: &mut [u64; 8]);
+}
+
+/// merges the full blocks of both segments using the network,
+/// returning the number of items consumed from the left and the right segment.
+/// # Safety
+/// the cpu must support the instructions used by the network.
+unsafe fn merge_blocks<N: Network>(
+    (left_keys, left_tags): (&[u64], &[u64]),
+    (right_keys, right_tags): (&[u64], &[u64]),
+    boundary: u64,
+    out_keys: &mut Vec<u64>,
+    out_tags: &mut Vec<u64>,
+) -> (usize, usize) {
+    let w = N::LANES;
+    if left_keys.len() < w || right_keys.len() < w {
+        return (0, 0);
+    }
+    let mut keys = [0; 8];
+    let mut tags = [0; 8];
+    keys[..w].copy_from_slice(&left_keys[..w]);
+    tags[..w].copy_from_slice(&left_tags[..w]);
+    keys[w..2 * w].copy_from_slice(&right_keys[..w]);
+    tags[w..2 * w].copy_from_slice(&right_tags[..w]);
+    let (mut l, mut r) = (w, w);
+    loop {
+        N::merge(&mut keys, &mut tags);
+        // the lower half is final, the upper half is merged with the next block.
+        out_keys.extend_from_slice(&keys[..w]);
+        out_tags.extend_from_slice(&tags[..w]);
+
+        // the next block comes from the segment whose next key is smaller.
+        let take_left = match (left_keys.get(l), right_keys.get(r)) {
+            (Some(left), Some(right)) => left <= right,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        let (block_keys, block_tags, pos) = if take_left {
+            (left_keys, left_tags, &mut l)
+        } else {
+            (right_keys, right_tags, &mut r)
+        };
+        if *pos + w > block_keys.len() {
+            break;
+        }
+        keys[..w].copy_from_slice(&block_keys[*pos..*pos + w]);
+        tags[..w].copy_from_slice(&block_tags[*pos..*pos + w]);
+        *pos += w;
+    }
+
+    // the items in the upper half have been loaded, but not written yet.
+    let pending_right = tags[w..2 * w]
+        .iter()
+        .filter(|&&tag| tag >= boundary)
+        .count();
+    (l - (w - pending_right), r - pending_right)
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::Network;
+
+    /// merges four keys with four keys using 256 bit vectors
+    pub struct Avx2;
+
+    /// merges two keys with two keys using 128 bit vectors
+    pub struct Sse42;
+
+    /// returns the lanes in which the left key is greater than the right one,
+    /// using the tags to break ties.
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn greater256(keys: (__m256i, __m256i), tags: (__m256i, __m256i)) -> __m256i {
+        // there is no unsigned comparison, so we flip the sign bits.
+        // The tags are small enough to be compared as signed numbers.
+        let sign = _mm256_set1_epi64x(i64::MIN);
+        let greater = _mm256_cmpgt_epi64(
+            _mm256_xor_si256(keys.0, sign),
+            _mm256_xor_si256(keys.1, sign),
+        );
+        let tie = _mm256_and_si256(
+            _mm256_cmpeq_epi64(keys.0, keys.1),
+            _mm256_cmpgt_epi64(tags.0, tags.1),
+        );
+        _mm256_or_si256(greater, tie)
+    }
+
+    /// exchanges the keys and their tags so that the first pair holds the lane wise minimum.
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn exchange256(
+        keys: (__m256i, __m256i),
+        tags: (__m256i, __m256i),
+    ) -> [(__m256i, __m256i); 2] {
+        let greater = greater256(keys, tags);
+        [
+            (
+                _mm256_blendv_epi8(keys.0, keys.1, greater),
+                _mm256_blendv_epi8(tags.0, tags.1, greater),
+            ),
+            (
+                _mm256_blendv_epi8(keys.1, keys.0, greater),
+                _mm256_blendv_epi8(tags.1, tags.0, greater),
+            ),
+        ]
+    }
+
+    /// compares the lanes paired up by the permutation, moving the smaller key
+    /// of each pair into the lower lane. UPPER selects the upper lanes of the pairs.
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn exchange_lanes256<const PERM: i32, const UPPER: i32>(
+        keys: __m256i,
+        tags: __m256i,
+    ) -> (__m256i, __m256i) {
+        let partner_keys = _mm256_permute4x64_epi64::<PERM>(keys);
+        let partner_tags = _mm256_permute4x64_epi64::<PERM>(tags);
+        let greater = greater256((keys, partner_keys), (tags, partner_tags));
+        // the upper lane of a pair swaps exactly when the lower one does.
+        let swap = _mm256_blend_epi32::<UPPER>(greater, _mm256_permute4x64_epi64::<PERM>(greater));
+        (
+            _mm256_blendv_epi8(keys, partner_keys, swap),
+            _mm256_blendv_epi8(tags, partner_tags, swap),
+        )
+    }
+
+    /// sorts a bitonic sequence of four keys.
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    unsafe fn clean256(keys: __m256i, tags: __m256i) -> (__m256i, __m256i) {
+        // compare the lanes two apart, then the neighbouring lanes
+        let (keys, tags) = exchange_lanes256::<0x4E, 0xF0>(keys, tags);
+        exchange_lanes256::<0xB1, 0xCC>(keys, tags)
+    }
+
+    impl Network for Avx2 {
+        const LANES: usize = 4;
+
+        #[target_feature(enable = "avx2")]
+        unsafe fn merge(keys: &mut [u64; 8], tags: &mut [u64; 8]) {
+            let k = keys.as_mut_ptr().cast::<__m256i>();
+            let t = tags.as_mut_ptr().cast::<__m256i>();
+            let left = (_mm256_loadu_si256(k), _mm256_loadu_si256(t));
+            let right = (_mm256_loadu_si256(k.add(1)), _mm256_loadu_si256(t.add(1)));
+            // reversing the right half makes the whole sequence bitonic
+            let right = (
+                _mm256_permute4x64_epi64::<0x1B>(right.0),
+                _mm256_permute4x64_epi64::<0x1B>(right.1),
+            );
+            let [low, high] = exchange256((left.0, right.0), (left.1, right.1));
+            let low = clean256(low.0, low.1);
+            let high = clean256(high.0, high.1);
+            _mm256_storeu_si256(k, low.0);
+            _mm256_storeu_si256(t, low.1);
+            _mm256_storeu_si256(k.add(1), high.0);
+            _mm256_storeu_si256(t.add(1), high.1);
+        }
+    }
+
+    /// returns the lanes in which the left key is greater than the right one,
+    /// using the tags to break ties.
+    #[inline]
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn greater128(keys: (__m128i, __m128i), tags: (__m128i, __m128i)) -> __m128i {
+        let sign = _mm_set1_epi64x(i64::MIN);
+        let greater = _mm_cmpgt_epi64(_mm_xor_si128(keys.0, sign), _mm_xor_si128(keys.1, sign));
+        let tie = _mm_and_si128(
+            _mm_cmpeq_epi64(keys.0, keys.1),
+            _mm_cmpgt_epi64(tags.0, tags.1),
+        );
+        _mm_or_si128(greater, tie)
+    }
+
+    /// exchanges the keys and their tags so that the first pair holds the lane wise minimum.
+    #[inline]
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn exchange128(
+        keys: (__m128i, __m128i),
+        tags: (__m128i, __m128i),
+    ) -> [(__m128i, __m128i); 2] {
+        let greater = greater128(keys, tags);
+        [
+            (
+                _mm_blendv_epi8(keys.0, keys.1, greater),
+                _mm_blendv_epi8(tags.0, tags.1, greater),
+            ),
+            (
+                _mm_blendv_epi8(keys.1, keys.0, greater),
+                _mm_blendv_epi8(tags.1, tags.0, greater),
+            ),
+        ]
+    }
+
+    /// sorts a bitonic sequence of two keys.
+    #[inline]
+    #[target_feature(enable = "sse4.2")]
+    unsafe fn clean128(keys: __m128i, tags: __m128i) -> (__m128i, __m128i) {
+        let partner_keys = _mm_shuffle_epi32::<0x4E>(keys);
+        let partner_tags = _mm_shuffle_epi32::<0x4E>(tags);
+        // both lanes swap if the lower one is greater.
+        let greater = greater128((keys, partner_keys), (tags, partner_tags));
+        let swap = _mm_shuffle_epi32::<0x44>(greater);
+        (
+            _mm_blendv_epi8(keys, partner_keys, swap),
+            _mm_blendv_epi8(tags, partner_tags, swap),
+        )
+    }
+
+    impl Network for Sse42 {
+        const LANES: usize = 2;
+
+        #[target_feature(enable = "sse4.2")]
+        unsafe fn merge(keys: &mut [u64; 8], tags: &mut [u64; 8]) {
+            let k = keys.as_mut_ptr().cast::<__m128i>();
+            let t = tags.as_mut_ptr().cast::<__m128i>();
+            let left = (_mm_loadu_si128(k), _mm_loadu_si128(t));
+            let right = (
+                _mm_shuffle_epi32::<0x4E>(_mm_loadu_si128(k.add(1))),
+                _mm_shuffle_epi32::<0x4E>(_mm_loadu_si128(t.add(1))),
+            );
+            let [low, high] = exchange128((left.0, right.0), (left.1, right.1));
+            let low = clean128(low.0, low.1);
+            let high = clean128(high.0, high.1);
+            _mm_storeu_si128(k, low.0);
+            _mm_storeu_si128(t, low.1);
+            _mm_storeu_si128(k.add(1), high.0);
+            _mm_storeu_si128(t.add(1), high.1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::Rng;
+
+    use crate::{
+        run::{buf_run::BufRun, Run},
+        ExtSortOrdExtension, ExtsortConfig,
+    };
+
+    use super::{merge, Isa, MergeKernel};
+
+    /// the instruction sets available on this cpu
+    fn supported() -> Vec<Isa> {
+        let mut isas = vec![Isa::Scalar];
+        #[cfg(all(target_arch = "x86_64", not(miri)))]
+        {
+            if is_x86_feature_detected!("sse4.2") {
+                isas.push(Isa::Sse42);
+            }
+            if is_x86_feature_detected!("avx2") {
+                isas.push(Isa::Avx2);
+            }
+        }
+        isas
+    }
+
+    fn sorted_keys(len: usize, max: u64) -> Vec<u64> {
+        let mut rng = rand::thread_rng();
+        let mut keys: Vec<u64> = (0..len).map(|_| rng.gen_range(0..=max)).collect();
+        keys.sort();
+        keys
+    }
+
+    /// a segment of keys with tags from the provided range, sorted by key and tag
+    fn segment(len: usize, max: u64, tags: std::ops::Range<u64>) -> Vec<(u64, u64)> {
+        let mut rng = rand::thread_rng();
+        let mut items: Vec<_> = (0..len)
+            .map(|_| (rng.gen_range(0..=max), rng.gen_range(tags.clone())))
+            .collect();
+        items.sort();
+        items
+    }
+
+    #[test]
+    fn test_merge() {
+        for isa in supported() {
+            for (left_len, right_len, max) in [
+                (0, 10, 100),
+                (3, 5, 10),
+                (7, 6, 5),
+                (100, 37, 1000),
+                (64, 64, 3),
+                (500, 500, u64::MAX),
+            ] {
+                for _ in 0..20 {
+                    let mut left = segment(left_len, max, 0..3);
+                    let right = segment(right_len, max, 3..5);
+                    if let Some(first) = left.first_mut() {
+                        // keys above i64::MAX must not be treated as negative
+                        first.0 = 0;
+                    }
+                    let (left_keys, left_tags): (Vec<_>, Vec<_>) = left.iter().copied().unzip();
+                    let (right_keys, right_tags): (Vec<_>, Vec<_>) = right.iter().copied().unzip();
+                    let mut keys = Vec::new();
+                    let mut tags = Vec::new();
+                    merge(
+                        isa,
+                        (&left_keys, &left_tags),
+                        (&right_keys, &right_tags),
+                        3,
+                        &mut keys,
+                        &mut tags,
+                    );
+
+                    let mut expected: Vec<_> = left.iter().chain(&right).copied().collect();
+                    expected.sort();
+                    let merged: Vec<_> = keys.into_iter().zip(tags).collect();
+                    assert_eq!(expected, merged, "{isa:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        for isa in supported() {
+            for num_runs in 2..=8 {
+                let runs: Vec<Vec<u64>> = (0..num_runs)
+                    .map(|idx| sorted_keys(200 + idx * 150, 2000))
+                    .collect();
+                let mut buf_runs: Vec<_> = runs.iter().cloned().map(BufRun::new).collect();
+                let mut kernel = MergeKernel::with_isa(isa);
+
+                let mut result = Vec::new();
+                while buf_runs.iter().any(|run| run.peek().is_some()) {
+                    assert!(kernel.plan(&buf_runs, |&key| Some(key)));
+                    assert!(!kernel.is_done());
+                    while let Some(source) = kernel.next_source() {
+                        result.push(buf_runs[source].next().unwrap());
+                    }
+                }
+
+                let mut expected: Vec<u64> = runs.into_iter().flatten().collect();
+                expected.sort();
+                assert_eq!(expected, result, "{isa:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_plan_missing_key() {
+        let runs = vec![BufRun::new(vec![1u64, 2, 3]), BufRun::new(vec![2, 4, 6])];
+        let mut kernel = MergeKernel::new();
+        assert!(!kernel.plan(&runs, |&key| (key != 4).then_some(key)));
+        assert!(kernel.is_done());
+        assert!(!kernel.plan(&runs, |&key| (key != 6).then_some(key)));
+        assert!(kernel.is_done());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_primitive_merge_kernels() {
+        // a few runs, so that the merge kernels merge all of them
+        let config = ExtsortConfig::with_buffer_size(2000).temp_file_folder(std::env::temp_dir());
+        let sorted = (0..10_000u32)
+            .map(|i| i * 7919 % 10_000)
+            .external_sort_radix(config)
+            .unwrap();
+        assert!(sorted.eq(0..10_000));
+
+        let config = ExtsortConfig::with_buffer_size(2000).temp_file_folder(std::env::temp_dir());
+        let sorted = (0..10_000i64)
+            .rev()
+            .map(|i| i - 5000)
+            .external_sort_radix(config)
+            .unwrap();
+        assert!(sorted.eq(-5000..5000));
+    }
+}
@@ -1,4 +1,5 @@
 mod array_node;
+mod kernel;
 mod treebuilder;
 
 use std::{cmp::Ordering, marker::PhantomData};
@@ -7,6 +8,7 @@ use crate::{orderer::Orderer, run::Run};
 
 use self::{
     array_node::{TreeNode, Winner},
+    kernel::{MergeKernel, MAX_KERNEL_RUNS},
     treebuilder::LoserTreeBuilder,
 };
 
@@ -15,13 +17,29 @@ use self::{
 /// head is the smallest element.
 pub struct LoserTree<T, R, O> {
     loser_indices: Vec<u32>,
+    /// the normalized key prefixes of the heads of the tapes,
+    /// if the orderer supports them. None for exhausted tapes
+    /// and for heads the orderer has no prefix for.
+    head_prefixes: Option<Vec<Option<u64>>>,
     tapes: Vec<R>,
     orderer: O,
     phantom: PhantomData<T>,
     winner: Winner,
     remaining_tapes: usize,
+    /// the number of times in a row the current winner has won
+    streak: usize,
+    /// the number of items following the head of the winner
+    /// that are known to win without playing any matches
+    gallop: usize,
+    /// merges the runs once few of them remain,
+    /// if the orderer decides the order using the key prefixes alone.
+    kernel: Option<MergeKernel>,
 }
 
+/// after this many wins in a row, we look ahead in the winning run
+/// for items that are sure to win as well.
+const GALLOP_AFTER: usize = 8;
+
 /// returns the largest power of 2 less or equal to the provided number
 fn previous_power_of_two(number: usize) -> usize {
     let leading_zeros = number.leading_zeros();
@@ -36,10 +54,22 @@ fn get_candidate<T>(runs: &[impl Run<T>], candidate: Winner) -> Option<&T> {
 
 fn compare_winners<T>(
     runs: &[impl Run<T>],
+    head_prefixes: Option<&[Option<u64>]>,
     orderer: &impl Orderer<T>,
     left: Winner,
     right: Winner,
 ) -> Ordering {
+    if let Some(prefixes) = head_prefixes {
+        // most matches are decided by the prefixes,
+        // without touching the items themselves.
+        if let (Some(l), Some(r)) = (prefixes[left.idx as usize], prefixes[right.idx as usize]) {
+            if l != r {
+                return l.cmp(&r);
+            }
+        }
+        // the prefixes are equal, or a run is exhausted or its head has no prefix,
+        // so we need to look at the items
+    }
     match (get_candidate(runs, left), get_candidate(runs, right)) {
         (Some(l), Some(r)) => orderer.compare(l, r),
         (Some(_), None) => Ordering::Less,
@@ -57,13 +87,18 @@ where
     /// and a provided ordering instruction.
     pub fn new(tapes: Vec<R>, orderer: O) -> Self {
         let remaining_tapes = tapes.len();
+        let kernel = orderer.exact_prefix().then(MergeKernel::new);
         let mut result = Self {
             loser_indices: Vec::new(),
+            head_prefixes: None,
             remaining_tapes,
             tapes,
             orderer,
             winner: Winner { idx: u32::MAX },
             phantom: PhantomData,
+            streak: 0,
+            gallop: 0,
+            kernel,
         };
 
         result.winner = result.rebuild_tree();
@@ -82,11 +117,27 @@ where
         if self.tapes.len() <= 1 {
             return self.tapes.first_mut()?.next();
         }
+        if self.uses_kernel() {
+            return self.next_from_kernel();
+        }
 
         let winning_tape = &mut self.tapes[self.winner.idx as usize];
         let winning_value = winning_tape.next()?;
+        if let Some(prefixes) = &mut self.head_prefixes {
+            let head = winning_tape
+                .peek()
+                .map(|item| self.orderer.key_prefix(item));
+            prefixes[self.winner.idx as usize] = head.flatten();
+        }
+        if self.gallop > 0 {
+            // the new head is known to beat the heads of all other runs,
+            // so the tree stays just as it is.
+            self.gallop -= 1;
+            return Some(winning_value);
+        }
         let tape_exhausted = winning_tape.peek().is_none();
 
+        let previous_winner = self.winner;
         self.winner = if tape_exhausted {
             // while we surely know that the next result must be a None
             // because the peek call did not return anything,
@@ -100,18 +151,141 @@ where
             self.replay_matches(self.winner)
         };
 
+        if tape_exhausted || self.winner.idx != previous_winner.idx {
+            self.streak = 0;
+        } else {
+            self.streak += 1;
+            if self.streak >= GALLOP_AFTER {
+                self.streak = 0;
+                self.gallop = self.count_sure_wins();
+            }
+        }
+
         Some(winning_value)
     }
 
+    /// moves up to max of the next items to the end of out.
+    ///
+    /// Unlike calling next repeatedly, the items that are known to win
+    /// are moved out of the winning run all at once.
+    pub fn fill(&mut self, out: &mut Vec<T>, max: usize) {
+        let target = out.len() + max;
+        while out.len() < target {
+            if self.gallop > 0 && !self.uses_kernel() {
+                // the tree stays just as it is for the same reason as in next.
+                let count = self.gallop.min(target - out.len());
+                let winning_tape = &mut self.tapes[self.winner.idx as usize];
+                winning_tape.take_buffered(count, out);
+                if let Some(prefixes) = &mut self.head_prefixes {
+                    let head = winning_tape
+                        .peek()
+                        .map(|item| self.orderer.key_prefix(item));
+                    prefixes[self.winner.idx as usize] = head.flatten();
+                }
+                self.gallop -= count;
+                continue;
+            }
+            match self.next() {
+                Some(item) => out.push(item),
+                None => return,
+            }
+        }
+    }
+
+    /// whether the remaining runs are merged by the kernel instead of the tree.
+    fn uses_kernel(&self) -> bool {
+        self.tapes.len() <= MAX_KERNEL_RUNS && self.kernel.is_some()
+    }
+
+    /// takes the next item from the run planned by the merge kernel,
+    /// planning the next batch once the current one is done.
+    fn next_from_kernel(&mut self) -> Option<T> {
+        let kernel = self.kernel.as_mut()?;
+        if kernel.is_done() {
+            let orderer = &self.orderer;
+            if !kernel.plan(&self.tapes, |item| orderer.key_prefix(item)) {
+                // the orderer does not have prefixes for all items after all,
+                // so the tree has to merge the rest.
+                self.kernel = None;
+                self.gallop = 0;
+                self.streak = 0;
+                self.winner = self.rebuild_tree();
+                return self.next();
+            }
+        }
+        let source = kernel.next_source()?;
+        let item = self.tapes[source].next();
+        if kernel.is_done() {
+            // the kernel does not use the tree, but rebuilding it
+            // drops the exhausted runs before the next batch.
+            self.winner = self.rebuild_tree();
+        }
+        item
+    }
+
+    /// counts the items following the head of the winner that will win
+    /// against the heads of all other runs, by galloping through the
+    /// items the winning run holds in memory.
+    fn count_sure_wins(&self) -> usize {
+        // the runner up is the best of the runs that lost against the winner.
+        let mut current_node = self.get_leaf_node(self.winner);
+        let mut runner_up = None;
+        while !current_node.is_root() {
+            current_node = current_node.parent();
+            let loser = Winner {
+                idx: self.loser_indices[current_node.idx],
+            };
+            runner_up = match runner_up {
+                Some(best) if self.compare_winners(best, loser).is_le() => Some(best),
+                _ => Some(loser),
+            };
+        }
+        let Some(runner_up) = runner_up else {
+            return 0;
+        };
+
+        let items = self.tapes[self.winner.idx as usize].buffered();
+        // just like when replaying the matches, the winner keeps winning on ties.
+        let wins = |item: &T| match get_candidate(&self.tapes, runner_up) {
+            Some(bound) => self.orderer.compare(item, bound).is_le(),
+            None => true,
+        };
+
+        // items[..known] are sure to win, the winner at items[0] is among them.
+        // We double the range we check until an item loses,
+        // and then search for the first loser within the last range.
+        let mut known = 1;
+        let mut end = 2;
+        while end <= items.len() && wins(&items[end - 1]) {
+            known = end;
+            end *= 2;
+        }
+        let end = end.min(items.len());
+        let sure_wins = known + items[known..end].partition_point(wins);
+        // the head itself has already won
+        sure_wins - 1
+    }
+
     /// rebuilds the loser tree, returning the new winner leaf
     /// after reconstruction.
     fn rebuild_tree(&mut self) -> Winner {
         self.tapes.retain(|t| t.peek().is_some());
         self.remaining_tapes = self.tapes.len();
 
+        // the prefixes are only used if the orderer provides them.
+        let prefixes: Option<Vec<u64>> = self
+            .tapes
+            .iter()
+            .map(|t| t.peek().and_then(|item| self.orderer.key_prefix(item)))
+            .collect();
+        self.head_prefixes = prefixes.map(|prefixes| prefixes.into_iter().map(Some).collect());
+
         if self.tapes.len() > 1 {
+            let head_prefixes = self.head_prefixes.as_deref();
             LoserTreeBuilder::new(
-                |left, right| compare_winners(&self.tapes, &self.orderer, left, right),
+                |left, right| {
+                    compare_winners(&self.tapes, head_prefixes, &self.orderer, left, right)
+                },
                 &mut self.loser_indices,
             )
             .build(self.tapes.len())
@@ -121,7 +295,13 @@ where
     }
 
     fn compare_winners(&self, left: Winner, right: Winner) -> Ordering {
-        compare_winners(&self.tapes, &self.orderer, left, right)
+        compare_winners(
+            &self.tapes,
+            self.head_prefixes.as_deref(),
+            &self.orderer,
+            left,
+            right,
+        )
     }
 
     /// replay the matches from the previous winner back up to the root.
@@ -261,6 +441,226 @@ mod test {
         run_merge_test(runs);
     }
 
+    #[test]
+    fn test_merge_clustered() {
+        // long streaks of wins, interleaved blocks and a run that dominates the rest
+        let runs = vec![
+            (0..1000).collect(),
+            (1000..2000).collect(),
+            (0..50).map(|i| i * 40).collect(),
+            (500..700).chain(1500..1800).collect(),
+            vec![],
+        ];
+        run_merge_test(runs);
+    }
+
+    #[test]
+    fn test_merge_duplicates() {
+        let runs = vec![vec![1; 100], vec![1; 50], vec![0, 1, 1, 1, 2], vec![2; 30]];
+        run_merge_test(runs);
+    }
+
+    #[test]
+    fn test_gallop_skips_comparisons() {
+        use std::cell::Cell;
+
+        use crate::orderer::FuncOrderer;
+
+        let comparisons = Cell::new(0);
+        let orderer = FuncOrderer::new(|l: &u32, r: &u32| {
+            comparisons.set(comparisons.get() + 1);
+            l.cmp(r)
+        });
+        let runs = vec![
+            BufRun::new((0..10_000).collect()),
+            BufRun::new((10_000..20_000).collect()),
+            BufRun::new((20_000..30_000).collect()),
+        ];
+        let merger = LoserTree::new(runs, orderer);
+        assert!(merger.eq(0..30_000));
+        // without galloping, every item would need at least one comparison
+        assert!(comparisons.get() < 1000, "{}", comparisons.get());
+    }
+
+    #[test]
+    fn test_fill() {
+        use crate::{run::file_run::ExternalRun, tape::vec_to_tape};
+        use std::num::NonZeroUsize;
+
+        let buffer_size = NonZeroUsize::new(64).unwrap();
+        // long stretches of a single run to gallop through, and interleaved items in between
+        let runs = (0..4u32)
+            .map(|run| {
+                let mut items: Vec<u32> = (0..1000u32)
+                    .map(|i| {
+                        if i % 200 < 100 {
+                            i * 4 + run
+                        } else {
+                            run * 10_000 + i
+                        }
+                    })
+                    .collect();
+                items.sort();
+                items
+            })
+            .collect::<Vec<_>>();
+        let mut expected: Vec<u32> = runs.iter().flatten().copied().collect();
+        expected.sort();
+
+        let tapes = runs
+            .into_iter()
+            .map(|items| ExternalRun::from_tape(vec_to_tape(items), buffer_size))
+            .collect();
+        // without prefixes, the tree merges the runs all the way instead of the kernel.
+        let orderer = crate::orderer::FuncOrderer::new(|l: &u32, r: &u32| l.cmp(r));
+        let mut merger = LoserTree::new(tapes, orderer);
+        let mut merged = Vec::new();
+        loop {
+            let len = merged.len();
+            merger.fill(&mut merged, 37);
+            if merged.len() == len {
+                break;
+            }
+            assert!(merged.len() - len <= 37);
+        }
+        assert_eq!(expected, merged);
+    }
+
+    #[test]
+    fn test_prefixes_skip_comparisons() {
+        use std::cell::Cell;
+
+        use crate::orderer::{FuncOrderer, PrefixOrderer};
+
+        let comparisons = Cell::new(0);
+        let counting = FuncOrderer::new(|l: &u32, r: &u32| {
+            comparisons.set(comparisons.get() + 1);
+            l.cmp(r)
+        });
+        // interleaved runs, so that galloping does not help
+        let runs = (0..4)
+            .map(|run| BufRun::new((0..1000).map(|i| i * 4 + run).collect()))
+            .collect();
+
+        // unique prefixes never need the full comparison
+        let merger = LoserTree::new(runs, PrefixOrderer::new(&counting, |i: &u32| *i as u64));
+        assert!(merger.eq(0..4000));
+        assert_eq!(0, comparisons.get());
+
+        // coarse prefixes fall back to the full comparison on ties
+        let runs = (0..4)
+            .map(|run| BufRun::new((0..1000).map(|i| i * 4 + run).collect()))
+            .collect();
+        let merger = LoserTree::new(
+            runs,
+            PrefixOrderer::new(&counting, |i: &u32| *i as u64 / 100),
+        );
+        assert!(merger.eq(0..4000));
+        assert!(comparisons.get() > 0);
+    }
+
+    #[test]
+    fn test_partial_prefixes() {
+        use std::cmp::Ordering;
+
+        use crate::orderer::Orderer;
+
+        /// only has prefixes for even items
+        struct EvenPrefix;
+        impl Orderer<u32> for EvenPrefix {
+            fn compare(&self, left: &u32, right: &u32) -> Ordering {
+                left.cmp(right)
+            }
+
+            fn key_prefix(&self, item: &u32) -> Option<u64> {
+                item.is_multiple_of(2).then_some(*item as u64)
+            }
+        }
+
+        // all heads have prefixes at first, but the odd items show up later
+        let runs = (0..4)
+            .map(|run| BufRun::new((0..1000).map(|i| i * 4 + run * 2 + i % 2).collect()))
+            .collect();
+        let merged: Vec<u32> = LoserTree::new(runs, EvenPrefix).collect();
+        let mut expected: Vec<u32> = (0..4)
+            .flat_map(|run| (0..1000).map(move |i| i * 4 + run * 2 + i % 2))
+            .collect();
+        expected.sort();
+        assert_eq!(expected, merged);
+    }
+
+    #[test]
+    fn test_exact_prefix_missing() {
+        use std::cmp::Ordering;
+
+        use crate::orderer::Orderer;
+
+        /// claims exact prefixes, but has none for the multiples of 7
+        struct Unreliable;
+        impl Orderer<u32> for Unreliable {
+            fn compare(&self, left: &u32, right: &u32) -> Ordering {
+                left.cmp(right)
+            }
+
+            fn key_prefix(&self, item: &u32) -> Option<u64> {
+                (!item.is_multiple_of(7) || *item < 1000).then_some(*item as u64)
+            }
+
+            fn exact_prefix(&self) -> bool {
+                true
+            }
+        }
+
+        let runs = (0..3)
+            .map(|run| BufRun::new((0..1000).map(|i| i * 3 + run).collect()))
+            .collect();
+        let merger = LoserTree::new(runs, Unreliable);
+        assert!(merger.kernel.is_some());
+        assert!(merger.eq(0..3000));
+    }
+
+    #[test]
+    fn test_merge_primitive_keys() {
+        use rand::Rng;
+
+        use crate::orderer::radix::{RadixKeyOrderer, RadixOrderer};
+
+        let mut rng = rand::thread_rng();
+        // more runs than the kernels merge, so the tree hands over to them
+        for num_runs in [2, 5, 8, 20] {
+            let runs: Vec<Vec<u64>> = (0..num_runs)
+                .map(|_| {
+                    let len = rng.gen_range(0..3000);
+                    let mut run: Vec<u64> = (0..len).map(|_| rng.gen_range(0..5000)).collect();
+                    run.sort();
+                    run
+                })
+                .collect();
+            let mut expected: Vec<u64> = runs.iter().flatten().copied().collect();
+            expected.sort();
+
+            let buf_runs = runs.iter().cloned().map(BufRun::new).collect();
+            let merger = LoserTree::new(buf_runs, RadixOrderer::new());
+            assert!(merger.kernel.is_some());
+            assert!(merger.eq(expected.iter().copied()));
+
+            // the native order does not know about the radix keys
+            let buf_runs = runs.iter().cloned().map(BufRun::new).collect();
+            let merger = LoserTree::new(buf_runs, OrdOrderer::new());
+            assert!(merger.kernel.is_none());
+            assert!(merger.eq(expected.iter().copied()));
+
+            // the items only need to agree on their keys
+            let keyed_runs = runs
+                .iter()
+                .map(|run| BufRun::new(run.iter().map(|&i| (i as f64 - 2500.0, i)).collect()))
+                .collect();
+            let merger =
+                LoserTree::new(keyed_runs, RadixKeyOrderer::new(|item: &(f64, u64)| item.0));
+            assert!(merger.map(|(_, i)| i).eq(expected.iter().copied()));
+        }
+    }
+
     #[cfg(not(miri))]
     // the only reason this is disabled on miri is that it would run too slowly
     mod random {
@@ -2,8 +2,8 @@ use std::{cmp::Ordering, ops::Range};
 
 use super::{TreeNode, Winner};
 
-/// This module contains the code to construct a complete loser tree
-/// in an implicit array representation.
+// This module contains the code to construct a complete loser tree
+// in an implicit array representation.
 
 /// This is a convenience struct to move the tree construction code out from the main merge
 /// code
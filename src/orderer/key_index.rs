@@ -0,0 +1,203 @@
+//! Sorting large items by their keys or indices, moving every item only once.
+
+use std::{cmp::Ordering, mem::size_of};
+
+/// the items are sorted using (key, index) pairs once they are
+/// at least this many times larger than those pairs.
+/// Below that, extracting the keys and following the permutation
+/// costs more than moving the items during the sort.
+const KEY_INDEX_RATIO: usize = 16;
+
+/// items of at least this many bytes are sorted through their indices
+/// if the orderer can not sort them through their keys.
+/// The indices are sorted by comparing the items they point to, which is slower
+/// than comparing the items directly unless moving the items is expensive.
+const INDEX_MIN_SIZE: usize = 256;
+
+/// marks the positions that already hold their item
+const PLACED: u32 = u32::MAX;
+
+/// returns true if sorting the items through (key, index) pairs
+/// moves less data around than sorting the items themselves.
+pub(crate) fn prefer_key_index<T, K>() -> bool {
+    size_of::<T>() >= KEY_INDEX_RATIO * size_of::<(K, u32)>()
+}
+
+/// returns true if sorting the indices of the items
+/// is cheaper than moving the items during the sort.
+pub(crate) fn prefer_index<T>() -> bool {
+    size_of::<T>() >= INDEX_MIN_SIZE
+}
+
+/// sorts the items by sorting their indices using the provided comparison,
+/// and then moves every item to its place directly.
+/// Returns false, without touching the items, if there are too many items to index them.
+pub(crate) fn sort_by_index<T>(items: &mut [T], compare: impl Fn(&T, &T) -> Ordering) -> bool {
+    let Ok(len) = u32::try_from(items.len()) else {
+        return false;
+    };
+    let mut sources: Vec<u32> = (0..len).collect();
+    sources
+        .sort_unstable_by(|&left, &right| compare(&items[left as usize], &items[right as usize]));
+    apply_permutation(items, &mut sources);
+    true
+}
+
+/// sorts the items by extracting each key once and sorting (key, index) pairs,
+/// and then moves every item to its place directly.
+/// This needs memory for the pairs and the permutation on top of the items,
+/// which is at most an eighth of their size if the key index is preferred for them.
+/// Returns false, without touching the items, if there are too many items to index them.
+pub(crate) fn sort_by_key_index<T, K: Ord>(
+    items: &mut [T],
+    key_extractor: impl Fn(&T) -> K,
+) -> bool {
+    let Ok(len) = u32::try_from(items.len()) else {
+        return false;
+    };
+    let mut keys: Vec<(K, u32)> = items
+        .iter()
+        .zip(0..len)
+        .map(|(item, idx)| (key_extractor(item), idx))
+        .collect();
+    keys.sort_unstable_by(|(left, _), (right, _)| left.cmp(right));
+    let mut sources: Vec<u32> = keys.into_iter().map(|(_, idx)| idx).collect();
+    apply_permutation(items, &mut sources);
+    true
+}
+
+/// moves the item at sources[i] to position i for every i, following the cycles of the permutation.
+/// There must be fewer than u32::MAX items.
+pub(crate) fn apply_permutation<T>(items: &mut [T], sources: &mut [u32]) {
+    debug_assert_eq!(items.len(), sources.len());
+    let ptr = items.as_mut_ptr();
+    for start in 0..items.len() {
+        if sources[start] == PLACED || sources[start] as usize == start {
+            continue;
+        }
+        // SAFETY:
+        // sources is a permutation of the indices of the items, so every position in the cycle
+        // is in bounds and receives exactly one item. The item taken out at the start of the cycle
+        // is written back at its end, and no code that could panic runs while it is taken out.
+        unsafe {
+            let first = ptr.add(start).read();
+            let mut current = start;
+            loop {
+                let source = sources[current] as usize;
+                sources[current] = PLACED;
+                if source == start {
+                    ptr.add(current).write(first);
+                    break;
+                }
+                ptr.add(current)
+                    .copy_from_nonoverlapping(ptr.add(source), 1);
+                current = source;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{seq::SliceRandom, Rng};
+
+    use crate::{ExtSortByExtension, ExtsortConfig};
+
+    use super::{
+        apply_permutation, prefer_index, prefer_key_index, sort_by_index, sort_by_key_index,
+    };
+
+    #[test]
+    fn test_apply_permutation() {
+        let mut rng = rand::thread_rng();
+        for len in [0, 1, 2, 10, 1000] {
+            let mut sources: Vec<u32> = (0..len).collect();
+            sources.shuffle(&mut rng);
+            // heap allocated items to catch double drops and leaks
+            let mut items: Vec<String> = (0..len).map(|i| i.to_string()).collect();
+            let expected: Vec<String> = sources.iter().map(|i| i.to_string()).collect();
+            apply_permutation(&mut items, &mut sources.clone());
+            assert_eq!(expected, items);
+        }
+    }
+
+    #[test]
+    fn test_sort_by_key_index() {
+        let mut rng = rand::thread_rng();
+        let mut items: Vec<(u32, [u64; 32], String)> = (0..5000)
+            .map(|i| (rng.gen_range(0..100), [i; 32], i.to_string()))
+            .collect();
+        assert!(sort_by_key_index(&mut items, |item| item.0));
+        assert!(items.windows(2).all(|w| w[0].0 <= w[1].0));
+        // the items stay intact
+        assert!(items
+            .iter()
+            .all(|(_, payload, name)| payload[0].to_string() == *name));
+    }
+
+    #[test]
+    fn test_sort_by_index() {
+        let mut rng = rand::thread_rng();
+        let mut items: Vec<([u64; 32], String)> = (0..5000)
+            .map(|_| {
+                let key = rng.gen_range(0..100);
+                ([key; 32], key.to_string())
+            })
+            .collect();
+        assert!(sort_by_index(&mut items, |left, right| left
+            .0
+            .cmp(&right.0)));
+        assert!(items.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert!(items
+            .iter()
+            .all(|(payload, name)| payload[0].to_string() == *name));
+    }
+
+    #[test]
+    fn test_prefer_index() {
+        assert!(prefer_index::<[u8; 256]>());
+        assert!(prefer_index::<(String, [u64; 32])>());
+        assert!(!prefer_index::<[u8; 128]>());
+        assert!(!prefer_index::<String>());
+    }
+
+    #[test]
+    fn test_prefer_key_index() {
+        assert!(prefer_key_index::<[u8; 256], u64>());
+        assert!(prefer_key_index::<[u8; 128], u32>());
+        assert!(!prefer_key_index::<[u8; 64], u64>());
+        assert!(!prefer_key_index::<(u64, u64), u64>());
+        assert!(!prefer_key_index::<[u8; 256], [u8; 64]>());
+        assert!(!prefer_key_index::<(), ()>());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_large_items() {
+        // large enough to be sorted through their keys
+        let items: Vec<(u32, [u64; 64])> = (0..3000u32)
+            .map(|i| (i * 7919 % 3000, [i as u64; 64]))
+            .collect();
+        let config =
+            ExtsortConfig::with_buffer_size(64 * 1024).temp_file_folder(std::env::temp_dir());
+        let sorted = items
+            .iter()
+            .cloned()
+            .external_sort_by_key(config, |item| item.0)
+            .unwrap();
+        assert!(sorted
+            .map(|(key, payload)| (key, payload[63] as u32 * 7919 % 3000))
+            .eq((0..3000).map(|i| (i, i))));
+
+        // without keys, they are sorted through their indices
+        let config =
+            ExtsortConfig::with_buffer_size(64 * 1024).temp_file_folder(std::env::temp_dir());
+        let sorted = items
+            .into_iter()
+            .external_sort_by(config, |left, right| left.0.cmp(&right.0))
+            .unwrap();
+        assert!(sorted
+            .map(|(key, payload)| (key, payload[63] as u32 * 7919 % 3000))
+            .eq((0..3000).map(|i| (i, i))));
+    }
+}
@@ -1,12 +1,82 @@
 use std::cmp::Ordering;
 
+pub(crate) mod key_index;
+pub mod radix;
+
 /// A generialisation of the Ord trait.
 /// The main difference is that the Orderer is able to
 /// reference some internal state as it is passed by ref
 /// and compares some other type.
+///
+/// Implement it to sort with a comparator that carries its own state,
+/// like a collator or a comparer driven by a schema, using
+/// [`external_sort_with`](crate::ExtSortByExtension::external_sort_with).
+/// Only [`compare`](Self::compare) is required, the other methods let
+/// the orderer speed up the sort and must agree with it.
 pub trait Orderer<T> {
-    /// the type to compare
+    /// compares the two items. This must be a total order.
     fn compare(&self, left: &T, right: &T) -> Ordering;
+
+    /// returns the normalized key prefix of the item, if the orderer supports them.
+    /// Any two items that both have a prefix and whose prefixes differ must be ordered
+    /// like their prefixes, only items with equal prefixes need to be compared.
+    ///
+    /// An orderer should return a prefix either for all items or for none of them.
+    /// Items without a prefix are still sorted correctly, but they are compared
+    /// in full and keep the merge from using the faster paths for prefixes.
+    fn key_prefix(&self, _item: &T) -> Option<u64> {
+        None
+    }
+
+    /// returns true if the key prefixes decide the order on their own,
+    /// so that items with equal prefixes compare as equal.
+    /// This lets the merge work on the prefixes alone.
+    ///
+    /// If this returns true, [`key_prefix`](Self::key_prefix) should return a prefix for every item.
+    /// Once an item without one is found, the rest is merged by comparing the items.
+    fn exact_prefix(&self) -> bool {
+        false
+    }
+
+    /// sorts the buffer in a way specific to the orderer, like a radix sort
+    /// or sorting the keys of large items on their own.
+    /// Returns false if the buffer has to be sorted by comparing the items instead.
+    fn sort_buffer(&self, _buffer: &mut [T]) -> bool {
+        false
+    }
+
+    /// like [`sort_buffer`](Self::sort_buffer), but the sort runs on a rayon pool and may use it.
+    fn par_sort_buffer(&self, _buffer: &mut [T]) -> bool
+    where
+        T: Send,
+    {
+        false
+    }
+}
+
+impl<T, O: Orderer<T> + ?Sized> Orderer<T> for &O {
+    fn compare(&self, left: &T, right: &T) -> Ordering {
+        (**self).compare(left, right)
+    }
+
+    fn key_prefix(&self, item: &T) -> Option<u64> {
+        (**self).key_prefix(item)
+    }
+
+    fn exact_prefix(&self) -> bool {
+        (**self).exact_prefix()
+    }
+
+    fn sort_buffer(&self, buffer: &mut [T]) -> bool {
+        (**self).sort_buffer(buffer)
+    }
+
+    fn par_sort_buffer(&self, buffer: &mut [T]) -> bool
+    where
+        T: Send,
+    {
+        (**self).par_sort_buffer(buffer)
+    }
 }
 
 /// An orderer that just delegates to the Ord implementation on the type itself
@@ -48,6 +118,13 @@ where
         let right = (self.key_extractor)(right);
         left.cmp(&right)
     }
+
+    fn sort_buffer(&self, buffer: &mut [T]) -> bool {
+        // large items are sorted through their keys, so they are only moved once.
+        // The keys may not be Send, so this is only done on the current thread.
+        key_index::prefer_key_index::<T, K>()
+            && key_index::sort_by_key_index(buffer, &self.key_extractor)
+    }
 }
 
 /// an orderer that compares values by delegating to a comparison function
@@ -72,3 +149,210 @@ where
         (self.comparator)(left, right)
     }
 }
+
+/// an orderer that compares the normalized key prefixes of the values first,
+/// and only compares the values using the inner orderer if their prefixes are equal.
+pub struct PrefixOrderer<O, P> {
+    inner: O,
+    prefix: P,
+}
+
+/// a [`KeyOrderer`] with normalized key prefixes
+pub type KeyPrefixOrderer<F, P> = PrefixOrderer<KeyOrderer<F>, P>;
+
+impl<O, P> PrefixOrderer<O, P> {
+    pub fn new<T>(inner: O, prefix: P) -> Self
+    where
+        O: Orderer<T>,
+        P: Fn(&T) -> u64,
+    {
+        Self { inner, prefix }
+    }
+}
+
+impl<O, P, T> Orderer<T> for PrefixOrderer<O, P>
+where
+    O: Orderer<T>,
+    P: Fn(&T) -> u64,
+{
+    fn compare(&self, left: &T, right: &T) -> Ordering {
+        (self.prefix)(left)
+            .cmp(&(self.prefix)(right))
+            .then_with(|| self.inner.compare(left, right))
+    }
+
+    fn key_prefix(&self, item: &T) -> Option<u64> {
+        Some((self.prefix)(item))
+    }
+
+    // the prefixes agree with the inner ordering
+    fn sort_buffer(&self, buffer: &mut [T]) -> bool {
+        self.inner.sort_buffer(buffer)
+    }
+
+    fn par_sort_buffer(&self, buffer: &mut [T]) -> bool
+    where
+        T: Send,
+    {
+        self.inner.par_sort_buffer(buffer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+
+    use crate::{ExtSortByExtension, ExtSortOrdExtension, ExtsortConfig};
+
+    use super::{KeyOrderer, OrdOrderer, Orderer, PrefixOrderer};
+
+    #[test]
+    fn test_prefix_orderer() {
+        let orderer = PrefixOrderer::new(KeyOrderer::new(|s: &&str| *s), |s: &&str| {
+            s.bytes().next().unwrap_or(0) as u64
+        });
+        assert_eq!(Some(b'b' as u64), orderer.key_prefix(&"b"));
+        assert_eq!(Ordering::Less, orderer.compare(&"abc", &"b"));
+        // equal prefixes fall back to the inner orderer
+        assert_eq!(Ordering::Greater, orderer.compare(&"abd", &"abc"));
+        assert_eq!(Ordering::Equal, orderer.compare(&"abc", &"abc"));
+
+        assert_eq!(None, Orderer::<&str>::key_prefix(&OrdOrderer::new(), &"a"));
+        assert_eq!(
+            None,
+            Orderer::<(u8, u8)>::key_prefix(&OrdOrderer::new(), &(1, 2))
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_key_prefix() {
+        let words: Vec<String> = (0..2000u32)
+            .rev()
+            .map(|i| format!("word{:05}", i * 7919 % 2000))
+            .collect();
+        let mut expected = words.clone();
+        expected.sort();
+
+        // the first 8 bytes only tell a few of the words apart
+        let prefix = |s: &String| {
+            let mut bytes = [0; 8];
+            let len = s.len().min(8);
+            bytes[..len].copy_from_slice(&s.as_bytes()[..len]);
+            u64::from_be_bytes(bytes)
+        };
+        let config = ExtsortConfig::with_buffer_size(1024).temp_file_folder(std::env::temp_dir());
+        let sorted = words
+            .iter()
+            .cloned()
+            .external_sort_with_prefix(config, prefix)
+            .unwrap();
+        assert!(sorted.eq(expected.iter().cloned()));
+
+        let config = ExtsortConfig::with_buffer_size(1024).temp_file_folder(std::env::temp_dir());
+        let sorted = words
+            .into_iter()
+            .external_sort_by_key_with_prefix(config, |s| s.clone(), prefix)
+            .unwrap();
+        assert!(sorted.eq(expected));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_custom_orderer() {
+        use std::sync::Arc;
+
+        struct Collation {
+            ignore_case: bool,
+        }
+        struct Collator {
+            collation: Arc<Collation>,
+        }
+        impl Orderer<String> for Collator {
+            fn compare(&self, left: &String, right: &String) -> Ordering {
+                if self.collation.ignore_case {
+                    left.to_lowercase()
+                        .cmp(&right.to_lowercase())
+                        .then_with(|| left.cmp(right))
+                } else {
+                    left.cmp(right)
+                }
+            }
+        }
+
+        let words: Vec<String> = (0..2000u32)
+            .map(|i| {
+                let word = format!("w{:04}", i * 7919 % 2000);
+                if i % 2 == 0 {
+                    word.to_uppercase()
+                } else {
+                    word
+                }
+            })
+            .collect();
+        let mut expected = words.clone();
+        expected.sort_by_key(|w| (w.to_lowercase(), w.clone()));
+
+        let collator = Collator {
+            collation: Arc::new(Collation { ignore_case: true }),
+        };
+        let config = ExtsortConfig::with_buffer_size(4096).temp_file_folder(std::env::temp_dir());
+        let sorted = words
+            .iter()
+            .cloned()
+            .external_sort_with(config, &collator)
+            .unwrap();
+        assert!(sorted.eq(expected.iter().cloned()));
+
+        #[cfg(feature = "parallel_sort")]
+        {
+            let config =
+                ExtsortConfig::with_buffer_size(4096).temp_file_folder(std::env::temp_dir());
+            let sorted = words
+                .into_iter()
+                .external_sort_with(config, collator)
+                .unwrap();
+            assert!(sorted.eq(expected));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_orderer_with_prefix() {
+        /// orders words by their lowercase form, with the first bytes of it as the prefix
+        struct CaseInsensitive;
+        impl Orderer<String> for CaseInsensitive {
+            fn compare(&self, left: &String, right: &String) -> Ordering {
+                left.to_lowercase().cmp(&right.to_lowercase())
+            }
+
+            fn key_prefix(&self, item: &String) -> Option<u64> {
+                let mut bytes = [0; 8];
+                for (byte, lower) in bytes.iter_mut().zip(item.to_lowercase().bytes()) {
+                    *byte = lower;
+                }
+                Some(u64::from_be_bytes(bytes))
+            }
+        }
+
+        let words: Vec<String> = (0..5000u32)
+            .map(|i| {
+                let word = format!("word{:05}", i * 7919 % 5000);
+                if i % 2 == 0 {
+                    word.to_uppercase()
+                } else {
+                    word
+                }
+            })
+            .collect();
+        let mut expected: Vec<String> = words.iter().map(|w| w.to_lowercase()).collect();
+        expected.sort();
+
+        let config = ExtsortConfig::with_buffer_size(1024).temp_file_folder(std::env::temp_dir());
+        let sorted = words
+            .into_iter()
+            .external_sort_with(config, CaseInsensitive)
+            .unwrap();
+        assert!(sorted.map(|w| w.to_lowercase()).eq(expected));
+    }
+}
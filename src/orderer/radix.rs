@@ -0,0 +1,872 @@
+//! Radix sorting for keys that can be normalized to byte strings.
+
+use std::cmp::Ordering;
+
+use super::{key_index::apply_permutation, Orderer};
+
+/// A key that can be sorted by looking at its bytes instead of comparing it to other keys.
+///
+/// The normalized key consists of [`FIELDS`](Self::FIELDS) byte strings.
+/// Keys are ordered by their first field, then by their second and so on,
+/// where each field is compared lexicographically and a field that ends
+/// is smaller than any longer field it is a prefix of.
+/// This order must match [`radix_cmp`](Self::radix_cmp), and the [`Ord`] implementation
+/// of the key if it has one.
+///
+/// It is implemented for the integer and floating point types, `bool`, `char`,
+/// byte strings and tuples of radix keys. Floating point numbers are ordered
+/// like [`f64::total_cmp`] orders them.
+pub trait RadixKey {
+    /// the number of byte strings in the normalized key
+    const FIELDS: usize;
+
+    /// the number of bytes in the normalized key, if it is the same for all keys.
+    const WIDTH: Option<usize> = None;
+
+    /// returns the byte at idx of the provided field of the normalized key,
+    /// or None if the field is shorter.
+    fn radix_byte(&self, field: usize, idx: usize) -> Option<u8>;
+
+    /// compares the normalized keys.
+    /// The default implementation compares them byte by byte.
+    fn radix_cmp(&self, other: &Self) -> Ordering {
+        for field in 0..Self::FIELDS {
+            for idx in 0.. {
+                match (self.radix_byte(field, idx), other.radix_byte(field, idx)) {
+                    (None, None) => break,
+                    (left, right) if left != right => return left.cmp(&right),
+                    _ => {}
+                }
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+macro_rules! unsigned_radix_key {
+    ($($t:ty),*) => {
+        $(
+            impl RadixKey for $t {
+                const FIELDS: usize = 1;
+                const WIDTH: Option<usize> = Some(std::mem::size_of::<$t>());
+
+                fn radix_byte(&self, _field: usize, idx: usize) -> Option<u8> {
+                    self.to_be_bytes().get(idx).copied()
+                }
+
+                fn radix_cmp(&self, other: &Self) -> Ordering {
+                    self.cmp(other)
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! signed_radix_key {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl RadixKey for $t {
+                const FIELDS: usize = 1;
+                const WIDTH: Option<usize> = Some(std::mem::size_of::<$t>());
+
+                fn radix_byte(&self, _field: usize, idx: usize) -> Option<u8> {
+                    // flipping the sign bit orders negative numbers before positive ones
+                    let normalized = (*self as $u) ^ (1 << (<$u>::BITS - 1));
+                    normalized.to_be_bytes().get(idx).copied()
+                }
+
+                fn radix_cmp(&self, other: &Self) -> Ordering {
+                    self.cmp(other)
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! float_radix_key {
+    ($($t:ty => $u:ty),*) => {
+        $(
+            impl RadixKey for $t {
+                const FIELDS: usize = 1;
+                const WIDTH: Option<usize> = Some(std::mem::size_of::<$t>());
+
+                fn radix_byte(&self, _field: usize, idx: usize) -> Option<u8> {
+                    // negative numbers are flipped entirely, so that larger magnitudes come first,
+                    // positive numbers only get their sign bit set.
+                    let bits = self.to_bits();
+                    let sign = 1 << (<$u>::BITS - 1);
+                    let normalized = if bits & sign != 0 { !bits } else { bits | sign };
+                    normalized.to_be_bytes().get(idx).copied()
+                }
+
+                fn radix_cmp(&self, other: &Self) -> Ordering {
+                    self.total_cmp(other)
+                }
+            }
+        )*
+    };
+}
+
+unsigned_radix_key!(u8, u16, u32, u64, u128, usize);
+signed_radix_key!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128, isize => usize);
+float_radix_key!(f32 => u32, f64 => u64);
+
+impl RadixKey for bool {
+    const FIELDS: usize = 1;
+    const WIDTH: Option<usize> = Some(1);
+
+    fn radix_byte(&self, field: usize, idx: usize) -> Option<u8> {
+        (*self as u8).radix_byte(field, idx)
+    }
+
+    fn radix_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl RadixKey for char {
+    const FIELDS: usize = 1;
+    const WIDTH: Option<usize> = Some(4);
+
+    fn radix_byte(&self, field: usize, idx: usize) -> Option<u8> {
+        (*self as u32).radix_byte(field, idx)
+    }
+
+    fn radix_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl RadixKey for [u8] {
+    const FIELDS: usize = 1;
+
+    fn radix_byte(&self, _field: usize, idx: usize) -> Option<u8> {
+        self.get(idx).copied()
+    }
+
+    fn radix_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl<const N: usize> RadixKey for [u8; N] {
+    const FIELDS: usize = 1;
+    const WIDTH: Option<usize> = Some(N);
+
+    fn radix_byte(&self, _field: usize, idx: usize) -> Option<u8> {
+        self.get(idx).copied()
+    }
+
+    fn radix_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl RadixKey for Vec<u8> {
+    const FIELDS: usize = 1;
+
+    fn radix_byte(&self, _field: usize, idx: usize) -> Option<u8> {
+        self.get(idx).copied()
+    }
+
+    fn radix_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+// the UTF-8 encoding orders strings just like their Ord implementation.
+impl RadixKey for str {
+    const FIELDS: usize = 1;
+
+    fn radix_byte(&self, _field: usize, idx: usize) -> Option<u8> {
+        self.as_bytes().get(idx).copied()
+    }
+
+    fn radix_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl RadixKey for String {
+    const FIELDS: usize = 1;
+
+    fn radix_byte(&self, _field: usize, idx: usize) -> Option<u8> {
+        self.as_bytes().get(idx).copied()
+    }
+
+    fn radix_cmp(&self, other: &Self) -> Ordering {
+        self.cmp(other)
+    }
+}
+
+impl<K: RadixKey + ?Sized> RadixKey for &K {
+    const FIELDS: usize = K::FIELDS;
+    const WIDTH: Option<usize> = K::WIDTH;
+
+    fn radix_byte(&self, field: usize, idx: usize) -> Option<u8> {
+        (**self).radix_byte(field, idx)
+    }
+
+    fn radix_cmp(&self, other: &Self) -> Ordering {
+        (**self).radix_cmp(*other)
+    }
+}
+
+impl RadixKey for () {
+    const FIELDS: usize = 0;
+    const WIDTH: Option<usize> = Some(0);
+
+    fn radix_byte(&self, _field: usize, _idx: usize) -> Option<u8> {
+        None
+    }
+}
+
+/// adds up the widths of the fields of a tuple
+const fn add_width(left: Option<usize>, right: Option<usize>) -> Option<usize> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(left + right),
+        _ => None,
+    }
+}
+
+macro_rules! tuple_radix_key {
+    ($($name:ident $idx:tt),+) => {
+        impl<$($name: RadixKey),+> RadixKey for ($($name,)+) {
+            const FIELDS: usize = 0 $(+ $name::FIELDS)+;
+            const WIDTH: Option<usize> = {
+                let width = Some(0);
+                $(let width = add_width(width, $name::WIDTH);)+
+                width
+            };
+
+            #[allow(unused_assignments)]
+            fn radix_byte(&self, mut field: usize, idx: usize) -> Option<u8> {
+                $(
+                    if field < $name::FIELDS {
+                        return self.$idx.radix_byte(field, idx);
+                    }
+                    field -= $name::FIELDS;
+                )+
+                None
+            }
+
+            fn radix_cmp(&self, other: &Self) -> Ordering {
+                Ordering::Equal $(.then_with(|| self.$idx.radix_cmp(&other.$idx)))+
+            }
+        }
+    };
+}
+
+tuple_radix_key!(A 0);
+tuple_radix_key!(A 0, B 1);
+tuple_radix_key!(A 0, B 1, C 2);
+tuple_radix_key!(A 0, B 1, C 2, D 3);
+
+/// the first 8 bytes of the first field, which order the keys like the keys themselves.
+fn radix_prefix<K: RadixKey>(key: &K) -> Option<u64> {
+    if K::FIELDS == 0 {
+        return None;
+    }
+    let mut bytes = [0; 8];
+    for (idx, byte) in bytes.iter_mut().enumerate() {
+        match key.radix_byte(0, idx) {
+            Some(b) => *byte = b,
+            None => break,
+        }
+    }
+    Some(u64::from_be_bytes(bytes))
+}
+
+/// the prefix is the whole key if it consists of a single field
+/// of at most 8 bytes, like the primitive numbers.
+fn radix_prefix_is_exact<K: RadixKey>() -> bool {
+    K::FIELDS == 1 && matches!(K::WIDTH, Some(width) if width <= 8)
+}
+
+/// an orderer that uses the radix key of the values themselves
+#[derive(Default)]
+pub struct RadixOrderer {}
+
+impl RadixOrderer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl<T: RadixKey> Orderer<T> for RadixOrderer {
+    fn compare(&self, left: &T, right: &T) -> Ordering {
+        left.radix_cmp(right)
+    }
+
+    fn key_prefix(&self, item: &T) -> Option<u64> {
+        radix_prefix(item)
+    }
+
+    fn exact_prefix(&self) -> bool {
+        radix_prefix_is_exact::<T>()
+    }
+
+    fn sort_buffer(&self, buffer: &mut [T]) -> bool {
+        KeySorter::<T>::for_keys().sort(buffer);
+        true
+    }
+
+    fn par_sort_buffer(&self, buffer: &mut [T]) -> bool
+    where
+        T: Send,
+    {
+        KeySorter::<T>::for_keys().par_sort(buffer);
+        true
+    }
+}
+
+/// an orderer that uses the radix key extracted from the values
+pub struct RadixKeyOrderer<F> {
+    key_extractor: F,
+}
+
+impl<F> RadixKeyOrderer<F> {
+    pub fn new<T, K>(key_extractor: F) -> Self
+    where
+        F: Fn(&T) -> K,
+        K: RadixKey,
+    {
+        Self { key_extractor }
+    }
+}
+
+impl<F, T, K> Orderer<T> for RadixKeyOrderer<F>
+where
+    F: Fn(&T) -> K,
+    K: RadixKey,
+{
+    fn compare(&self, left: &T, right: &T) -> Ordering {
+        (self.key_extractor)(left).radix_cmp(&(self.key_extractor)(right))
+    }
+
+    fn key_prefix(&self, item: &T) -> Option<u64> {
+        radix_prefix(&(self.key_extractor)(item))
+    }
+
+    fn exact_prefix(&self) -> bool {
+        radix_prefix_is_exact::<K>()
+    }
+
+    fn sort_buffer(&self, buffer: &mut [T]) -> bool {
+        sort_by_normalized_keys(buffer, &self.key_extractor, false)
+    }
+
+    fn par_sort_buffer(&self, buffer: &mut [T]) -> bool
+    where
+        T: Send,
+    {
+        sort_by_normalized_keys(buffer, &self.key_extractor, true)
+    }
+}
+
+/// sorts the items by their radix keys, extracting each key only once.
+/// The indices of the items are radix sorted by their normalized keys,
+/// in parallel if requested, and then every item is moved to its place directly.
+/// Returns false, without touching the items, if there are too many items to index them.
+fn sort_by_normalized_keys<T, K: RadixKey>(
+    items: &mut [T],
+    key_extractor: impl Fn(&T) -> K,
+    parallel: bool,
+) -> bool {
+    let Ok(len) = u32::try_from(items.len()) else {
+        return false;
+    };
+    let keys = NormalizedKeys::extract(items, key_extractor);
+    let mut sources: Vec<u32> = (0..len).collect();
+    let sorter = RadixSorter {
+        fields: keys.fields,
+        digit: |item: &u32, field: usize, idx: usize| keys.field(*item, field).get(idx).copied(),
+        compare: |left: &u32, right: &u32| keys.compare(*left, *right),
+    };
+    if parallel {
+        sorter.par_sort(&mut sources);
+    } else {
+        sorter.sort(&mut sources);
+    }
+    drop(keys);
+    apply_permutation(items, &mut sources);
+    true
+}
+
+/// The normalized keys of a buffer of items.
+/// They are extracted up front, so that sorting the items does not need
+/// to extract a key for every byte it looks at.
+struct NormalizedKeys {
+    bytes: Vec<u8>,
+    /// the start of every field of every item in the bytes, followed by the end of the last one.
+    /// Keys of a single field of fixed width are stored without them.
+    starts: Vec<usize>,
+    fields: usize,
+    /// the width of the keys stored without starts
+    width: usize,
+}
+
+impl NormalizedKeys {
+    fn extract<T, K: RadixKey>(items: &[T], key_extractor: impl Fn(&T) -> K) -> Self {
+        let mut bytes = Vec::new();
+        match K::WIDTH {
+            Some(width) if K::FIELDS == 1 => {
+                bytes.reserve_exact(items.len() * width);
+                for item in items {
+                    let key = key_extractor(item);
+                    bytes.extend((0..width).map(|idx| key.radix_byte(0, idx).unwrap_or(0)));
+                }
+                Self {
+                    bytes,
+                    starts: Vec::new(),
+                    fields: 1,
+                    width,
+                }
+            }
+            _ => {
+                let mut starts = Vec::with_capacity(items.len() * K::FIELDS + 1);
+                for item in items {
+                    let key = key_extractor(item);
+                    for field in 0..K::FIELDS {
+                        starts.push(bytes.len());
+                        bytes.extend((0..).map_while(|idx| key.radix_byte(field, idx)));
+                    }
+                }
+                starts.push(bytes.len());
+                Self {
+                    bytes,
+                    starts,
+                    fields: K::FIELDS,
+                    width: 0,
+                }
+            }
+        }
+    }
+
+    /// the bytes of the field of the key of the item with the provided index
+    fn field(&self, item: u32, field: usize) -> &[u8] {
+        if self.starts.is_empty() {
+            let start = item as usize * self.width;
+            &self.bytes[start..start + self.width]
+        } else {
+            let slot = item as usize * self.fields + field;
+            &self.bytes[self.starts[slot]..self.starts[slot + 1]]
+        }
+    }
+
+    /// compares the keys of the items with the provided indices, field by field.
+    fn compare(&self, left: u32, right: u32) -> Ordering {
+        (0..self.fields)
+            .map(|field| self.field(left, field).cmp(self.field(right, field)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// below this many items, comparing them is faster than distributing them.
+const SMALL_SORT: usize = 64;
+
+/// above this many items, the buckets are sorted in parallel if allowed.
+#[cfg(feature = "parallel_sort")]
+const PARALLEL_SORT: usize = 1 << 14;
+
+/// An in place MSD radix sort (american flag sort).
+struct RadixSorter<D, C> {
+    fields: usize,
+    /// returns the byte of the key at the field and index
+    digit: D,
+    /// compares the items, for the small buckets
+    compare: C,
+}
+
+/// the radix sorter for items that are radix keys themselves
+type KeySorter<T> = RadixSorter<fn(&T, usize, usize) -> Option<u8>, fn(&T, &T) -> Ordering>;
+
+impl<T: RadixKey> KeySorter<T> {
+    fn for_keys() -> Self {
+        RadixSorter {
+            fields: T::FIELDS,
+            digit: T::radix_byte,
+            compare: T::radix_cmp,
+        }
+    }
+}
+
+impl<D, C> RadixSorter<D, C> {
+    /// sorts the items on the current thread.
+    fn sort<T>(&self, items: &mut [T])
+    where
+        D: Fn(&T, usize, usize) -> Option<u8>,
+        C: Fn(&T, &T) -> Ordering,
+    {
+        self.sort_from(items, 0, 0);
+    }
+
+    /// sorts the items, distributing the large buckets across the rayon threads.
+    fn par_sort<T>(&self, items: &mut [T])
+    where
+        T: Send,
+        D: Fn(&T, usize, usize) -> Option<u8> + Sync,
+        C: Fn(&T, &T) -> Ordering + Sync,
+    {
+        #[cfg(feature = "parallel_sort")]
+        self.par_sort_from(items, 0, 0);
+        #[cfg(not(feature = "parallel_sort"))]
+        self.sort_from(items, 0, 0);
+    }
+
+    /// the bucket the item belongs to. 0 is for items whose field has ended.
+    fn bucket<T>(&self, item: &T, field: usize, idx: usize) -> usize
+    where
+        D: Fn(&T, usize, usize) -> Option<u8>,
+    {
+        match (self.digit)(item, field, idx) {
+            Some(byte) => byte as usize + 1,
+            None => 0,
+        }
+    }
+
+    /// sorts items that are known to be equal before the provided byte of the field.
+    fn sort_from<T>(&self, items: &mut [T], field: usize, idx: usize)
+    where
+        D: Fn(&T, usize, usize) -> Option<u8>,
+        C: Fn(&T, &T) -> Ordering,
+    {
+        // the keys can be arbitrarily long, so the buckets left
+        // to sort are kept on the heap instead of recursing.
+        let mut pending = vec![(items, field, idx)];
+        while let Some((items, field, idx)) = pending.pop() {
+            pending.extend(self.partition(items, field, idx));
+        }
+    }
+
+    /// like sort_from, but sorts the buckets of large inputs in parallel.
+    #[cfg(feature = "parallel_sort")]
+    fn par_sort_from<T>(&self, items: &mut [T], field: usize, idx: usize)
+    where
+        T: Send,
+        D: Fn(&T, usize, usize) -> Option<u8> + Sync,
+        C: Fn(&T, &T) -> Ordering + Sync,
+    {
+        rayon::scope(|scope| {
+            let (mut items, mut field, mut idx) = (items, field, idx);
+            // we keep working on the largest bucket ourselves. All others hold at most half
+            // of the items, so the recursion stays shallow no matter how long the keys are.
+            while items.len() > PARALLEL_SORT {
+                let mut buckets = self.partition(items, field, idx);
+                let Some(largest) = (0..buckets.len()).max_by_key(|&b| buckets[b].0.len()) else {
+                    return;
+                };
+                (items, field, idx) = buckets.swap_remove(largest);
+                for (bucket, field, idx) in buckets {
+                    scope.spawn(move |_| self.par_sort_from(bucket, field, idx));
+                }
+            }
+            self.sort_from(items, field, idx);
+        });
+    }
+
+    /// moves items that are known to be equal before the provided byte of the field
+    /// into buckets by the first byte they differ in.
+    /// Small inputs are sorted right away.
+    /// Returns the buckets that still need to be sorted, with the byte to continue from.
+    fn partition<'a, T>(
+        &self,
+        items: &'a mut [T],
+        mut field: usize,
+        mut idx: usize,
+    ) -> Vec<(&'a mut [T], usize, usize)>
+    where
+        D: Fn(&T, usize, usize) -> Option<u8>,
+        C: Fn(&T, &T) -> Ordering,
+    {
+        let counts = loop {
+            if items.len() <= SMALL_SORT {
+                items.sort_unstable_by(&self.compare);
+                return Vec::new();
+            }
+            if field >= self.fields {
+                // all keys are equal
+                return Vec::new();
+            }
+
+            let mut counts = [0usize; 257];
+            for item in items.iter() {
+                counts[self.bucket(item, field, idx)] += 1;
+            }
+            if counts[0] == items.len() {
+                // the field has ended for all items, so we move on to the next one
+                field += 1;
+                idx = 0;
+            } else if counts.contains(&items.len()) {
+                // all items share this byte
+                idx += 1;
+            } else {
+                break counts;
+            }
+        };
+
+        let mut next = [0usize; 257];
+        let mut ends = [0usize; 257];
+        let mut total = 0;
+        for bucket in 0..257 {
+            next[bucket] = total;
+            total += counts[bucket];
+            ends[bucket] = total;
+        }
+
+        // move every item into its bucket by swapping it with
+        // the next unsorted item of the target bucket.
+        for bucket in 0..257 {
+            while next[bucket] < ends[bucket] {
+                let target = self.bucket(&items[next[bucket]], field, idx);
+                if target == bucket {
+                    next[bucket] += 1;
+                } else {
+                    items.swap(next[bucket], next[target]);
+                    next[target] += 1;
+                }
+            }
+        }
+
+        // the items in the first bucket are equal in this field and continue with the next one,
+        // the others continue with the next byte.
+        let mut buckets = Vec::new();
+        let mut rest = items;
+        for (bucket, count) in counts.into_iter().enumerate() {
+            let (current, tail) = std::mem::take(&mut rest).split_at_mut(count);
+            rest = tail;
+            if current.len() > 1 {
+                let (next_field, next_idx) = if bucket == 0 {
+                    (field + 1, 0)
+                } else {
+                    (field, idx + 1)
+                };
+                buckets.push((current, next_field, next_idx));
+            }
+        }
+        buckets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+    use rand::{Rng, RngCore};
+
+    use crate::{
+        orderer::Orderer, ExtSortByExtension, ExtSortOrdExtension, ExtsortConfig, Parallelism,
+    };
+
+    use super::{RadixKey, RadixKeyOrderer, RadixOrderer};
+
+    fn check_sorted<T: RadixKey + Ord + Clone + Send + std::fmt::Debug>(mut items: Vec<T>) {
+        let mut expected = items.clone();
+        expected.sort();
+        assert!(RadixOrderer::new().sort_buffer(&mut items));
+        assert_eq!(expected, items);
+    }
+
+    #[test]
+    fn test_integers() {
+        let mut rng = rand::thread_rng();
+        check_sorted((0..10_000).map(|_| rng.next_u64()).collect::<Vec<_>>());
+        check_sorted((0..10_000).map(|_| rng.gen::<i32>()).collect::<Vec<_>>());
+        check_sorted(
+            (0..10_000)
+                .map(|_| rng.gen_range(-5i16..5))
+                .collect::<Vec<_>>(),
+        );
+        check_sorted((0..1000).map(|i| i % 2 == 0).collect::<Vec<_>>());
+        check_sorted(vec![3u8, 1, 2]);
+        check_sorted(Vec::<u32>::new());
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut rng = rand::thread_rng();
+        // lots of shared prefixes and strings that are prefixes of others
+        let strings: Vec<String> = (0..5000)
+            .map(|_| {
+                let len = rng.gen_range(0..12);
+                (0..len).map(|_| rng.gen_range('a'..'d')).collect()
+            })
+            .collect();
+        check_sorted(strings.clone());
+        check_sorted(strings.iter().map(|s| s.clone().into_bytes()).collect());
+        check_sorted(vec!["same".to_owned(); 500]);
+    }
+
+    #[test]
+    #[cfg(not(miri))]
+    fn test_long_keys() {
+        // every byte splits off a single item, so there is a bucket per byte of the longest key
+        let strings: Vec<String> = (0..3000).rev().map(|k| "a".repeat(k)).collect();
+        check_sorted(strings.clone());
+        let mut expected = strings.clone();
+        expected.sort();
+        let mut items = strings;
+        assert!(RadixOrderer::new().par_sort_buffer(&mut items));
+        assert_eq!(expected, items);
+    }
+
+    #[test]
+    fn test_tuples() {
+        let mut rng = rand::thread_rng();
+        let items: Vec<(String, i64, char)> = (0..5000)
+            .map(|_| {
+                let len = rng.gen_range(0..3);
+                let s = (0..len).map(|_| rng.gen_range('a'..'c')).collect();
+                (s, rng.gen_range(-3..3), rng.gen_range('x'..'z'))
+            })
+            .collect();
+        check_sorted(items);
+    }
+
+    #[test]
+    fn test_floats() {
+        let mut rng = rand::thread_rng();
+        let mut items: Vec<f64> = (0..5000).map(|_| rng.gen_range(-1e6..1e6)).collect();
+        items.extend([
+            0.0,
+            -0.0,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NAN,
+            1e-300,
+        ]);
+        let mut expected = items.clone();
+        expected.sort_by(f64::total_cmp);
+
+        assert!(RadixOrderer::new().sort_buffer(&mut items));
+        assert_eq!(
+            expected.iter().map(|f| f.to_bits()).collect::<Vec<_>>(),
+            items.iter().map(|f| f.to_bits()).collect::<Vec<_>>()
+        );
+        let orderer = RadixOrderer::new();
+        assert!(orderer.key_prefix(&-1.5f64) < orderer.key_prefix(&-0.5f64));
+        assert!(orderer.key_prefix(&-0.0f64) < orderer.key_prefix(&0.0f64));
+    }
+
+    #[test]
+    fn test_exact_prefix() {
+        let orderer = RadixOrderer::new();
+        assert!(Orderer::<u64>::exact_prefix(&orderer));
+        assert!(Orderer::<f32>::exact_prefix(&orderer));
+        assert!(Orderer::<(i32,)>::exact_prefix(&orderer));
+        assert!(!Orderer::<u128>::exact_prefix(&orderer));
+        assert!(!Orderer::<String>::exact_prefix(&orderer));
+        assert!(!Orderer::<(u8, u8)>::exact_prefix(&orderer));
+    }
+
+    #[test]
+    fn test_by_key() {
+        let mut items: Vec<(u32, String)> = (0..5000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) % 1000, i.to_string()))
+            .collect();
+        let orderer = RadixKeyOrderer::new(|item: &(u32, String)| item.0);
+        assert!(orderer.sort_buffer(&mut items));
+        assert!(items.windows(2).all(|w| w[0].0 <= w[1].0));
+    }
+
+    #[test]
+    fn test_by_key_extracts_once() {
+        let mut rng = rand::thread_rng();
+        let items: Vec<(String, u32)> = (0..5000u32)
+            .map(|i| {
+                let len = rng.gen_range(0..6);
+                let s = (0..len).map(|_| rng.gen_range('a'..'d')).collect();
+                (s, i)
+            })
+            .collect();
+        let key = |item: &(String, u32)| (item.0.clone(), item.1 % 7);
+        let calls = AtomicUsize::new(0);
+        let orderer = RadixKeyOrderer::new(|item: &(String, u32)| {
+            calls.fetch_add(1, Relaxed);
+            key(item)
+        });
+
+        let mut sorted = items.clone();
+        assert!(orderer.sort_buffer(&mut sorted));
+        assert!(sorted.windows(2).all(|w| key(&w[0]) <= key(&w[1])));
+        assert_eq!(items.len(), calls.swap(0, Relaxed));
+
+        let mut sorted = items.clone();
+        assert!(orderer.par_sort_buffer(&mut sorted));
+        assert!(sorted.windows(2).all(|w| key(&w[0]) <= key(&w[1])));
+        assert_eq!(items.len(), calls.load(Relaxed));
+    }
+
+    #[test]
+    fn test_prefix() {
+        let orderer = RadixOrderer::new();
+        assert!(orderer.key_prefix(&-1i32) < orderer.key_prefix(&0i32));
+        assert!(orderer.key_prefix(&"ab") < orderer.key_prefix(&"b"));
+        assert_eq!(
+            orderer.key_prefix(&"abcdefgh1"),
+            orderer.key_prefix(&"abcdefgh2")
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "parallel_sort", not(miri)))]
+    fn test_parallel() {
+        let mut rng = rand::thread_rng();
+        let mut items: Vec<u64> = (0..200_000).map(|_| rng.next_u64()).collect();
+        let mut expected = items.clone();
+        expected.sort();
+        assert!(RadixOrderer::new().par_sort_buffer(&mut items));
+        assert_eq!(expected, items);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_radix_sort() {
+        let words: Vec<String> = (0..5000u32)
+            .map(|i| format!("w{}", i.wrapping_mul(2_654_435_761) % 3000))
+            .collect();
+        let mut expected = words.clone();
+        expected.sort();
+
+        let config = ExtsortConfig::with_buffer_size(1024).temp_file_folder(std::env::temp_dir());
+        let sorted = words.iter().cloned().external_sort_radix(config).unwrap();
+        assert!(sorted.eq(expected.iter().cloned()));
+
+        // keys differing only in their length need a pass per byte
+        let config = ExtsortConfig::with_buffer_size(4096).temp_file_folder(std::env::temp_dir());
+        let sorted = (0..3000)
+            .rev()
+            .map(|k| "a".repeat(k))
+            .external_sort_radix(config)
+            .unwrap();
+        assert!(sorted.eq((0..3000).map(|k| "a".repeat(k))));
+
+        let config = ExtsortConfig::with_buffer_size(1024)
+            .temp_file_folder(std::env::temp_dir())
+            .parallelism(Parallelism::BackgroundWriter);
+        let sorted = (0..10_000i64)
+            .rev()
+            .map(|i| (i - 5000, i))
+            .external_sort_radix_by_key(config, |&(key, _)| key)
+            .unwrap();
+        assert!(sorted.map(|(key, _)| key).eq(-5000..5000));
+
+        // floating point keys are merged using the vectorized kernels
+        let config = ExtsortConfig::with_buffer_size(1024).temp_file_folder(std::env::temp_dir());
+        let sorted = (0..10_000u32)
+            .map(|i| (i * 7919 % 10_000) as f64 - 5000.5)
+            .external_sort_radix_by_key(config, |&f| f)
+            .unwrap();
+        assert!(sorted.eq((0..10_000).map(|i| i as f64 - 5000.5)));
+
+        #[cfg(feature = "parallel_sort")]
+        {
+            let config = ExtsortConfig::with_buffer_size(50_000)
+                .temp_file_folder(std::env::temp_dir())
+                .parallelism(Parallelism::Rayon { threads: 2 });
+            let sorted = words.into_iter().external_sort_radix(config).unwrap();
+            assert!(sorted.eq(expected));
+        }
+    }
+}
@@ -18,7 +18,7 @@ impl<T> BufRun<T> {
 
 impl<T> Run<T> for BufRun<T> {
     fn peek(&self) -> Option<&T> {
-        self.source.as_slice().get(0)
+        self.source.as_slice().first()
     }
 
     fn next(&mut self) -> Option<T> {
@@ -28,6 +28,10 @@ impl<T> Run<T> for BufRun<T> {
     fn remaining_items(&self) -> usize {
         self.source.len()
     }
+
+    fn buffered(&self) -> &[T] {
+        self.source.as_slice()
+    }
 }
 
 #[cfg(test)]
@@ -6,7 +6,7 @@ use std::{
 
 use crate::tape::Tape;
 
-use super::Run;
+use super::{read_budget::ReadBudget, Run};
 
 /// A backing for a run. Basically, we extend the Read trait
 /// with an option for premature resource release
@@ -46,6 +46,9 @@ where
     /// the remaining entries for this run.
     /// used for the size_hint and to be able to deal with zero sized types
     remaining_entries: usize,
+    /// the read buffer space shared with the other runs of the merge.
+    /// The read buffer grows to our share of it on refills.
+    budget: Option<ReadBudget>,
 }
 
 impl<T, B> Drop for ExternalRun<T, B>
@@ -58,6 +61,7 @@ where
             // drop all elements by reading from the source until all items are exhausted
             while self.next().is_some() {}
         }
+        self.release_budget();
     }
 }
 
@@ -77,6 +81,7 @@ pub fn create_buffer_run<T>(source: Vec<T>) -> ExternalRun<T, Box<dyn Read + Sen
         buffer,
         read_idx: 0,
         remaining_entries,
+        budget: None,
     }
 }
 
@@ -97,6 +102,7 @@ where
             read_idx: 0,
             remaining_entries: num_entries,
             source,
+            budget: None,
         };
 
         res.refill_buffer();
@@ -104,6 +110,41 @@ where
         res
     }
 
+    /// lets the read buffer grow to this run's share of the budget
+    /// as the other runs sharing it finish.
+    pub fn share_read_budget(&mut self, budget: ReadBudget) {
+        self.budget = Some(budget);
+    }
+
+    /// hands our share of the read budget to the runs that are still live.
+    /// Must only be called once the run is exhausted.
+    fn release_budget(&mut self) {
+        if let Some(budget) = self.budget.take() {
+            // the other runs may grow into our share right away,
+            // so our buffer has to go before we hand it over.
+            self.buffer = Vec::new();
+            budget.release();
+        }
+    }
+
+    /// moves past the provided number of items, which have been consumed.
+    fn advance(&mut self, count: usize) {
+        self.read_idx += count;
+        self.remaining_entries -= count;
+
+        if self.remaining_entries == 0 {
+            // the run is exhausted, so the remaining runs can have our share of the budget
+            // without waiting for the merge to ask us for the next item.
+            self.source.finalize();
+            self.release_budget();
+        } else if self.read_idx >= self.buffer.len() {
+            // we check if we need to refill the buffer in case we have reached the end
+            // we do this here to make sure that the peek is always inside
+            // the buffer as long as there are still items
+            self.refill_buffer();
+        }
+    }
+
     /// refills the read buffer.
     /// this should only be called if the read_idx is at the end of the buffer
     ///
@@ -146,6 +187,14 @@ where
             return;
         }
 
+        if let Some(budget) = &self.budget {
+            // there is no point in growing beyond the rest of the run.
+            let target = budget.share().min(self.remaining_entries);
+            if target > self.buffer.len() {
+                self.buffer.resize_with(target, MaybeUninit::uninit);
+            }
+        }
+
         let slice = unsafe {
             let start = self.buffer.as_mut_ptr() as *mut u8;
             std::slice::from_raw_parts_mut(start, self.buffer.len() * item_size)
@@ -185,6 +234,7 @@ where
     fn next(&mut self) -> Option<T> {
         if self.remaining_entries == 0 {
             self.source.finalize();
+            self.release_budget();
             return None;
         }
 
@@ -199,16 +249,7 @@ where
 
         // we consumed the value at the read_index so we need to make sure that we increment it
         // to maintain the buffer invariant
-        // as well as decrement the remaining entries in our run.
-        self.read_idx += 1;
-        self.remaining_entries -= 1;
-
-        // we check if we need to refill the buffer in case we have reached the end
-        // we do this here to make sure that the peek is always inside
-        // the buffer as long as there are still items
-        if self.read_idx >= self.buffer.len() {
-            self.refill_buffer();
-        }
+        self.advance(1);
 
         Some(result)
     }
@@ -216,6 +257,32 @@ where
     fn remaining_items(&self) -> usize {
         self.remaining_entries
     }
+
+    fn buffered(&self) -> &[T] {
+        if self.remaining_entries == 0 {
+            return &[];
+        }
+        // for zero sized types, the buffer may be longer than the run.
+        let end = self
+            .buffer
+            .len()
+            .min(self.read_idx + self.remaining_entries);
+        let items = &self.buffer[self.read_idx..end];
+        // SAFETY:
+        // everything from the read_idx to the end of the buffer is initialized,
+        // and MaybeUninit<T> has the same layout as T.
+        unsafe { &*(items as *const [MaybeUninit<T>] as *const [T]) }
+    }
+
+    fn take_buffered(&mut self, count: usize, out: &mut Vec<T>) {
+        assert!(count <= self.buffered().len());
+        // SAFETY: the buffered items are initialized, and we advance past them.
+        unsafe {
+            let items = self.buffer.as_ptr().add(self.read_idx) as *const T;
+            super::move_items(items, count, out);
+        }
+        self.advance(count);
+    }
 }
 
 #[cfg(test)]
@@ -250,7 +317,7 @@ mod test {
     #[test]
     fn test_drop() {
         let vec: Vec<i32> = (1..5).collect();
-        let data: Vec<_> = core::iter::repeat(&vec).take(20).cloned().collect();
+        let data: Vec<_> = core::iter::repeat_n(&vec, 20).cloned().collect();
         let tape = vec_to_tape(data);
         let mut run: ExternalRun<Vec<i32>, _> =
             ExternalRun::from_tape(tape, NonZeroUsize::new(4096).unwrap());
@@ -260,6 +327,83 @@ mod test {
         drop(run);
     }
 
+    #[test]
+    fn test_read_budget() {
+        let budget = ReadBudget::new(8, 2);
+        let size = NonZeroUsize::new(4).unwrap();
+        let mut short: ExternalRun<u32, _> =
+            ExternalRun::from_tape(vec_to_tape(vec![1u32; 2]), size);
+        let mut long = ExternalRun::from_tape(vec_to_tape((0..100u32).collect()), size);
+        short.share_read_budget(budget.clone());
+        long.share_read_budget(budget);
+
+        for expected in 0..4 {
+            assert_eq!(Some(expected), long.next());
+        }
+        assert_eq!(4, long.buffer.len());
+
+        while short.next().is_some() {}
+        for expected in 4..8 {
+            assert_eq!(Some(expected), long.next());
+        }
+        // the short run is done, so its share went to the long one
+        assert_eq!(8, long.buffer.len());
+        assert!(std::iter::from_fn(|| long.next()).eq(8..100));
+    }
+
+    #[test]
+    fn test_read_budget_total() {
+        let budget = ReadBudget::new(8, 4);
+        let size = NonZeroUsize::new(2).unwrap();
+        let mut runs: Vec<ExternalRun<u32, _>> = [2, 2, 100, 100]
+            .into_iter()
+            .map(|len| ExternalRun::from_tape(vec_to_tape((0..len).collect()), size))
+            .collect();
+        for run in &mut runs {
+            run.share_read_budget(budget.clone());
+        }
+        let capacity = |runs: &[ExternalRun<u32, _>]| -> usize {
+            runs.iter().map(|run| run.buffer.capacity()).sum()
+        };
+
+        for run in &mut runs[..2] {
+            assert_eq!(Some(0), run.next());
+            assert_eq!(Some(1), run.next());
+        }
+        // the exhausted runs hand back their buffers before the others grow
+        assert_eq!(0, capacity(&runs[..2]));
+        for run in &mut runs[2..] {
+            assert!(std::iter::from_fn(|| run.next()).take(10).eq(0..10));
+        }
+        assert!(capacity(&runs) <= 8);
+    }
+
+    #[test]
+    fn test_take_buffered() {
+        let budget = ReadBudget::new(8, 2);
+        let mut run: ExternalRun<Vec<u32>, _> = ExternalRun::from_tape(
+            vec_to_tape((0..100).map(|i| vec![i]).collect()),
+            NonZeroUsize::new(8).unwrap(),
+        );
+        // the other run of the budget is never read
+        run.share_read_budget(budget);
+
+        let mut taken = Vec::new();
+        while run.remaining_items() > 0 {
+            // takes the whole buffer every other time, leaving a single item otherwise
+            let buffered = run.buffered().len();
+            let count = if taken.len() % 2 == 0 {
+                buffered
+            } else {
+                buffered - 1
+            };
+            run.take_buffered(count.max(1), &mut taken);
+        }
+        assert!(taken.into_iter().eq((0..100).map(|i| vec![i])));
+        assert!(run.buffer.is_empty());
+        assert_eq!(None, run.next());
+    }
+
     #[test]
     fn works_with_vecs() {
         let d = (1..100).collect::<Vec<_>>();
@@ -0,0 +1,270 @@
+use std::{fs::File, io, marker::PhantomData, mem, os::fd::AsRawFd, ptr::NonNull};
+
+use super::Run;
+
+/// release the pages we are done with in steps of this size,
+/// so that we do not need a syscall for every page.
+const UNMAP_GRANULARITY: usize = 1 << 20;
+
+/// A run that reads its items directly from a memory mapping of the sort file.
+///
+/// This avoids copying the data into a read buffer first and leaves the
+/// readahead to the kernel. The pages we have moved past are unmapped
+/// as the run advances.
+pub struct MappedRun<T> {
+    /// the start of the part of the mapping that is still mapped.
+    /// This is always page aligned.
+    mapped_start: NonNull<u8>,
+    /// the number of bytes still mapped from mapped_start
+    mapped_len: usize,
+    /// the next item to return. Everything from here to the end
+    /// of the mapping holds initialized items.
+    next_item: *const T,
+    /// the number of items left in the run
+    remaining_entries: usize,
+    /// the page size of the system, queried once when mapping the run
+    page_size: usize,
+    phantom: PhantomData<T>,
+}
+
+// SAFETY: the mapping is owned exclusively by the run,
+// so it is sendable if the items are.
+unsafe impl<T: Send> Send for MappedRun<T> {}
+
+impl<T> MappedRun<T> {
+    /// Maps the num_entries items starting at the offset in the file.
+    ///
+    /// The data must have been written from properly initialized values of T
+    /// and the offset must be suitably aligned for T.
+    /// Zero sized types can not be mapped.
+    pub fn new(file: &File, offset: u64, num_entries: usize) -> io::Result<Self> {
+        let item_size = mem::size_of::<T>();
+        assert!(item_size > 0, "zero sized types can not be mapped");
+        debug_assert_eq!(0, offset % mem::align_of::<T>() as u64);
+
+        let page_size = page_size();
+        // mappings must start at page boundaries, so we may need to map
+        // a few bytes in front of our data.
+        let lead = (offset % page_size as u64) as usize;
+        let map_offset = offset - lead as u64;
+        let mapped_len = lead + num_entries * item_size;
+        if num_entries == 0 {
+            return Ok(Self {
+                mapped_start: NonNull::dangling(),
+                mapped_len: 0,
+                next_item: NonNull::dangling().as_ptr(),
+                remaining_entries: 0,
+                page_size,
+                phantom: PhantomData,
+            });
+        }
+
+        let map_offset = libc::off_t::try_from(map_offset)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset too large"))?;
+        // SAFETY: we create a new private read-only mapping, which does not alias any of our memory.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped_len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                map_offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the range was just mapped.
+        // Advice is only a hint, so failures do not matter.
+        unsafe {
+            libc::madvise(ptr, mapped_len, libc::MADV_SEQUENTIAL);
+        }
+
+        let mapped_start = NonNull::new(ptr.cast::<u8>()).expect("mmap returned null");
+        Ok(Self {
+            mapped_start,
+            mapped_len,
+            // SAFETY: the lead is part of the mapping.
+            next_item: unsafe { mapped_start.as_ptr().add(lead).cast() },
+            remaining_entries: num_entries,
+            page_size,
+            phantom: PhantomData,
+        })
+    }
+
+    /// unmaps the pages in front of the next item once enough of them have accumulated.
+    fn release_consumed(&mut self, force: bool) {
+        let consumed = self.next_item as usize - self.mapped_start.as_ptr() as usize;
+        let releasable = consumed - consumed % self.page_size;
+        if releasable == 0 || (!force && releasable < UNMAP_GRANULARITY) {
+            return;
+        }
+        // SAFETY: the pages lie in front of the next item, so they
+        // are no longer referenced by us.
+        unsafe {
+            libc::munmap(self.mapped_start.as_ptr().cast(), releasable);
+            self.mapped_start = NonNull::new_unchecked(self.mapped_start.as_ptr().add(releasable));
+        }
+        self.mapped_len -= releasable;
+    }
+}
+
+impl<T> Run<T> for MappedRun<T> {
+    fn peek(&self) -> Option<&T> {
+        if self.remaining_entries == 0 {
+            None
+        } else {
+            // SAFETY: while there are entries remaining, next_item points to
+            // an initialized and aligned item inside the mapping.
+            unsafe { Some(&*self.next_item) }
+        }
+    }
+
+    fn next(&mut self) -> Option<T> {
+        if self.remaining_entries == 0 {
+            return None;
+        }
+        // SAFETY: see peek. Since we advance past the item,
+        // it will never be read again.
+        let result = unsafe { self.next_item.read() };
+        self.remaining_entries -= 1;
+        // SAFETY: at most one past the end of the mapping
+        self.next_item = unsafe { self.next_item.add(1) };
+        self.release_consumed(self.remaining_entries == 0);
+        Some(result)
+    }
+
+    fn remaining_items(&self) -> usize {
+        self.remaining_entries
+    }
+
+    fn buffered(&self) -> &[T] {
+        if self.remaining_entries == 0 {
+            return &[];
+        }
+        // SAFETY: all remaining items are initialized and lie in the mapping.
+        unsafe { std::slice::from_raw_parts(self.next_item, self.remaining_entries) }
+    }
+
+    fn take_buffered(&mut self, count: usize, out: &mut Vec<T>) {
+        assert!(count <= self.remaining_entries);
+        // SAFETY: see buffered. We advance past the items,
+        // so they will never be read again.
+        unsafe {
+            super::move_items(self.next_item, count, out);
+            self.next_item = self.next_item.add(count);
+        }
+        self.remaining_entries -= count;
+        self.release_consumed(self.remaining_entries == 0);
+    }
+}
+
+impl<T> Drop for MappedRun<T> {
+    fn drop(&mut self) {
+        if mem::needs_drop::<T>() {
+            while self.next().is_some() {}
+        }
+        if self.mapped_len > 0 {
+            // SAFETY: the range is still mapped and nothing refers to it anymore.
+            unsafe {
+                libc::munmap(self.mapped_start.as_ptr().cast(), self.mapped_len);
+            }
+        }
+    }
+}
+
+fn page_size() -> usize {
+    // SAFETY: sysconf has no preconditions.
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    usize::try_from(size).unwrap_or(4096)
+}
+
+#[cfg(all(test, not(miri)))]
+mod test {
+    use std::io::Write;
+
+    use crate::{run::Run, ExtSortOrdExtension, ExtsortConfig};
+
+    use super::MappedRun;
+
+    fn file_with(data: &[u8]) -> std::fs::File {
+        let mut file = crate::tape::file::create_file(&std::env::temp_dir(), 0).unwrap();
+        file.write_all(data).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_read_items() {
+        // large enough to unmap a few times along the way
+        let data: Vec<u64> = (0..500_000).collect();
+        let bytes =
+            unsafe { std::slice::from_raw_parts(data.as_ptr().cast::<u8>(), data.len() * 8) };
+        let file = file_with(bytes);
+        let mut run = MappedRun::<u64>::new(&file, 0, data.len()).unwrap();
+        drop(file);
+
+        assert_eq!(Some(&0), run.peek());
+        let collected: Vec<u64> = std::iter::from_fn(|| run.next()).collect();
+        assert_eq!(data, collected);
+        assert_eq!(None, run.peek());
+    }
+
+    #[test]
+    fn test_take_buffered() {
+        let data: Vec<u64> = (0..500_000).collect();
+        let bytes =
+            unsafe { std::slice::from_raw_parts(data.as_ptr().cast::<u8>(), data.len() * 8) };
+        let file = file_with(bytes);
+        let mut run = MappedRun::<u64>::new(&file, 0, data.len()).unwrap();
+
+        let mut taken = Vec::new();
+        while run.remaining_items() > 0 {
+            let count = run.buffered().len().min(100_001);
+            run.take_buffered(count, &mut taken);
+        }
+        assert_eq!(data, taken);
+        assert_eq!(None, run.next());
+    }
+
+    #[test]
+    fn test_unaligned_offset() {
+        let mut bytes = vec![0u8; 5000];
+        bytes.extend((0..100u32).flat_map(u32::to_ne_bytes));
+        let file = file_with(&bytes);
+
+        let mut run = MappedRun::<u32>::new(&file, 5000, 100).unwrap();
+        assert_eq!(100, run.remaining_items());
+        let collected: Vec<u32> = std::iter::from_fn(|| run.next()).collect();
+        assert_eq!((0..100).collect::<Vec<_>>(), collected);
+    }
+
+    #[test]
+    fn test_empty() {
+        let file = file_with(&[]);
+        let mut run = MappedRun::<u32>::new(&file, 0, 0).unwrap();
+        assert_eq!(None, run.peek());
+        assert_eq!(None, run.next());
+    }
+
+    #[test]
+    fn test_memory_map_runs() {
+        let config = ExtsortConfig::with_buffer_size(16)
+            .temp_file_folder(std::env::temp_dir())
+            .memory_map_runs(true);
+        let sorted = (0..2000).rev().external_sort(config).unwrap();
+        assert!(sorted.eq(0..2000));
+
+        // items owning heap memory must be dropped exactly once
+        let config = ExtsortConfig::with_buffer_size(256)
+            .temp_file_folder(std::env::temp_dir())
+            .memory_map_runs(true);
+        let mut sorted = (0..100)
+            .rev()
+            .map(|i| vec![i; 3])
+            .external_sort(config)
+            .unwrap();
+        assert_eq!(Some(vec![0; 3]), sorted.next());
+        drop(sorted);
+    }
+}
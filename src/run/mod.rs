@@ -3,10 +3,80 @@ use std::io::Read;
 #[cfg(test)]
 pub(crate) mod buf_run;
 pub mod file_run;
+#[cfg(unix)]
+pub mod mapped_run;
+pub mod read_budget;
 pub mod split_backing;
 
 pub type BoxedRun<T> = file_run::ExternalRun<T, Box<dyn Read + Send>>;
 
+/// A run read back from a tape.
+pub enum TapeRun<T> {
+    /// the run is read through a buffer
+    Buffered(BoxedRun<T>),
+    /// the run is read directly from a mapping of the sort file
+    #[cfg(unix)]
+    Mapped(mapped_run::MappedRun<T>),
+}
+
+impl<T> From<BoxedRun<T>> for TapeRun<T> {
+    fn from(run: BoxedRun<T>) -> Self {
+        TapeRun::Buffered(run)
+    }
+}
+
+impl<T> Run<T> for TapeRun<T> {
+    fn peek(&self) -> Option<&T> {
+        match self {
+            TapeRun::Buffered(run) => run.peek(),
+            #[cfg(unix)]
+            TapeRun::Mapped(run) => run.peek(),
+        }
+    }
+
+    fn next(&mut self) -> Option<T> {
+        match self {
+            TapeRun::Buffered(run) => run.next(),
+            #[cfg(unix)]
+            TapeRun::Mapped(run) => run.next(),
+        }
+    }
+
+    fn remaining_items(&self) -> usize {
+        match self {
+            TapeRun::Buffered(run) => run.remaining_items(),
+            #[cfg(unix)]
+            TapeRun::Mapped(run) => run.remaining_items(),
+        }
+    }
+
+    fn buffered(&self) -> &[T] {
+        match self {
+            TapeRun::Buffered(run) => run.buffered(),
+            #[cfg(unix)]
+            TapeRun::Mapped(run) => run.buffered(),
+        }
+    }
+
+    fn take_buffered(&mut self, count: usize, out: &mut Vec<T>) {
+        match self {
+            TapeRun::Buffered(run) => run.take_buffered(count, out),
+            #[cfg(unix)]
+            TapeRun::Mapped(run) => run.take_buffered(count, out),
+        }
+    }
+}
+
+/// moves count items starting at the provided pointer to the end of out.
+///
+/// # Safety
+/// the items must be initialized, and must not be read again afterwards.
+unsafe fn move_items<T>(items: *const T, count: usize, out: &mut Vec<T>) {
+    out.reserve(count);
+    std::ptr::copy_nonoverlapping(items, out.as_mut_ptr().add(out.len()), count);
+    out.set_len(out.len() + count);
+}
+
 /// A run is a sequence of items in ascending order.
 pub trait Run<T> {
     /// peeks at the next value in the sequence.
@@ -19,4 +89,16 @@ pub trait Run<T> {
     /// returns the bounds on the remaining length of the run
     /// See https://doc.rust-lang.org/std/iter/trait.Iterator.html#method.size_hint
     fn remaining_items(&self) -> usize;
+
+    /// the items of the run that are already in memory, starting with the next one.
+    /// They will be returned by the next calls to next.
+    fn buffered(&self) -> &[T] {
+        self.peek().map(std::slice::from_ref).unwrap_or(&[])
+    }
+
+    /// moves the next count items of the run to the end of out.
+    /// count must not exceed the number of buffered items.
+    fn take_buffered(&mut self, count: usize, out: &mut Vec<T>) {
+        out.extend(std::iter::from_fn(|| self.next()).take(count));
+    }
 }
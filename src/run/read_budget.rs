@@ -0,0 +1,65 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// The read buffer space shared by the buffered runs of a merge.
+///
+/// Every run is entitled to an equal share of the total among the runs
+/// that are still live, so the share of a run grows as the others finish.
+#[derive(Clone)]
+pub struct ReadBudget {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// the number of items all read buffers together may hold
+    total_items: usize,
+    /// the number of runs that have not released their share yet
+    live_runs: AtomicUsize,
+}
+
+impl ReadBudget {
+    /// creates a budget of total_items shared between num_runs runs.
+    pub fn new(total_items: usize, num_runs: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                total_items,
+                live_runs: AtomicUsize::new(num_runs),
+            }),
+        }
+    }
+
+    /// the number of items each live run may currently hold in its read buffer.
+    ///
+    /// As runs only ever leave the budget, the shares never shrink,
+    /// and buffers grown to their current share never exceed the total together.
+    pub fn share(&self) -> usize {
+        let live_runs = self.inner.live_runs.load(Ordering::Relaxed).max(1);
+        self.inner.total_items / live_runs
+    }
+
+    /// hands the share of a finished run to the remaining ones.
+    /// Must be called at most once per run.
+    pub fn release(&self) {
+        self.inner.live_runs.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ReadBudget;
+
+    #[test]
+    fn test_share_grows() {
+        let budget = ReadBudget::new(100, 4);
+        assert_eq!(25, budget.share());
+        budget.release();
+        assert_eq!(33, budget.share());
+        budget.clone().release();
+        assert_eq!(50, budget.share());
+        budget.release();
+        budget.release();
+        assert_eq!(100, budget.share());
+    }
+}
@@ -1,34 +1,90 @@
 use std::{
-    io::{self, Read, Seek, SeekFrom, Write},
-    sync::{Arc, Mutex},
+    fs::File,
+    io::{self, Read, Write},
+    ops::Range,
+    sync::Arc,
 };
 
-use self::backing_wrapper::BackingWrapper;
+/// A backing that can be read from and written to at arbitrary positions
+/// through a shared reference.
+/// This allows many views to access it at the same time without
+/// having to coordinate a common seek position.
+pub trait PositionalBacking {
+    /// reads from the provided position without affecting other readers.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+    /// writes to the provided position without affecting other readers.
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<usize>;
+    fn flush(&self) -> io::Result<()>;
+    /// returns the current size of the backing in bytes
+    fn len(&self) -> io::Result<u64>;
+}
+
+// files support positional IO natively (pread/pwrite),
+// so there is no need to lock them.
+impl PositionalBacking for File {
+    #[cfg(unix)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+    #[cfg(windows)]
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    #[cfg(unix)]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::write_at(self, buf, offset)
+    }
+    #[cfg(windows)]
+    fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_write(self, buf, offset)
+    }
 
-mod backing_wrapper;
+    fn flush(&self) -> io::Result<()> {
+        // files opened by us do not buffer anything in userspace
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
 
-pub struct SplitView<T> {
-    backing: Arc<Mutex<backing_wrapper::BackingWrapper<T>>>,
+pub struct SplitView<B> {
+    backing: Arc<B>,
+    // the index of the first byte of the current segment in the file
+    segment_start: u64,
     // a past-the-end index of the current segment in the file
     segment_end: u64,
     // the current read position inside this segment
     current_index: u64,
 }
 
-impl<T> SplitView<T>
+impl<B> SplitView<B>
 where
-    T: Seek + Read + Send,
+    B: PositionalBacking,
 {
-    pub fn new(mut backing: T) -> io::Result<Self> {
-        let segment_end = backing.seek(SeekFrom::End(0))?;
+    pub fn new(backing: B) -> io::Result<Self> {
+        let segment_end = backing.len()?;
         Ok(Self {
-            backing: Arc::new(Mutex::new(BackingWrapper::new(backing))),
+            backing: Arc::new(backing),
+            segment_start: 0,
             segment_end,
             current_index: 0,
         })
     }
-    pub fn add_segment(&mut self) -> io::Result<SplitViewWrite<T>> {
-        let segment_start = self.backing.lock().unwrap().seek(SeekFrom::End(0))?;
+    /// the backing shared by all views
+    pub fn backing(&self) -> &B {
+        &self.backing
+    }
+
+    /// the range of the backing covered by this view
+    pub fn segment(&self) -> Range<u64> {
+        self.segment_start..self.segment_end
+    }
+
+    pub fn add_segment(&mut self) -> io::Result<SplitViewWrite<B>> {
+        let segment_start = self.backing.len()?;
         Ok(SplitViewWrite {
             backing: self.backing.clone(),
             length: 0,
@@ -37,44 +93,55 @@ where
     }
 }
 
-pub struct SplitViewWrite<T> {
-    backing: Arc<Mutex<BackingWrapper<T>>>,
+pub struct SplitViewWrite<B> {
+    backing: Arc<B>,
     segment_start: u64,
     length: u64,
 }
-impl<T> From<SplitViewWrite<T>> for SplitView<T> {
-    fn from(val: SplitViewWrite<T>) -> Self {
+impl<B> SplitViewWrite<B> {
+    /// the backing shared by all views
+    pub fn backing(&self) -> &B {
+        &self.backing
+    }
+
+    /// the range of the backing written so far
+    pub fn segment(&self) -> Range<u64> {
+        self.segment_start..self.segment_start + self.length
+    }
+}
+
+impl<B> From<SplitViewWrite<B>> for SplitView<B> {
+    fn from(val: SplitViewWrite<B>) -> Self {
         let segment_end = val.segment_start + val.length;
         SplitView {
             backing: val.backing,
+            segment_start: val.segment_start,
             segment_end,
             current_index: val.segment_start,
         }
     }
 }
 
-impl<T> Write for SplitViewWrite<T>
+impl<B> Write for SplitViewWrite<B>
 where
-    T: Write + Seek,
+    B: PositionalBacking,
 {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let bytes_written = self
             .backing
-            .lock()
-            .unwrap()
             .write_at(self.segment_start + self.length, buf)?;
         self.length += bytes_written as u64;
         Ok(bytes_written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.backing.lock().unwrap().flush()
+        self.backing.flush()
     }
 }
 
-impl<T> Read for SplitView<T>
+impl<B> Read for SplitView<B>
 where
-    T: Seek + Read,
+    B: PositionalBacking,
 {
     fn read(&mut self, mut buf: &mut [u8]) -> io::Result<usize> {
         let remaining_len = (self.segment_end - self.current_index) as usize;
@@ -84,7 +151,7 @@ where
         if buf.is_empty() {
             return Ok(0);
         }
-        let bytes_read = self.backing.lock().unwrap().read(self.current_index, buf)?;
+        let bytes_read = self.backing.read_at(self.current_index, buf)?;
         self.current_index += bytes_read as u64;
         Ok(bytes_read)
     }
@@ -92,15 +159,84 @@ where
 
 #[cfg(test)]
 mod test {
-    use std::io::{Cursor, Write};
+    use std::{
+        io::{self, Read, Write},
+        sync::Mutex,
+    };
+
+    use super::{PositionalBacking, SplitView};
+
+    /// an in-memory backing for testing
+    impl PositionalBacking for Mutex<Vec<u8>> {
+        fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+            let data = self.lock().unwrap();
+            let available = data.get(offset as usize..).unwrap_or(&[]);
+            let len = available.len().min(buf.len());
+            buf[..len].copy_from_slice(&available[..len]);
+            Ok(len)
+        }
+
+        fn write_at(&self, offset: u64, buf: &[u8]) -> io::Result<usize> {
+            let mut data = self.lock().unwrap();
+            let end = offset as usize + buf.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[offset as usize..end].copy_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
 
-    use super::SplitView;
+        fn len(&self) -> io::Result<u64> {
+            Ok(self.lock().unwrap().len() as u64)
+        }
+    }
 
     #[test]
     fn test_flush() {
-        let file = Cursor::new(vec![]);
+        let file = Mutex::new(vec![]);
         let mut wrapper = SplitView::new(file).unwrap();
         let mut view = wrapper.add_segment().unwrap();
         view.flush().unwrap();
     }
+
+    fn roundtrip_interleaved(backing: impl PositionalBacking) {
+        let mut base = SplitView::new(backing).unwrap();
+        let mut first = base.add_segment().unwrap();
+        first.write_all(&[1; 100]).unwrap();
+        let mut second = base.add_segment().unwrap();
+        second.write_all(&[2; 50]).unwrap();
+
+        let mut first = SplitView::from(first);
+        let mut second = SplitView::from(second);
+        let mut first_buf = vec![];
+        let mut second_buf = vec![];
+        let mut chunk = [0; 7];
+        loop {
+            let read_first = first.read(&mut chunk).unwrap();
+            first_buf.extend_from_slice(&chunk[..read_first]);
+            let read_second = second.read(&mut chunk).unwrap();
+            second_buf.extend_from_slice(&chunk[..read_second]);
+            if read_first == 0 && read_second == 0 {
+                break;
+            }
+        }
+        assert_eq!(vec![1; 100], first_buf);
+        assert_eq!(vec![2; 50], second_buf);
+    }
+
+    #[test]
+    fn test_interleaved_memory() {
+        roundtrip_interleaved(Mutex::new(vec![]));
+    }
+
+    #[test]
+    #[cfg(not(miri))]
+    fn test_interleaved_file() {
+        let file = crate::tape::file::create_file(&std::env::temp_dir(), 0).unwrap();
+        roundtrip_interleaved(file);
+    }
 }
@@ -1,16 +1,17 @@
-use std::io::{self};
+use std::{
+    io::{self},
+    num::NonZeroUsize,
+};
 
-use crate::{orderer::Orderer, run::BoxedRun};
+use crate::{orderer::Orderer, run::TapeRun};
 
 pub mod sequential;
-
-#[cfg(feature = "parallel_sort")]
 pub mod threaded;
 
 /// A struct to get the finalization results
 pub struct FinalizeContents<T, O, F> {
     /// the runs that were moved to disk during execution
-    pub tapes: Vec<BoxedRun<T>>,
+    pub tapes: Vec<TapeRun<T>>,
     /// the orderer supplied to the cleaner
     pub orderer: O,
     /// the sorting function supplied to the cleaner
@@ -28,11 +29,26 @@ where
     /// and ready for reuse.
     fn clean_buffer(&mut self, buffer: &mut Vec<T>) -> io::Result<()>;
 
-    /// constructs an initial buffer for sorting use, matching the configured size
+    /// constructs an initial buffer for sorting use.
+    /// The buffer may start out without any capacity and is grown by the sorter
+    /// up to the [buffer capacity](Self::buffer_capacity) as it fills.
     /// To avoid excessive resource consumption, only one buffer should be constructed
     /// using this method.
     fn get_buffer(&mut self) -> Vec<T>;
 
+    /// the number of items a buffer may hold before it needs to be cleaned.
+    fn buffer_capacity(&self) -> NonZeroUsize;
+
     /// stops the sorting process and returns the runs moved to disk.
-    fn finalize(self) -> io::Result<FinalizeContents<T, O, F>>;
+    ///
+    /// kept_items is the number of items the caller keeps in memory during the merge.
+    /// The read buffers of the runs share what is left of the sort buffer.
+    fn finalize(self, kept_items: usize) -> io::Result<FinalizeContents<T, O, F>>;
+}
+
+/// the part of the sort buffer left for the read buffers
+/// once the provided number of items is kept in memory.
+fn read_buffer_size(total: NonZeroUsize, kept_items: usize) -> NonZeroUsize {
+    let one = NonZeroUsize::new(1).unwrap();
+    NonZeroUsize::new(total.get().saturating_sub(kept_items)).unwrap_or(one)
 }
@@ -26,11 +26,17 @@ where
     }
 
     fn get_buffer(&mut self) -> Vec<T> {
-        Vec::with_capacity(self.buffer_cap.get())
+        Vec::new()
     }
 
-    fn finalize(self) -> io::Result<FinalizeContents<T, O, F>> {
-        let runs = self.tape_collection.into_tapes(self.buffer_cap);
+    fn buffer_capacity(&self) -> NonZeroUsize {
+        self.buffer_cap
+    }
+
+    fn finalize(self, kept_items: usize) -> io::Result<FinalizeContents<T, O, F>> {
+        let runs = self
+            .tape_collection
+            .into_tapes(read_buffer_size(self.buffer_cap, kept_items));
         let finalize_contents = FinalizeContents {
             orderer: self.orderer,
             sort_func: self.buffer_sort,
@@ -47,12 +53,7 @@ where
     pub fn new(config: ExtsortConfig, orderer: O, buffer_sort: F) -> Self {
         let max_buffer_size_nonzero = config.get_num_items_for::<T>();
 
-        let compression_choice = config.compression_choice();
-        let tape_collection = TapeCollection::<T>::new(
-            config.temp_file_folder,
-            NonZeroUsize::new(256).unwrap(),
-            compression_choice,
-        );
+        let tape_collection = TapeCollection::<T>::new(&config, NonZeroUsize::new(256).unwrap());
 
         Self {
             tape_collection,
@@ -1,6 +1,9 @@
 use std::{
     num::NonZeroUsize,
-    sync::mpsc::{Receiver, SyncSender},
+    sync::{
+        mpsc::{Receiver, SyncSender},
+        Arc, Mutex,
+    },
     thread::ScopedJoinHandle,
 };
 
@@ -8,11 +11,13 @@ use crate::{orderer::Orderer, tape::TapeCollection, ExtsortConfig};
 
 use super::*;
 
-/// A multithreaded buffer cleaner.
-/// The idea here is that we split our available sort buffer into 2 equal parts,
-/// and flush one buffer using a background thread while the main thread fills the
-/// other buffer.
-/// On every clean call, the buffers are swapped.
+// A multithreaded buffer cleaner.
+// The idea here is that we split our available sort buffer into N equal parts,
+// and sort and flush the full buffers using background threads while the main thread fills
+// the next buffer.
+// Each background thread writes to its own tape collection, so that sorting, compressing and
+// writing can happen on all of them at the same time. The collections are combined
+// once the sort is finalized.
 
 /// the cleaner object
 pub struct MultithreadedBufferCleaner<O, F> {
@@ -21,32 +26,29 @@ pub struct MultithreadedBufferCleaner<O, F> {
     buffer_sort: F,
 }
 
-/// A handle object to send commands to the background thread
-/// and receive responses from it.
+/// A handle object to send buffers to the background threads
+/// and receive the emptied buffers from them.
 ///
 /// Because our type T or the orderer might have a lifetime on it,
-/// the background thread needs to be scoped to only this sort call.
+/// the background threads need to be scoped to only this sort call.
 ///
 /// On the receive side, we can either receive a cleaned buffer or
 /// an IO error.
 pub struct MultithreadedBufferCleanerHandle<'scope, T, O, F> {
     rx: Receiver<io::Result<Vec<T>>>,
-    tx: SyncSender<BufferCleanerCommand<T>>,
-    finalize_handle: ScopedJoinHandle<'scope, FinalizeContents<T, O, F>>,
+    tx: SyncSender<Vec<T>>,
+    writer_handles: Vec<ScopedJoinHandle<'scope, io::Result<TapeCollection<T>>>>,
+    orderer: Arc<O>,
+    buffer_sort: F,
+    /// the capacity of each of the buffers
     buffer_capacity: NonZeroUsize,
-}
-
-/// the commands that may be sent to the background thread.
-enum BufferCleanerCommand<T> {
-    /// Instruct the background thread to write the provided buffer to disk
-    CleanBuffer(Vec<T>),
-    /// Instruct the background thread to finalize their runs and exit.
-    Finalize,
+    /// the capacity of all buffers combined
+    total_capacity: NonZeroUsize,
 }
 
 impl<O, F> MultithreadedBufferCleaner<O, F>
 where
-    O: Send,
+    O: Send + Sync,
 {
     pub fn new(config: ExtsortConfig, orderer: O, buffer_sort: F) -> Self {
         Self {
@@ -56,42 +58,64 @@ where
         }
     }
 
-    /// spawns the io thread and runs the provided closure with a command handle to that thread.
-    pub fn run<Fo, T, R>(self, func: Fo) -> R
+    /// spawns the io threads and runs the provided closure with a command handle to them.
+    /// fails if the threads could not be spawned.
+    pub fn run<Fo, T, R>(self, func: Fo) -> io::Result<R>
     where
         Fo: FnOnce(MultithreadedBufferCleanerHandle<T, O, F>) -> R,
-        F: FnMut(&O, &mut [T]) + Send,
+        F: FnMut(&O, &mut [T]) + Clone + Send,
+        O: Orderer<T>,
         T: Send,
     {
         std::thread::scope(move |scope| {
             let config = self.config;
 
-            let max_buffer_size_nonzero = config.get_num_items_for::<T>();
-            let max_buffer_size = max_buffer_size_nonzero.get();
+            let total_capacity = config.get_num_items_for::<T>();
+            let (num_buffers, num_writers) = config.pipeline_size();
+            let one = NonZeroUsize::new(1).unwrap();
+            let buffer_capacity =
+                NonZeroUsize::new(total_capacity.get() / num_buffers.get()).unwrap_or(one);
 
-            let compression_choice = config.compression_choice();
-            let tape_collection = TapeCollection::<T>::new(
-                config.temp_file_folder,
+            let tape_collections = TapeCollection::<T>::new_group(
+                &config,
                 NonZeroUsize::new(256).unwrap(),
-                compression_choice,
+                num_writers,
             );
 
-            let (worker_tx, rx) = std::sync::mpsc::sync_channel(1);
-            let (tx, worker_rx) = std::sync::mpsc::sync_channel(1);
-
-            let finalize_handle = std::thread::Builder::new()
-                .name("Sort-Buffer-Writer".to_owned())
-                .spawn_scoped(scope, move || {
-                    // we hold a second empty buffer ready to exchange with the main thread.
-                    let mut cleaned_buffer = Vec::with_capacity(max_buffer_size / 2);
-                    let orderer = self.orderer;
-                    let mut tape_collection = tape_collection;
-                    let mut buffer_sort = self.buffer_sort;
-                    loop {
-                        match worker_rx.recv().unwrap() {
-                            BufferCleanerCommand::CleanBuffer(mut buf) => {
-                                // first send the previously cleaned buffer so that the main thread can continue
-                                worker_tx.send(Ok(cleaned_buffer)).ok();
+            let (tx, worker_rx) = std::sync::mpsc::sync_channel::<Vec<T>>(num_buffers.get());
+            let (worker_tx, rx) = std::sync::mpsc::sync_channel(num_buffers.get());
+            let worker_rx = Arc::new(Mutex::new(worker_rx));
+
+            // the main thread holds one buffer, the others start out in the pool
+            // of cleaned buffers. They are only allocated once they are needed.
+            for _ in 1..num_buffers.get() {
+                worker_tx.send(Ok(Vec::new())).unwrap();
+            }
+
+            let orderer = Arc::new(self.orderer);
+            let writer_handles = tape_collections
+                .into_iter()
+                .enumerate()
+                .map(|(idx, mut tape_collection)| {
+                    let worker_rx = worker_rx.clone();
+                    let worker_tx = worker_tx.clone();
+                    let orderer = orderer.clone();
+                    let mut buffer_sort = self.buffer_sort.clone();
+                    let wrapper = config.writer_thread_wrapper.clone();
+                    let merge_fan_in = config.background_merge_fan_in;
+                    let builder = match &config.writer_thread_builder {
+                        Some(create_builder) => create_builder(idx),
+                        None => std::thread::Builder::new().name("Sort-Buffer-Writer".to_owned()),
+                    };
+                    builder.spawn_scoped(scope, move || {
+                        let body = move || {
+                            loop {
+                                // the lock is released as soon as we have received the buffer
+                                let received = worker_rx.lock().unwrap().recv();
+                                // the main thread hangs up once it is done.
+                                let Ok(mut buf) = received else {
+                                    break;
+                                };
                                 // sort the buffer
                                 (buffer_sort)(&orderer, &mut buf);
                                 // move it to disk
@@ -99,90 +123,261 @@ where
                                     worker_tx.send(Err(e)).ok();
                                     break;
                                 }
-                                // and mark it as cleaned for the next iteration
-                                cleaned_buffer = buf;
-                            }
-                            BufferCleanerCommand::Finalize => {
-                                // drop our half of the cleaned buffer before the tap finalization call
-                                // to avoid double memory consumption.
-                                drop(cleaned_buffer);
-                                // exit the loop to terminate the thread.
-                                break;
+                                if let Some(fan_in) = merge_fan_in {
+                                    // the merge gets the memory of the buffer,
+                                    // which is allocated again in full once it is done.
+                                    let capacity = buf.capacity();
+                                    drop(std::mem::take(&mut buf));
+                                    let merged =
+                                        tape_collection.compact(&*orderer, fan_in, buffer_capacity);
+                                    if let Err(e) = merged {
+                                        worker_tx.send(Err(e)).ok();
+                                        break;
+                                    }
+                                    buf = Vec::with_capacity(capacity);
+                                }
+                                // and hand it back for the main thread to fill again
+                                worker_tx.send(Ok(buf)).ok();
                             }
+                            tape_collection
                         };
-                    }
-                    // rewind all tapes and prefill read buffers
-                    let tapes = tape_collection.into_tapes(max_buffer_size_nonzero);
-                    FinalizeContents {
-                        tapes,
-                        orderer,
-                        sort_func: buffer_sort,
-                    }
+                        match wrapper {
+                            Some(wrapper) => {
+                                let mut body = Some(body);
+                                let mut result = None;
+                                wrapper(&mut || {
+                                    if let Some(body) = body.take() {
+                                        result = Some(body());
+                                    }
+                                });
+                                result.ok_or_else(|| {
+                                    io::Error::other(
+                                        "the writer thread wrapper did not run the writer",
+                                    )
+                                })
+                            }
+                            None => Ok(body()),
+                        }
+                    })
                 })
-                .unwrap();
+                .collect::<io::Result<Vec<_>>>()?;
+            // the writers hold the only remaining senders, so that the main thread
+            // notices once all of them have exited.
+            drop(worker_tx);
 
             let handle = MultithreadedBufferCleanerHandle {
                 rx,
                 tx,
-                finalize_handle,
-                buffer_capacity: max_buffer_size_nonzero,
+                writer_handles,
+                orderer,
+                buffer_sort: self.buffer_sort,
+                buffer_capacity,
+                total_capacity,
             };
 
             // run our processing function and pass the handle to it.
-            func(handle)
-        })
-    }
-}
-
-impl<T, O, F> MultithreadedBufferCleanerHandle<'_, T, O, F> {
-    /// convenience function that converts send errors to io errs
-    fn send(&mut self, command: BufferCleanerCommand<T>) -> io::Result<()> {
-        self.tx.send(command).map_err(|_buf| {
-            io::Error::new(
-                io::ErrorKind::BrokenPipe,
-                "the writer thread exited unexpectedly",
-            )
+            Ok(func(handle))
         })
     }
 }
 
 impl<T, O, F> BufferCleaner<T, O, F> for MultithreadedBufferCleanerHandle<'_, T, O, F>
 where
-    O: Orderer<T> + Send,
+    O: Orderer<T> + Send + Sync,
     T: Send,
     F: FnMut(&O, &mut [T]),
 {
-    /// clean the provided buffer by handing it over to the background thread
-    /// and swapping it with a newly cleaned buffer.
+    /// clean the provided buffer by handing it over to the background threads
+    /// and swapping it with a cleaned buffer.
     fn clean_buffer(&mut self, buffer: &mut Vec<T>) -> io::Result<()> {
         let buf = core::mem::take(buffer);
-        self.send(BufferCleanerCommand::CleanBuffer(buf))?;
+        self.tx.send(buf).map_err(|_buf| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the writer threads exited unexpectedly",
+            )
+        })?;
 
-        let buf = self
+        let mut buf = self
             .rx
             .recv()
             .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e))??;
+        // buffers that have not been used yet still need to be allocated
+        buf.reserve_exact(self.buffer_capacity.get());
         *buffer = buf;
         Ok(())
     }
 
-    // we can only hand out a buffer of half the allocated size because
-    // there is another, equally sized buffer in use by the background thread.
     fn get_buffer(&mut self) -> Vec<T> {
-        Vec::with_capacity(self.buffer_capacity.get() / 2)
+        Vec::new()
+    }
+
+    // we can only hand out a buffer of a part of the allocated size because
+    // the other, equally sized buffers are in use by the background threads.
+    fn buffer_capacity(&self) -> NonZeroUsize {
+        self.buffer_capacity
     }
 
-    fn finalize(mut self) -> io::Result<FinalizeContents<T, O, F>> {
-        // send the io command
-        self.send(BufferCleanerCommand::Finalize)?;
+    fn finalize(self, kept_items: usize) -> io::Result<FinalizeContents<T, O, F>> {
+        let Self {
+            rx,
+            tx,
+            writer_handles,
+            orderer,
+            buffer_sort,
+            total_capacity,
+            ..
+        } = self;
 
-        // ensure that we get notified about all errors (if any)
-        while let Ok(msg) = self.rx.recv() {
+        // hanging up tells the background threads to exit once all buffers are written.
+        drop(tx);
+
+        // ensure that we get notified about all errors (if any).
+        // the cleaned buffers are released right away to avoid
+        // double memory consumption with the read buffers.
+        while let Ok(msg) = rx.recv() {
             drop(msg?);
         }
 
         // and collect the final result
-        let res = self.finalize_handle.join().unwrap();
-        Ok(res)
+        let mut tape_collections = Vec::with_capacity(writer_handles.len());
+        for handle in writer_handles {
+            tape_collections.push(handle.join().unwrap()?);
+        }
+        let mut tape_collections = tape_collections.into_iter();
+        let mut tape_collection = tape_collections
+            .next()
+            .expect("there is at least one writer");
+        for other in tape_collections {
+            tape_collection.absorb(other);
+        }
+        let tapes = tape_collection.into_tapes(read_buffer_size(total_capacity, kept_items));
+
+        let orderer = Arc::try_unwrap(orderer)
+            .ok()
+            .expect("all writer threads have exited");
+        Ok(FinalizeContents {
+            tapes,
+            orderer,
+            sort_func: buffer_sort,
+        })
+    }
+}
+
+#[cfg(all(test, not(miri)))]
+mod test {
+    use crate::{
+        orderer::OrdOrderer,
+        run::Run,
+        sorter::{buffer_cleaner::BufferCleaner, ExtsortConfig},
+        ExtSortOrdExtension, Parallelism,
+    };
+
+    use super::MultithreadedBufferCleaner;
+
+    #[test]
+    fn test_pipeline() {
+        let config = ExtsortConfig::with_buffer_size(64)
+            .temp_file_folder(std::env::temp_dir())
+            .sort_buffer_count(4)
+            .writer_threads(3);
+        let cleaner = MultithreadedBufferCleaner::new(
+            config,
+            OrdOrderer::new(),
+            |_: &OrdOrderer, buf: &mut [u32]| buf.sort(),
+        );
+        let mut tapes = cleaner
+            .run(|mut handle| {
+                let mut buffer = handle.get_buffer();
+                assert_eq!(4, handle.buffer_capacity().get());
+                for run in 0..20u32 {
+                    buffer.extend((0..4).map(|i| run * 4 + 3 - i));
+                    handle.clean_buffer(&mut buffer).unwrap();
+                    assert!(buffer.is_empty());
+                }
+                handle.finalize(0).unwrap().tapes
+            })
+            .unwrap();
+        assert_eq!(20, tapes.len());
+        let mut items: Vec<u32> = tapes
+            .iter_mut()
+            .flat_map(|run| std::iter::from_fn(|| run.next()).collect::<Vec<_>>())
+            .collect();
+        items.sort();
+        assert!(items.into_iter().eq(0..80));
+    }
+
+    #[test]
+    fn test_writer_thread_hooks() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let wrapped = Arc::new(AtomicUsize::new(0));
+        let counter = wrapped.clone();
+        let config = ExtsortConfig::with_buffer_size(64)
+            .temp_file_folder(std::env::temp_dir())
+            .parallelism(Parallelism::BackgroundWriter)
+            .sort_buffer_count(3)
+            .writer_threads(2)
+            .writer_thread_builder(|idx| {
+                std::thread::Builder::new().name(format!("custom-writer-{idx}"))
+            })
+            .writer_thread_wrapper(move |body| {
+                assert!(std::thread::current()
+                    .name()
+                    .is_some_and(|name| name.starts_with("custom-writer-")));
+                counter.fetch_add(1, Ordering::Relaxed);
+                body();
+            });
+        let sorted = (0..1000).rev().external_sort(config).unwrap();
+        assert!(sorted.eq(0..1000));
+        assert_eq!(2, wrapped.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_writer_thread_wrapper_without_body() {
+        let config = ExtsortConfig::with_buffer_size(64)
+            .temp_file_folder(std::env::temp_dir())
+            .parallelism(Parallelism::BackgroundWriter)
+            .writer_thread_wrapper(|_body| {});
+        // the sort fails instead of panicking
+        let result = (0..1000).rev().external_sort(config);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_background_merge() {
+        let config = ExtsortConfig::with_buffer_size(64)
+            .temp_file_folder(std::env::temp_dir())
+            .parallelism(Parallelism::BackgroundWriter)
+            .background_merge(4);
+        let sorted = (0..5000).rev().external_sort(config).unwrap();
+        assert!(sorted.eq(0..5000));
+
+        // items owning heap memory move through several merges
+        let config = ExtsortConfig::with_buffer_size(256)
+            .temp_file_folder(std::env::temp_dir())
+            .parallelism(Parallelism::BackgroundWriter)
+            .sort_buffer_count(4)
+            .writer_threads(2)
+            .background_merge(2);
+        let sorted = (0..500)
+            .rev()
+            .map(|i| vec![i; 2])
+            .external_sort(config)
+            .unwrap();
+        assert!(sorted.eq((0..500).map(|i| vec![i; 2])));
+
+        // merged runs are compressed in chunks
+        #[cfg(feature = "compression_lz4_flex")]
+        {
+            let config = ExtsortConfig::with_buffer_size(64)
+                .temp_file_folder(std::env::temp_dir())
+                .compress_lz4_flex()
+                .parallelism(Parallelism::BackgroundWriter)
+                .background_merge(3);
+            let sorted = (0..5000).rev().external_sort(config).unwrap();
+            assert!(sorted.eq(0..5000));
+        }
     }
 }
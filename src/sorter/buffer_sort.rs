@@ -0,0 +1,65 @@
+#[cfg(feature = "parallel_sort")]
+use std::sync::Arc;
+
+use crate::{
+    orderer::{key_index, Orderer},
+    ExtsortConfig, Parallelism,
+};
+
+/// The strategy used to sort a single buffer.
+#[derive(Clone)]
+pub enum BufferSort {
+    /// sort on the current thread
+    Sequential,
+    /// sort using rayon, on the provided pool or the global one.
+    #[cfg(feature = "parallel_sort")]
+    Rayon(Option<Arc<rayon::ThreadPool>>),
+}
+
+impl BufferSort {
+    /// sets up the strategy matching the configuration.
+    /// The dedicated pool of [`Parallelism::Rayon`] must have been built
+    /// using [`ExtsortConfig::build_rayon_pool`] already.
+    pub fn new(config: &ExtsortConfig) -> Self {
+        match config.parallelism {
+            Parallelism::Sequential | Parallelism::BackgroundWriter => BufferSort::Sequential,
+            #[cfg(feature = "parallel_sort")]
+            Parallelism::Rayon { .. } => BufferSort::Rayon(config.rayon_pool.clone()),
+        }
+    }
+
+    pub fn sort<T, O>(&self, orderer: &O, buffer: &mut [T])
+    where
+        T: Send,
+        O: Orderer<T> + Sync,
+    {
+        match self {
+            BufferSort::Sequential => {
+                let compare = |a: &T, b: &T| orderer.compare(a, b);
+                // large items are sorted through their indices, so they are only moved once.
+                // Sharing the items with the rayon threads would need them to be Sync,
+                // so this is only done on the current thread.
+                let sorted = orderer.sort_buffer(buffer)
+                    || (key_index::prefer_index::<T>()
+                        && key_index::sort_by_index(buffer, compare));
+                if !sorted {
+                    buffer.sort_unstable_by(compare)
+                }
+            }
+            #[cfg(feature = "parallel_sort")]
+            BufferSort::Rayon(pool) => {
+                use rayon::slice::ParallelSliceMut;
+
+                let mut sort = || {
+                    if !orderer.par_sort_buffer(buffer) {
+                        buffer.par_sort_unstable_by(|a, b| orderer.compare(a, b))
+                    }
+                };
+                match pool {
+                    Some(pool) => pool.install(sort),
+                    None => sort(),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,666 @@
+//! Sorting by distributing the items into buckets of key ranges
+//! instead of merging sorted runs.
+//!
+//! A sample of the first buffer decides the splitters between the buckets.
+//! Every item is appended to the bucket of its range, or to the bucket of the splitter
+//! it is equal to. The buckets are written to disk as runs of unsorted items
+//! and sorted one by one in memory while the result is read, each of them using the rayon threads
+//! if the buffers are sorted in parallel. Buckets too large for that are distributed again.
+
+use std::{io, iter, marker::PhantomData, num::NonZeroUsize, vec};
+
+use crate::{
+    orderer::{key_index::apply_permutation, Orderer},
+    run::{file_run::create_buffer_run, Run, TapeRun},
+    tape::{quota::TempQuota, TapeCollection},
+    ExtsortConfig,
+};
+
+use super::{buffer_sort::BufferSort, fill_buffer, result_iter::ResultIterator};
+
+/// the number of sampled items per bucket the splitters are chosen from
+const OVERSAMPLING: usize = 16;
+
+/// the share of the sort buffer used to read the buckets back from disk.
+const READ_BUFFER_SHARE: usize = 8;
+
+/// the most items that are distributed at once, so that they can be indexed using u32.
+const MAX_BATCH_ITEMS: usize = u32::MAX as usize - 1;
+
+/// the maximum number of sort files shared by the buckets
+const MAX_FILES: usize = 256;
+
+/// Sorts the source by distributing the items into the provided number of buckets.
+///
+/// The buffers are sorted using the buffer_sort.
+pub(crate) fn sort<T, O>(
+    mut source: impl Iterator<Item = T>,
+    config: ExtsortConfig,
+    orderer: O,
+    buffer_sort: BufferSort,
+    buckets: usize,
+) -> io::Result<ResultIterator<T, O>>
+where
+    T: Send,
+    O: Orderer<T> + Sync,
+{
+    // distributing a buffer takes a bucket id and a position of the permutation per item.
+    let item_bytes = std::mem::size_of::<T>() + 2 * std::mem::size_of::<u32>();
+    let capacity = (config.sort_buffer_size_bytes / item_bytes).clamp(1, MAX_BATCH_ITEMS);
+    let mut buffer = Vec::new();
+    fill_buffer(&mut source, &mut buffer, capacity);
+    if buffer.len() < capacity {
+        // everything fits into memory, so there is nothing to distribute.
+        buffer_sort.sort(&orderer, &mut buffer);
+        buffer.shrink_to_fit();
+        let buffer_run = create_buffer_run(buffer);
+        return Ok(ResultIterator::new(vec![buffer_run.into()], orderer));
+    }
+
+    let one = NonZeroUsize::new(1).unwrap();
+    let read_items = NonZeroUsize::new(capacity / READ_BUFFER_SHARE).unwrap_or(one);
+    let distributor = Distributor {
+        config: &config,
+        orderer: &orderer,
+        quota: TempQuota::new(config.temp_limit()),
+        ranges: buckets.max(2),
+        bucket_items: (capacity - read_items.get()).max(1),
+        read_items,
+        phantom: PhantomData,
+    };
+    let max_files = NonZeroUsize::new(MAX_FILES).unwrap();
+    // the input is not stored in temp storage.
+    let buckets = distributor.distribute(buffer, source, 0, capacity, max_files)?;
+
+    Ok(ResultIterator::from_buckets(Buckets {
+        remaining: buckets.iter().map(Bucket::len).sum(),
+        buckets: buckets.into_iter(),
+        current: Current::Equal(Vec::new()),
+        orderer,
+        buffer_sort,
+        sort_func: BufferSort::sort,
+        read_items,
+    }))
+}
+
+/// Sorts a buffer using the strategy.
+/// The result iterator does not know that the items may be sorted on other threads,
+/// so the sort function is chosen when the buckets are created.
+type SortFunc<T, O> = fn(&BufferSort, &O, &mut [T]);
+
+/// The items of a range of keys, written to disk in the order they arrived.
+enum Bucket<T> {
+    /// the items between two splitters
+    Range {
+        tapes: TapeCollection<T>,
+        len: usize,
+    },
+    /// the items equal to the splitter, which need no sorting
+    Equal {
+        splitter: T,
+        tapes: TapeCollection<T>,
+        len: usize,
+    },
+}
+
+impl<T> Bucket<T> {
+    fn len(&self) -> usize {
+        match self {
+            Bucket::Range { len, .. } => *len,
+            Bucket::Equal { len, .. } => len + 1,
+        }
+    }
+}
+
+struct Distributor<'a, T, O> {
+    config: &'a ExtsortConfig,
+    orderer: &'a O,
+    /// all buckets are held to the same temp storage limit
+    quota: TempQuota,
+    /// the number of ranges to split the items into, unless there are too few files
+    ranges: usize,
+    /// the most items a bucket may hold to be sorted in memory
+    bucket_items: usize,
+    /// the size of the read buffers of the buckets
+    read_items: NonZeroUsize,
+    phantom: PhantomData<T>,
+}
+
+impl<T, O: Orderer<T>> Distributor<'_, T, O> {
+    /// Distributes the items of the full buffer and the source into buckets,
+    /// refilling the buffer up to buffer_items items at a time.
+    /// Buckets that are too large to be sorted in memory are distributed again.
+    /// Returns the buckets in ascending order.
+    ///
+    /// The source takes up source_bytes of temp storage, which are
+    /// released once it has been distributed.
+    fn distribute(
+        &self,
+        mut buffer: Vec<T>,
+        mut source: impl Iterator<Item = T>,
+        source_bytes: u64,
+        buffer_items: usize,
+        max_files: NonZeroUsize,
+    ) -> io::Result<Vec<Bucket<T>>> {
+        let splitters = self.take_splitters(&mut buffer, self.ranges_for(max_files));
+        // ranges and splitter buckets alternate, starting and ending with a range.
+        let count = NonZeroUsize::new(2 * splitters.len() + 1).unwrap();
+        let mut tapes =
+            TapeCollection::new_group_sharing(self.config, max_files, count, &self.quota);
+        let mut lens = vec![0; count.get()];
+        let written = loop {
+            if buffer.is_empty() {
+                break Ok(());
+            }
+            if let Err(e) = self.write_buckets(&mut buffer, &splitters, &mut tapes, &mut lens) {
+                break Err(e);
+            }
+            fill_buffer(&mut source, &mut buffer, buffer_items);
+        };
+        // release the memory and storage before distributing any of the buckets again.
+        drop(buffer);
+        drop(source);
+        self.quota.release(source_bytes);
+        written?;
+        self.collect_buckets(splitters, tapes, lens, max_files)
+    }
+
+    /// Puts the written buckets in order, distributing those that are too large again.
+    /// This is kept apart from the generic source of the items,
+    /// which would otherwise grow with every level of distribution.
+    fn collect_buckets(
+        &self,
+        splitters: Vec<T>,
+        tapes: Vec<TapeCollection<T>>,
+        lens: Vec<usize>,
+        max_files: NonZeroUsize,
+    ) -> io::Result<Vec<Bucket<T>>> {
+        let count = tapes.len();
+        let one = NonZeroUsize::new(1).unwrap();
+        let files_each = NonZeroUsize::new(max_files.get() / count).unwrap_or(one);
+        let mut splitters = splitters.into_iter();
+        let mut buckets = Vec::with_capacity(count);
+        for (idx, (tapes, len)) in tapes.into_iter().zip(lens).enumerate() {
+            if idx % 2 == 1 {
+                let splitter = splitters.next().unwrap();
+                buckets.push(Bucket::Equal {
+                    splitter,
+                    tapes,
+                    len,
+                });
+            } else if len > self.bucket_items {
+                // each bucket only uses the files it was given, so the number
+                // of open files does not grow as we go deeper.
+                // All runs of the bucket are consumed, freeing their space even if they share files.
+                let bytes = tapes.len_bytes();
+                let mut items = tapes
+                    .into_tapes(self.read_items)
+                    .into_iter()
+                    .flat_map(|mut run| iter::from_fn(move || run.next()));
+                let mut buffer = Vec::new();
+                fill_buffer(&mut items, &mut buffer, self.bucket_items);
+                buckets.extend(self.distribute(
+                    buffer,
+                    items,
+                    bytes,
+                    self.bucket_items,
+                    files_each,
+                )?);
+            } else if len > 0 {
+                buckets.push(Bucket::Range { tapes, len });
+            }
+        }
+        Ok(buckets)
+    }
+
+    /// the number of ranges to split the items into, so that each range
+    /// and each splitter between them can have a sort file of its own.
+    /// Buckets that are distributed again with fewer files are split into fewer ranges.
+    fn ranges_for(&self, max_files: NonZeroUsize) -> usize {
+        self.ranges.min(max_files.get().div_ceil(2)).max(2)
+    }
+
+    /// Chooses the splitters from a sample of the buffer and takes them out of it.
+    /// The splitters are distinct and in ascending order, and there is at least one.
+    fn take_splitters(&self, buffer: &mut Vec<T>, ranges: usize) -> Vec<T> {
+        let len = buffer.len();
+        let sample_len = (ranges * OVERSAMPLING).min(len);
+        // the positions of a sample spread evenly across the buffer, ordered by their items
+        let mut sample: Vec<usize> = (0..sample_len).map(|idx| idx * len / sample_len).collect();
+        sample.sort_unstable_by(|&a, &b| self.orderer.compare(&buffer[a], &buffer[b]));
+
+        // the positions of the splitters, in the order of the splitters
+        let mut chosen: Vec<usize> = Vec::with_capacity(ranges);
+        for pos in (1..ranges).map(|range| sample[range * sample_len / ranges]) {
+            let is_new = chosen
+                .last()
+                .is_none_or(|&last| self.orderer.compare(&buffer[last], &buffer[pos]).is_lt());
+            if is_new {
+                chosen.push(pos);
+            }
+        }
+
+        // removing from the back of the buffer first keeps the positions
+        // of the remaining splitters intact.
+        let mut by_position: Vec<(usize, usize)> = chosen
+            .into_iter()
+            .enumerate()
+            .map(|(rank, pos)| (pos, rank))
+            .collect();
+        by_position.sort_unstable_by(|a, b| b.cmp(a));
+        let mut splitters: Vec<(usize, T)> = by_position
+            .into_iter()
+            .map(|(pos, rank)| (rank, buffer.swap_remove(pos)))
+            .collect();
+        splitters.sort_unstable_by_key(|&(rank, _)| rank);
+        splitters
+            .into_iter()
+            .map(|(_, splitter)| splitter)
+            .collect()
+    }
+
+    /// returns the index of the bucket the item belongs to
+    fn classify(&self, splitters: &[T], item: &T) -> usize {
+        let idx =
+            splitters.partition_point(|splitter| self.orderer.compare(splitter, item).is_lt());
+        match splitters.get(idx) {
+            Some(splitter) if self.orderer.compare(item, splitter).is_eq() => 2 * idx + 1,
+            _ => 2 * idx,
+        }
+    }
+
+    /// groups the items of the buffer by their bucket
+    /// and appends each group to its bucket as a run.
+    fn write_buckets(
+        &self,
+        buffer: &mut Vec<T>,
+        splitters: &[T],
+        tapes: &mut [TapeCollection<T>],
+        lens: &mut [usize],
+    ) -> io::Result<()> {
+        let buckets: Vec<u32> = buffer
+            .iter()
+            .map(|item| self.classify(splitters, item) as u32)
+            .collect();
+
+        // the start of the group of each bucket, as in a counting sort
+        let mut starts = vec![0; tapes.len() + 1];
+        for &bucket in &buckets {
+            starts[bucket as usize + 1] += 1;
+        }
+        for idx in 1..starts.len() {
+            starts[idx] += starts[idx - 1];
+        }
+        let mut next = starts.clone();
+        let mut sources = vec![0u32; buffer.len()];
+        for (idx, &bucket) in (0..).zip(&buckets) {
+            sources[next[bucket as usize]] = idx;
+            next[bucket as usize] += 1;
+        }
+        drop(buckets);
+        apply_permutation(buffer, &mut sources);
+
+        // the groups are taken off the end of the buffer
+        for bucket in (0..tapes.len()).rev() {
+            let start = starts[bucket];
+            if start < buffer.len() {
+                lens[bucket] += buffer.len() - start;
+                tapes[bucket].add_run_tail(buffer, start)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the items of the buckets, sorting each in memory once it is reached.
+pub(crate) struct Buckets<T, O> {
+    buckets: vec::IntoIter<Bucket<T>>,
+    /// the items of the current bucket
+    current: Current<T>,
+    orderer: O,
+    buffer_sort: BufferSort,
+    sort_func: SortFunc<T, O>,
+    /// the size of the read buffers of each bucket
+    read_items: NonZeroUsize,
+    remaining: usize,
+}
+
+enum Current<T> {
+    /// the sorted items of a range
+    Sorted(vec::IntoIter<T>),
+    /// the runs of items equal to a splitter
+    Equal(Vec<TapeRun<T>>),
+}
+
+impl<T> Current<T> {
+    fn next(&mut self) -> Option<T> {
+        match self {
+            Current::Sorted(items) => items.next(),
+            Current::Equal(runs) => {
+                while let Some(run) = runs.last_mut() {
+                    if let Some(item) = run.next() {
+                        return Some(item);
+                    }
+                    runs.pop();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<T, O> Buckets<T, O> {
+    /// returns the number of items that have not been returned yet.
+    pub fn remaining_items(&self) -> usize {
+        self.remaining
+    }
+
+    /// reads the bucket back, sorting its items if needed.
+    fn open(&self, bucket: Bucket<T>) -> Current<T> {
+        match bucket {
+            Bucket::Range { tapes, len } => {
+                let mut items = Vec::with_capacity(len);
+                for mut run in tapes.into_tapes(self.read_items) {
+                    items.extend(iter::from_fn(|| run.next()));
+                }
+                (self.sort_func)(&self.buffer_sort, &self.orderer, &mut items);
+                Current::Sorted(items.into_iter())
+            }
+            Bucket::Equal {
+                splitter,
+                tapes,
+                len: _,
+            } => {
+                let mut runs = tapes.into_tapes(self.read_items);
+                runs.push(create_buffer_run(vec![splitter]).into());
+                Current::Equal(runs)
+            }
+        }
+    }
+}
+
+impl<T, O> Iterator for Buckets<T, O> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        loop {
+            if let Some(item) = self.current.next() {
+                self.remaining -= 1;
+                return Some(item);
+            }
+            let bucket = self.buckets.next()?;
+            // release the previous bucket before reading the next one.
+            self.current = Current::Equal(Vec::new());
+            self.current = self.open(bucket);
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::marker::PhantomData;
+    use std::num::NonZeroUsize;
+
+    use rand::Rng;
+
+    use crate::{
+        orderer::OrdOrderer, tape::quota::TempQuota, ExtSortByExtension, ExtSortOrdExtension,
+        ExtsortConfig,
+    };
+
+    use super::{sort, BufferSort, Distributor};
+
+    fn distributor<'a>(
+        config: &'a ExtsortConfig,
+        orderer: &'a OrdOrderer,
+        ranges: usize,
+    ) -> Distributor<'a, u32, OrdOrderer> {
+        Distributor {
+            config,
+            orderer,
+            quota: TempQuota::unlimited(),
+            ranges,
+            bucket_items: 100,
+            read_items: NonZeroUsize::new(10).unwrap(),
+            phantom: PhantomData,
+        }
+    }
+
+    #[test]
+    fn test_take_splitters() {
+        let config = ExtsortConfig::new();
+        let orderer = OrdOrderer::new();
+        let distributor = distributor(&config, &orderer, 4);
+
+        let mut buffer: Vec<u32> = (0..1000).rev().collect();
+        let splitters = distributor.take_splitters(&mut buffer, 4);
+        assert_eq!(3, splitters.len());
+        assert!(splitters.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(997, buffer.len());
+        assert!(splitters.iter().all(|s| !buffer.contains(s)));
+
+        // duplicate splitters are dropped
+        let mut buffer = vec![7; 1000];
+        assert_eq!(vec![7], distributor.take_splitters(&mut buffer, 4));
+        assert_eq!(999, buffer.len());
+
+        let mut buffer = vec![3];
+        assert_eq!(vec![3], distributor.take_splitters(&mut buffer, 4));
+        assert!(buffer.is_empty());
+
+        // the sample is spread evenly, so the splitters are the quartiles
+        let mut buffer: Vec<u32> = (0..1000).collect();
+        assert_eq!(
+            vec![250, 500, 750],
+            distributor.take_splitters(&mut buffer, 4)
+        );
+        let mut rest = buffer.clone();
+        rest.sort();
+        assert!(rest
+            .iter()
+            .copied()
+            .eq((0..1000).filter(|i| i % 250 != 0 || *i == 0)));
+    }
+
+    #[test]
+    fn test_ranges_for() {
+        let config = ExtsortConfig::new();
+        let orderer = OrdOrderer::new();
+        let distributor = distributor(&config, &orderer, 3000);
+        for max_files in [256, 255, 3] {
+            let ranges = distributor.ranges_for(NonZeroUsize::new(max_files).unwrap());
+            // the ranges and the splitters between them
+            assert!(2 * ranges - 1 <= max_files);
+        }
+        assert_eq!(128, distributor.ranges_for(NonZeroUsize::new(256).unwrap()));
+        assert_eq!(2, distributor.ranges_for(NonZeroUsize::new(1).unwrap()));
+        let distributor = super::Distributor {
+            ranges: 4,
+            ..distributor
+        };
+        assert_eq!(4, distributor.ranges_for(NonZeroUsize::new(256).unwrap()));
+    }
+
+    #[test]
+    fn test_classify() {
+        let config = ExtsortConfig::new();
+        let orderer = OrdOrderer::new();
+        let distributor = distributor(&config, &orderer, 4);
+        let splitters = [10, 20];
+        let buckets: Vec<usize> = [0, 10, 15, 20, 25]
+            .iter()
+            .map(|item| distributor.classify(&splitters, item))
+            .collect();
+        assert_eq!(vec![0, 1, 2, 3, 4], buckets);
+    }
+
+    fn sort_distributed(items: Vec<u32>, buffer_items: usize, buckets: usize) -> Vec<u32> {
+        let config = ExtsortConfig::with_buffer_size(buffer_items * 4)
+            .temp_file_folder(std::env::temp_dir());
+        let buffer_sort = BufferSort::new(&config);
+        let sorted = sort(
+            items.into_iter(),
+            config,
+            OrdOrderer::new(),
+            buffer_sort,
+            buckets,
+        )
+        .unwrap();
+        assert_eq!(sorted.len(), sorted.size_hint().0);
+        sorted.collect()
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_distribution_sort() {
+        let mut rng = rand::thread_rng();
+        let items: Vec<u32> = (0..20_000).map(|_| rng.gen()).collect();
+        let mut expected = items.clone();
+        expected.sort();
+
+        assert_eq!(expected, sort_distributed(items.clone(), 1000, 16));
+        // few buckets need to be distributed again
+        assert_eq!(expected, sort_distributed(items.clone(), 1000, 2));
+        // more buckets than sort files
+        assert_eq!(expected, sort_distributed(items, 1000, 3000));
+
+        // fits into memory
+        assert_eq!(vec![1, 2, 3], sort_distributed(vec![3, 1, 2], 1000, 16));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_distribution_sort_refunds_quota() {
+        // 80k bytes are distributed, and then distributed again several times
+        // as two buckets hold far more than a buffer.
+        let config = ExtsortConfig::with_buffer_size(4000)
+            .temp_file_folder(std::env::temp_dir())
+            .max_temp_bytes(150_000);
+        let sorted = sort(
+            (0..20_000u32).map(|i| i * 7919 % 20_000),
+            config,
+            OrdOrderer::new(),
+            BufferSort::new(&ExtsortConfig::new()),
+            2,
+        )
+        .unwrap();
+        assert!(sorted.eq(0..20_000));
+    }
+
+    #[test]
+    #[cfg(feature = "parallel_sort")]
+    #[cfg_attr(miri, ignore)]
+    fn test_distribution_sort_rayon() {
+        use std::{
+            cmp::Ordering,
+            sync::atomic::{AtomicUsize, Ordering::Relaxed},
+        };
+
+        use crate::{orderer::Orderer, Parallelism};
+
+        /// counts the buffers sorted in parallel
+        #[derive(Default)]
+        struct Counting(AtomicUsize);
+        impl Orderer<u32> for Counting {
+            fn compare(&self, left: &u32, right: &u32) -> Ordering {
+                left.cmp(right)
+            }
+
+            fn par_sort_buffer(&self, _buffer: &mut [u32]) -> bool {
+                self.0.fetch_add(1, Relaxed);
+                false
+            }
+        }
+
+        let config = ExtsortConfig::with_buffer_size(4000)
+            .temp_file_folder(std::env::temp_dir())
+            .parallelism(Parallelism::Rayon { threads: 2 })
+            .build_rayon_pool()
+            .unwrap();
+        let buffer_sort = BufferSort::new(&config);
+        let orderer = Counting::default();
+        let sorted = sort(
+            (0..20_000u32).map(|i| i * 7919 % 20_000),
+            config,
+            &orderer,
+            buffer_sort,
+            16,
+        )
+        .unwrap();
+        assert!(sorted.eq(0..20_000));
+        // every bucket of a range is sorted using the rayon pool
+        assert!(orderer.0.load(Relaxed) >= 16);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_distribution_sort_duplicates() {
+        let mut rng = rand::thread_rng();
+        // a few values make up most of the items, which end up in the buckets of the splitters
+        let items: Vec<u32> = (0..20_000)
+            .map(|_| match rng.gen_range(0..4) {
+                0 => rng.gen(),
+                other => other * 1000,
+            })
+            .collect();
+        let mut expected = items.clone();
+        expected.sort();
+        assert_eq!(expected, sort_distributed(items, 500, 8));
+
+        assert_eq!(vec![5; 10_000], sort_distributed(vec![5; 10_000], 500, 8));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_external_sort_distributed() {
+        let words: Vec<String> = (0..5000u32)
+            .map(|i| format!("w{}", i.wrapping_mul(2_654_435_761) % 3000))
+            .collect();
+        let mut expected = words.clone();
+        expected.sort();
+
+        let config = ExtsortConfig::with_buffer_size(4096)
+            .temp_file_folder(std::env::temp_dir())
+            .distribution_sort(16);
+        let sorted = words.iter().cloned().external_sort(config).unwrap();
+        assert_eq!(5000, sorted.len());
+        // the buckets are read back on another thread
+        let sorted = std::thread::spawn(move || sorted.collect::<Vec<_>>())
+            .join()
+            .unwrap();
+        assert_eq!(expected, sorted);
+
+        // most keys are equal, which the buckets of the splitters take care of
+        let config = ExtsortConfig::with_buffer_size(1024)
+            .temp_file_folder(std::env::temp_dir())
+            .distribution_sort(4);
+        let sorted = (0..10_000u64)
+            .map(|i| if i % 10 == 0 { i } else { 42 })
+            .external_sort_by_key(config, |&i| i)
+            .unwrap();
+        let mut expected: Vec<u64> = (0..10_000u64)
+            .map(|i| if i % 10 == 0 { i } else { 42 })
+            .collect();
+        expected.sort();
+        assert!(sorted.eq(expected));
+
+        #[cfg(feature = "parallel_sort")]
+        {
+            use crate::Parallelism;
+
+            let config = ExtsortConfig::with_buffer_size(8 * 1024)
+                .temp_file_folder(std::env::temp_dir())
+                .distribution_sort(8)
+                .parallelism(Parallelism::Rayon { threads: 2 });
+            let sorted = (0..50_000u64)
+                .map(|i| i * 7919 % 50_000)
+                .external_sort(config)
+                .unwrap();
+            assert!(sorted.eq(0..50_000));
+        }
+    }
+}
@@ -2,26 +2,103 @@ use std::{
     io::{self},
     num::NonZeroUsize,
     path::PathBuf,
+    sync::Arc,
+    thread,
 };
 
 use crate::{
-    orderer::Orderer, run::file_run::create_buffer_run, sorter::buffer_cleaner::BufferCleaner,
-    tape::compressor::CompressionCodec,
+    orderer::Orderer,
+    run::file_run::create_buffer_run,
+    sorter::buffer_cleaner::BufferCleaner,
+    tape::{
+        compressor::{CompressionCodec, CompressionPool},
+        file,
+        throttle::IoRateLimiter,
+    },
 };
 
-use self::result_iter::ResultIterator;
+use self::{
+    buffer_cleaner::{
+        sequential::SingleThreadedBufferCleaner, threaded::MultithreadedBufferCleaner,
+    },
+    buffer_sort::BufferSort,
+    result_iter::ResultIterator,
+};
 
 pub mod buffer_cleaner;
+mod buffer_sort;
+mod distribution;
 pub mod result_iter;
 
+/// How the sort distributes its work across threads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Parallelism {
+    /// Sorts and writes all buffers on the calling thread.
+    #[default]
+    Sequential,
+    /// Sorts and writes full buffers on background threads while the calling thread
+    /// fills the next buffer.
+    ///
+    /// The sort buffer is split between the buffers in flight, see
+    /// [`ExtsortConfig::sort_buffer_count`] and [`ExtsortConfig::writer_threads`].
+    BackgroundWriter,
+    /// Like [`BackgroundWriter`](Self::BackgroundWriter), but every buffer is additionally
+    /// sorted in parallel using rayon.
+    ///
+    /// With 0 threads, the global rayon thread pool is used.
+    /// Otherwise, a dedicated pool with the given number of threads is created for the sort.
+    #[cfg(feature = "parallel_sort")]
+    Rayon { threads: usize },
+}
+
+/// Creates the builder for the background writer thread with the provided index.
+pub type WriterThreadBuilder = Arc<dyn Fn(usize) -> thread::Builder + Send + Sync>;
+/// Runs the body of a background writer thread, which is passed as the argument.
+pub type WriterThreadWrapper = Arc<dyn Fn(&mut dyn FnMut()) + Send + Sync>;
+
 /// The configuration for the external sorting.
 #[non_exhaustive]
 pub struct ExtsortConfig {
     /// the maximum size of the sort buffer
     pub(crate) sort_buffer_size_bytes: usize,
     pub temp_file_folder: PathBuf,
+    /// folders to move to when the temp file folder runs out of space
+    pub(crate) fallback_temp_file_folders: Vec<PathBuf>,
     #[cfg(feature = "compression")]
     pub compress_with: CompressionCodec,
+    /// the maximum number of bytes that may be written to temp storage
+    pub(crate) max_temp_bytes: Option<u64>,
+    /// whether to check for sufficient disk space before starting the sort
+    pub(crate) check_disk_space: bool,
+    /// limits the bandwidth used for the sort files
+    pub(crate) io_rate_limiter: Option<IoRateLimiter>,
+    /// whether to give the kernel hints on how we access the sort files
+    pub(crate) page_cache_hints: bool,
+    /// whether to read uncompressed runs through memory mappings
+    pub(crate) memory_map_runs: bool,
+    /// the maximum number of bytes of runs to keep in memory instead of writing them to disk
+    pub(crate) spill_to_memory: Option<u64>,
+    /// the number of buffers the sort buffer is split into when writing in the background
+    pub(crate) num_sort_buffers: usize,
+    /// the number of threads sorting and writing buffers in the background
+    pub(crate) num_writer_threads: usize,
+    /// the number of runs the background writers merge into one while the input arrives
+    pub(crate) background_merge_fan_in: Option<usize>,
+    /// the number of buckets to distribute the items into instead of merging runs
+    pub(crate) distribution_buckets: Option<usize>,
+    /// how the work of the sort is distributed across threads
+    pub(crate) parallelism: Parallelism,
+    /// the pool to run the parallel parts of the sort on
+    #[cfg(feature = "parallel_sort")]
+    pub(crate) rayon_pool: Option<Arc<rayon::ThreadPool>>,
+    /// creates the builders for the background writer threads
+    pub(crate) writer_thread_builder: Option<WriterThreadBuilder>,
+    /// runs the body of each background writer thread
+    pub(crate) writer_thread_wrapper: Option<WriterThreadWrapper>,
+    /// whether to bypass the page cache for the sort files
+    #[cfg(feature = "direct_io")]
+    pub(crate) direct_io: bool,
 }
 
 impl Default for ExtsortConfig {
@@ -29,8 +106,26 @@ impl Default for ExtsortConfig {
         Self {
             sort_buffer_size_bytes: 10_000_000,
             temp_file_folder: PathBuf::from("/tmp"),
+            fallback_temp_file_folders: Vec::new(),
             #[cfg(feature = "compression")]
             compress_with: Default::default(),
+            max_temp_bytes: None,
+            check_disk_space: false,
+            io_rate_limiter: None,
+            page_cache_hints: false,
+            memory_map_runs: false,
+            spill_to_memory: None,
+            num_sort_buffers: 2,
+            num_writer_threads: 1,
+            background_merge_fan_in: None,
+            distribution_buckets: None,
+            parallelism: Parallelism::Sequential,
+            #[cfg(feature = "parallel_sort")]
+            rayon_pool: None,
+            writer_thread_builder: None,
+            writer_thread_wrapper: None,
+            #[cfg(feature = "direct_io")]
+            direct_io: false,
         }
     }
 }
@@ -41,11 +136,10 @@ impl ExtsortConfig {
 
         let one = NonZeroUsize::new(1).unwrap();
 
-        if t_size == 0 {
-            one
-        } else {
-            NonZeroUsize::new(self.sort_buffer_size_bytes / t_size).unwrap_or(one)
-        }
+        self.sort_buffer_size_bytes
+            .checked_div(t_size)
+            .and_then(NonZeroUsize::new)
+            .unwrap_or(one)
     }
 
     /// Creates a configuration with a sort buffer size of 10M
@@ -89,6 +183,18 @@ impl ExtsortConfig {
             ..self
         }
     }
+    /// Adds a folder to place sort files in once the temp file folder
+    /// (and all previously added fallback folders) run out of space.
+    ///
+    /// When the disk fills up while a run is being written, the run is retried
+    /// in the next fallback folder. If there are no fallback folders left and the
+    /// `compression_lz4_flex` feature is enabled, all remaining runs are compressed instead.
+    /// Only if that fails as well is the error returned.
+    pub fn fallback_temp_file_folder(mut self, folder: impl Into<PathBuf>) -> Self {
+        self.fallback_temp_file_folders.push(folder.into());
+        self
+    }
+
     #[cfg(feature = "compression_lz4_flex")]
     pub fn compress_lz4_flex(mut self) -> Self {
         self.compress_with = CompressionCodec::Lz4Flex;
@@ -101,7 +207,302 @@ impl ExtsortConfig {
         self
     }
 
-    fn compression_choice(&self) -> CompressionCodec {
+    /// Limits the number of bytes the sort may write to temp storage.
+    ///
+    /// If the limit would be exceeded, the sort fails with an error of kind
+    /// [`io::ErrorKind::QuotaExceeded`] carrying a [`TempLimitExceeded`](crate::TempLimitExceeded)
+    /// payload, and all files written so far are removed.
+    /// When compression is enabled, the compressed size is counted.
+    pub fn max_temp_bytes(mut self, limit: u64) -> Self {
+        self.max_temp_bytes = Some(limit);
+        self
+    }
+
+    /// Enables or disables the free disk space check performed before sorting.
+    ///
+    /// When enabled and the source iterator reports a lower size bound
+    /// that will not fit into the sort buffer, the sort fails early with an error of kind
+    /// [`io::ErrorKind::StorageFull`] if the temp file folder does not have enough free
+    /// space to hold the entire input. The space in the fallback folders is taken into account.
+    /// The check is skipped when compression is enabled,
+    /// as the size on disk can not be known up front.
+    ///
+    /// The free space is the estimate reported by the file system, which may be wrong
+    /// for thin provisioned mounts or mounts with quotas, so the check is disabled by default.
+    pub fn disk_space_check(mut self, enabled: bool) -> Self {
+        self.check_disk_space = enabled;
+        self
+    }
+
+    /// Enables hints to the kernel about how the sort files are accessed (linux and android only).
+    ///
+    /// Sort files are written once and read once sequentially, so caching them is of
+    /// little use, but it can displace data other processes on the host rely on.
+    /// With hints enabled, the written data and the data already consumed by the merge
+    /// are dropped from the page cache, while readahead is requested for the data needed next.
+    ///
+    /// This has no effect on other platforms.
+    pub fn page_cache_hints(mut self, enabled: bool) -> Self {
+        self.page_cache_hints = enabled;
+        self
+    }
+
+    /// Limits the bandwidth used for writing the runs to disk and reading them back during the merge.
+    ///
+    /// Keep a clone of the limiter to adjust the rate while the sort is running.
+    /// Runs kept in memory using [`ExtsortConfig::spill_to_memory`] are not limited,
+    /// and [`ExtsortConfig::memory_map_runs`] has no effect while a limiter is set.
+    pub fn io_rate_limit(mut self, limiter: IoRateLimiter) -> Self {
+        self.io_rate_limiter = Some(limiter);
+        self
+    }
+
+    /// Chooses how the sort distributes its work across threads.
+    /// The default is [`Parallelism::Sequential`].
+    pub fn parallelism(mut self, parallelism: Parallelism) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// Runs the parallel parts of the sort on the provided rayon thread pool
+    /// instead of the global one.
+    ///
+    /// This covers the parallel buffer sort of [`Parallelism::Rayon`], where the pool takes
+    /// precedence over the configured number of threads, as well as the compression of the runs.
+    #[cfg(feature = "parallel_sort")]
+    pub fn rayon_thread_pool(mut self, pool: Arc<rayon::ThreadPool>) -> Self {
+        self.rayon_pool = Some(pool);
+        self
+    }
+
+    /// Customizes the background writer threads, for example their stack size or name.
+    ///
+    /// The function is called with the index of the writer thread to create and
+    /// returns the builder to spawn it with.
+    /// By default, the threads are named `Sort-Buffer-Writer`.
+    /// If spawning a thread fails, the sort fails with the error.
+    pub fn writer_thread_builder(
+        mut self,
+        builder: impl Fn(usize) -> thread::Builder + Send + Sync + 'static,
+    ) -> Self {
+        self.writer_thread_builder = Some(Arc::new(builder));
+        self
+    }
+
+    /// Wraps the body of the background writer threads,
+    /// for example to set up thread local state or to register the thread somewhere.
+    ///
+    /// The wrapper is called on the spawned thread with the body as its argument
+    /// and must call it exactly once, otherwise the sort fails.
+    pub fn writer_thread_wrapper(
+        mut self,
+        wrapper: impl Fn(&mut dyn FnMut()) + Send + Sync + 'static,
+    ) -> Self {
+        self.writer_thread_wrapper = Some(Arc::new(wrapper));
+        self
+    }
+
+    /// Sets the number of buffers the sort buffer is split into when writing in the background.
+    ///
+    /// This only applies if the configured [`Parallelism`] writes in the background.
+    ///
+    /// While the current thread fills one buffer, the others are sorted and written
+    /// in the background. More buffers allow more of that work to overlap,
+    /// but each buffer (and thus each run) becomes smaller, as they share the sort buffer size.
+    /// At least 2 buffers are used. The default is 2.
+    pub fn sort_buffer_count(mut self, count: usize) -> Self {
+        self.num_sort_buffers = count.max(2);
+        self
+    }
+
+    /// Sets the number of background threads used to sort and write buffers
+    /// if the configured [`Parallelism`] writes in the background. Each of them works on a different buffer,
+    /// so there can be at most one less thread than there are buffers.
+    /// The default is 1.
+    pub fn writer_threads(mut self, count: usize) -> Self {
+        self.num_writer_threads = count.max(1);
+        self
+    }
+
+    /// Lets the background writers merge runs while the input is still being read.
+    ///
+    /// Whenever a writer has written fan_in runs of the same size, they are merged
+    /// into a single larger run, like in an LSM tree. This keeps the number of runs
+    /// small and spreads the merge IO over the time the input arrives, so that
+    /// the final merge is cheaper and the first item is returned sooner.
+    /// In exchange, the data is written (and read) once more per level of merging.
+    ///
+    /// Each merge uses the memory of the buffer that was just written, so the sort buffer size
+    /// is not exceeded. The temp storage limit counts the merged runs as well. The runs they were
+    /// merged from no longer count against it, unless they are stored in a sort file shared with
+    /// other runs, which only happens once more runs exist than sort files may be opened.
+    /// A fan in below 2 is raised to 2.
+    ///
+    /// This only applies if the configured [`Parallelism`] writes in the background.
+    pub fn background_merge(mut self, fan_in: usize) -> Self {
+        self.background_merge_fan_in = Some(fan_in.max(2));
+        self
+    }
+
+    /// Sorts by distributing the items into buckets of key ranges instead of merging sorted runs.
+    ///
+    /// The splitters between the buckets are chosen from a sample of the first sort buffer.
+    /// Each item is then appended to its bucket on disk, and the buckets are sorted
+    /// in memory one after another while the result is read. Buckets that are too large
+    /// for the sort buffer are distributed again before the sort returns.
+    /// Items equal to a splitter are collected in a bucket of their own that needs no sorting,
+    /// so inputs with many duplicates are handled as well.
+    ///
+    /// For well spread keys, this avoids the merge of many runs entirely, but skewed keys
+    /// may need several rounds of distribution. With `Parallelism::Rayon`, each bucket is sorted
+    /// in parallel using the rayon threads, like the buffers of the merge sort. The buckets themselves
+    /// are sorted one after another as the result is read, and the items are distributed
+    /// on the calling thread for all parallelism choices.
+    ///
+    /// Distributing the items takes 8 bytes per item on top of the items themselves,
+    /// so fewer items fit into the sort buffer. A bucket count below 2 is raised to 2,
+    /// and the items are split into at most 128 ranges at once to bound the number of open files.
+    pub fn distribution_sort(mut self, buckets: usize) -> Self {
+        self.distribution_buckets = Some(buckets.max(2));
+        self
+    }
+
+    /// Keeps the sorted runs in memory instead of writing them to the temp file folder.
+    ///
+    /// This is meant for environments without a usable disk. Combined with compression,
+    /// data several times larger than the available memory can be sorted, provided it
+    /// compresses well. Each run is stored compressed with the configured codec, so without
+    /// compression this only adds overhead compared to an in memory sort.
+    ///
+    /// The runs may take up at most max_bytes of memory in addition to the sort buffer,
+    /// counting their compressed sizes. If they would exceed it, the sort fails just like
+    /// when exceeding [`max_temp_bytes`](Self::max_temp_bytes).
+    pub fn spill_to_memory(mut self, max_bytes: u64) -> Self {
+        self.spill_to_memory = Some(max_bytes);
+        self
+    }
+
+    /// the maximum number of bytes the runs may take up in temp storage,
+    /// which is memory when spilling to memory.
+    pub(crate) fn temp_limit(&self) -> Option<u64> {
+        match (self.max_temp_bytes, self.spill_to_memory) {
+            (Some(limit), Some(memory)) => Some(limit.min(memory)),
+            (limit, memory) => limit.or(memory),
+        }
+    }
+
+    /// Reads the runs through memory mappings of the sort files during the merge (unix only).
+    ///
+    /// The items are read directly from the mapped pages instead of being copied into
+    /// a read buffer first, so the sort buffer size does not need to be split among the runs
+    /// and readahead is left to the kernel. Pages are unmapped as the merge moves past them.
+    ///
+    /// Compressed runs and runs read with direct IO are still read through a buffer.
+    /// This takes precedence over [`page_cache_hints`](Self::page_cache_hints).
+    ///
+    /// This has no effect on other platforms.
+    pub fn memory_map_runs(mut self, enabled: bool) -> Self {
+        self.memory_map_runs = enabled;
+        self
+    }
+
+    /// Enables unbuffered IO for the sort files (linux only).
+    ///
+    /// Runs are written with `O_DIRECT` from aligned buffers, bypassing the page cache,
+    /// and are read back during the merge through a shared io_uring, so the refills
+    /// of many runs can be in flight at once. If io_uring is not available,
+    /// blocking reads are used instead. Filesystems that do not support `O_DIRECT`
+    /// keep using the page cache, but still use the aligned IO.
+    ///
+    /// Part of the read buffer is used to read ahead, so each run reads
+    /// in smaller chunks than without direct IO.
+    /// This takes precedence over [`page_cache_hints`](Self::page_cache_hints).
+    ///
+    /// This has no effect on other platforms.
+    #[cfg(feature = "direct_io")]
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.direct_io = enabled;
+        self
+    }
+
+    /// Verifies that the temp file folder has enough free space
+    /// to hold a sort of the provided number of items.
+    pub(crate) fn check_free_space<T>(&self, expected_items: usize) -> io::Result<()> {
+        let buffer_items = self.get_num_items_for::<T>().get();
+        let skip_check = !self.check_disk_space
+            || self.spill_to_memory.is_some()
+            || expected_items <= buffer_items
+            || !matches!(self.compression_choice(), CompressionCodec::NoCompression);
+        if skip_check {
+            return Ok(());
+        }
+
+        let projected_bytes =
+            (expected_items as u64).saturating_mul(std::mem::size_of::<T>() as u64);
+        // the fallback folders may be used once the temp file folder is full,
+        // so their space counts as well.
+        let mut available = 0u64;
+        for folder in
+            std::iter::once(&self.temp_file_folder).chain(&self.fallback_temp_file_folders)
+        {
+            match file::available_space(folder)? {
+                Some(space) => available = available.saturating_add(space),
+                // the platform does not let us know, so there is nothing to check
+                None => return Ok(()),
+            }
+        }
+
+        if available < projected_bytes {
+            Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "the sort needs to write {projected_bytes} bytes to {}, but only {available} bytes are available",
+                    self.temp_file_folder.display()
+                ),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// the number of buffers and writer threads used when writing in the background
+    pub(crate) fn pipeline_size(&self) -> (NonZeroUsize, NonZeroUsize) {
+        let buffers = self.num_sort_buffers.max(2);
+        let writers = self.num_writer_threads.clamp(1, buffers - 1);
+        (
+            NonZeroUsize::new(buffers).unwrap(),
+            NonZeroUsize::new(writers).unwrap(),
+        )
+    }
+
+    /// builds the dedicated pool for [`Parallelism::Rayon`] with a fixed number of threads,
+    /// so that the buffer sort and the compression of the runs share it.
+    pub(crate) fn build_rayon_pool(self) -> io::Result<Self> {
+        #[cfg(feature = "parallel_sort")]
+        if let Parallelism::Rayon { threads } = self.parallelism {
+            if threads > 0 && self.rayon_pool.is_none() {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .thread_name(|idx| format!("Sort-Buffer-Sorter-{idx}"))
+                    .build()
+                    .map_err(io::Error::other)?;
+                return Ok(self.rayon_thread_pool(Arc::new(pool)));
+            }
+        }
+        Ok(self)
+    }
+
+    /// the pool to compress runs on.
+    /// Runs are only compressed in parallel if the sort uses rayon.
+    pub(crate) fn compression_pool(&self) -> CompressionPool {
+        match self.parallelism {
+            #[cfg(all(feature = "parallel_sort", feature = "compression_lz4_flex"))]
+            Parallelism::Rayon { .. } => CompressionPool::Rayon(self.rayon_pool.clone()),
+            _ => CompressionPool::CurrentThread,
+        }
+    }
+
+    pub(crate) fn compression_choice(&self) -> CompressionCodec {
         #[cfg(feature = "compression")]
         {
             self.compress_with
@@ -113,6 +514,60 @@ impl ExtsortConfig {
     }
 }
 
+/// Sorts the source with the parallelism chosen in the options.
+pub(crate) fn sort<T, O>(
+    source: impl Iterator<Item = T>,
+    options: ExtsortConfig,
+    orderer: O,
+) -> io::Result<ResultIterator<T, O>>
+where
+    T: Send,
+    O: Orderer<T> + Send + Sync,
+{
+    options.check_free_space::<T>(source.size_hint().0)?;
+    let options = options.build_rayon_pool()?;
+    let buffer_sort = BufferSort::new(&options);
+    if let Some(buckets) = options.distribution_buckets {
+        return distribution::sort(source, options, orderer, buffer_sort, buckets);
+    }
+    let sort_func = move |orderer: &O, buffer: &mut [T]| buffer_sort.sort(orderer, buffer);
+
+    if options.parallelism == Parallelism::Sequential {
+        let cleaner = SingleThreadedBufferCleaner::new(options, orderer, sort_func);
+        ExtSorter::new().run(source, cleaner)
+    } else {
+        let cleaner = MultithreadedBufferCleaner::new(options, orderer, sort_func);
+        cleaner.run(move |cleaner_handle| ExtSorter::new().run(source, cleaner_handle))?
+    }
+}
+
+/// the number of items the sort buffer starts out with.
+/// It grows geometrically from there up to the configured size.
+const INITIAL_BUFFER_ITEMS: usize = 1024;
+
+/// Moves items from the source to the buffer until it holds target items
+/// or the source is exhausted.
+///
+/// The buffer is grown geometrically as it fills, so that small inputs
+/// do not allocate the whole sort buffer.
+fn fill_buffer<T>(source: &mut impl Iterator<Item = T>, buffer: &mut Vec<T>, target: usize) {
+    loop {
+        let free = buffer.capacity().min(target) - buffer.len();
+        let len_before = buffer.len();
+        buffer.extend(source.by_ref().take(free));
+        if buffer.len() - len_before < free || buffer.len() >= target {
+            return;
+        }
+        // the buffer is full, but we are still within the budget.
+        let grow_to = buffer
+            .capacity()
+            .saturating_mul(2)
+            .max(INITIAL_BUFFER_ITEMS)
+            .min(target);
+        buffer.reserve_exact(grow_to - buffer.len());
+    }
+}
+
 pub struct ExtSorter {}
 
 impl ExtSorter {
@@ -133,14 +588,14 @@ impl ExtSorter {
         O: Orderer<T>,
     {
         let mut sort_buffer = buffer_cleaner.get_buffer();
+        let capacity = buffer_cleaner.buffer_capacity().get();
 
         let source = &mut source;
         let mut any_buffer_was_flushed = false;
         loop {
             debug_assert!(sort_buffer.is_empty());
-            let capacity = sort_buffer.capacity();
 
-            sort_buffer.extend(source.take(capacity));
+            fill_buffer(source, &mut sort_buffer, capacity);
             if sort_buffer.len() < capacity {
                 // we could not completely fill the buffer, so we know that this
                 // is the last run that will be generated.
@@ -149,12 +604,19 @@ impl ExtSorter {
                     // we did not acually move anything to disk.
                     // in this case we can just reuse the sort buffer
                     // as a sort of pseudo tape.
-                    let mut finalize_response = buffer_cleaner.finalize()?;
+                    let mut finalize_response = buffer_cleaner.finalize(sort_buffer.len())?;
                     let orderer = finalize_response.orderer;
                     (finalize_response.sort_func)(&orderer, &mut sort_buffer);
+                    // the buffer may have grown past the input
+                    sort_buffer.shrink_to_fit();
                     let buffer_run = create_buffer_run(sort_buffer);
-                    return Ok(ResultIterator::new(vec![buffer_run], orderer));
-                } else if !sort_buffer.is_empty() {
+                    return Ok(ResultIterator::new(vec![buffer_run.into()], orderer));
+                } else if sort_buffer.len() <= capacity / 2 {
+                    // the final run is small enough to keep in memory.
+                    // The read buffers of the runs on disk make room for it,
+                    // which saves us writing it out and reading it back in.
+                    return Self::merge_with_tail(sort_buffer, buffer_cleaner);
+                } else {
                     // since we moved runs to disk, we will need to use memory for the read buffers.
                     // to avoid going over budget, we move the final run to disk as well
                     buffer_cleaner.clean_buffer(&mut sort_buffer)?;
@@ -175,10 +637,204 @@ impl ExtSorter {
         drop(sort_buffer);
 
         // wait for the io thread to be done writing and get the file handles back to the main thread
-        let finalize_response = buffer_cleaner.finalize()?;
+        let finalize_response = buffer_cleaner.finalize(0)?;
         Ok(ResultIterator::new(
             finalize_response.tapes,
             finalize_response.orderer,
         ))
     }
+
+    /// merges the runs moved to disk with the final run that is kept in memory.
+    fn merge_with_tail<T, C, O, F>(
+        mut tail: Vec<T>,
+        buffer_cleaner: C,
+    ) -> io::Result<ResultIterator<T, O>>
+    where
+        C: BufferCleaner<T, O, F>,
+        F: FnMut(&O, &mut [T]),
+        O: Orderer<T>,
+    {
+        // the buffer was allocated for a full run, we only keep what is needed.
+        tail.shrink_to_fit();
+        let mut finalize_response = buffer_cleaner.finalize(tail.len())?;
+        let orderer = finalize_response.orderer;
+        (finalize_response.sort_func)(&orderer, &mut tail);
+
+        let mut tapes = finalize_response.tapes;
+        if !tail.is_empty() {
+            tapes.push(create_buffer_run(tail).into());
+        }
+        Ok(ResultIterator::new(tapes, orderer))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashSet, sync::Mutex};
+
+    use crate::{ExtSortByExtension, ExtSortOrdExtension};
+
+    use super::{fill_buffer, ExtsortConfig, Parallelism, INITIAL_BUFFER_ITEMS};
+
+    #[test]
+    fn test_fill_buffer_grows_lazily() {
+        let mut buffer = Vec::new();
+        fill_buffer(&mut (0..100), &mut buffer, 1 << 30);
+        assert_eq!(100, buffer.len());
+        assert_eq!(INITIAL_BUFFER_ITEMS, buffer.capacity());
+
+        let mut buffer = Vec::new();
+        fill_buffer(&mut (0..5000), &mut buffer, 1 << 30);
+        assert!(buffer.iter().copied().eq(0..5000));
+        assert!(buffer.capacity() < 10_000);
+    }
+
+    #[test]
+    fn test_fill_buffer_small_target() {
+        let mut buffer = Vec::new();
+        fill_buffer(&mut (0..100), &mut buffer, 16);
+        assert!(buffer.iter().copied().eq(0..16));
+        assert_eq!(16, buffer.capacity());
+    }
+
+    #[test]
+    fn test_fill_buffer_stops_at_target() {
+        let mut source = 0..10_000;
+        let mut buffer = Vec::new();
+        fill_buffer(&mut source, &mut buffer, 3000);
+        assert_eq!(3000, buffer.len());
+        assert_eq!(3000, buffer.capacity());
+
+        // a buffer that was already grown is refilled without growing it further
+        buffer.clear();
+        fill_buffer(&mut source, &mut buffer, 3000);
+        assert!(buffer.iter().copied().eq(3000..6000));
+        assert_eq!(3000, buffer.capacity());
+    }
+
+    #[test]
+    #[cfg(feature = "parallel_sort")]
+    fn test_dedicated_pool_is_shared() {
+        use super::BufferSort;
+        #[cfg(feature = "compression_lz4_flex")]
+        use super::CompressionPool;
+
+        let config = ExtsortConfig::default()
+            .parallelism(Parallelism::Rayon { threads: 3 })
+            .build_rayon_pool()
+            .unwrap();
+        let BufferSort::Rayon(Some(sort_pool)) = BufferSort::new(&config) else {
+            panic!("the buffers are not sorted on the dedicated pool");
+        };
+        assert_eq!(3, sort_pool.current_num_threads());
+        #[cfg(feature = "compression_lz4_flex")]
+        {
+            let CompressionPool::Rayon(Some(compression_pool)) = config.compression_pool() else {
+                panic!("the runs are not compressed on the dedicated pool");
+            };
+            assert!(std::sync::Arc::ptr_eq(&sort_pool, &compression_pool));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    #[cfg(unix)]
+    fn test_disk_space_preflight() {
+        let config = ExtsortConfig::with_buffer_size(64)
+            .temp_file_folder(std::env::temp_dir())
+            .disk_space_check(true);
+        let err = (0..u64::MAX).external_sort(config).map(|_| ()).unwrap_err();
+        assert_eq!(std::io::ErrorKind::StorageFull, err.kind());
+
+        // the check is off by default
+        let config = ExtsortConfig::with_buffer_size(64).temp_file_folder(std::env::temp_dir());
+        let sorted = (0..1000u64).rev().external_sort(config).unwrap();
+        assert!(sorted.eq(0..1000));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_parallelism() {
+        let caller = std::thread::current().name().map(str::to_owned);
+        let modes = [
+            Parallelism::Sequential,
+            Parallelism::BackgroundWriter,
+            #[cfg(feature = "parallel_sort")]
+            Parallelism::Rayon { threads: 0 },
+            #[cfg(feature = "parallel_sort")]
+            Parallelism::Rayon { threads: 2 },
+        ];
+        for mode in modes {
+            // record the threads the items are compared on
+            let threads = Mutex::new(HashSet::new());
+            let config = ExtsortConfig::with_buffer_size(40_000)
+                .temp_file_folder(std::env::temp_dir())
+                .parallelism(mode);
+            let sorted = (0..20_000)
+                .map(|i| i * 7919 % 20_000)
+                .external_sort_by(config, |a: &i32, b: &i32| {
+                    let name = std::thread::current().name().map(str::to_owned);
+                    threads.lock().unwrap().insert(name);
+                    a.cmp(b)
+                })
+                .unwrap();
+            assert!(sorted.eq(0..20_000), "{mode:?}");
+
+            let threads = threads.into_inner().unwrap();
+            let writer = Some("Sort-Buffer-Writer".to_owned());
+            let on_pool = threads
+                .iter()
+                .any(|name| *name != caller && *name != writer);
+            match mode {
+                Parallelism::Sequential => assert_eq!(HashSet::from([caller.clone()]), threads),
+                Parallelism::BackgroundWriter => {
+                    assert!(threads.contains(&writer));
+                    assert!(!on_pool);
+                }
+                #[cfg(feature = "parallel_sort")]
+                Parallelism::Rayon { threads: 0 } => assert!(on_pool),
+                #[cfg(feature = "parallel_sort")]
+                Parallelism::Rayon { .. } => assert!(threads
+                    .iter()
+                    .flatten()
+                    .any(|name| name.starts_with("Sort-Buffer-Sorter-"))),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    #[cfg(feature = "parallel_sort")]
+    fn test_rayon_thread_pool() {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let config = ExtsortConfig::with_buffer_size(64)
+            .temp_file_folder(std::env::temp_dir())
+            .parallelism(Parallelism::Rayon { threads: 2 })
+            .rayon_thread_pool(std::sync::Arc::new(pool));
+        let sorted = (0..1000).rev().external_sort(config).unwrap();
+        assert!(sorted.eq(0..1000));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_small_tail_stays_in_memory() {
+        // the background writer splits the sort buffer into two runs
+        for (mode, run_len) in [
+            (Parallelism::Sequential, 256u64),
+            (Parallelism::BackgroundWriter, 128),
+        ] {
+            // the temp limit only leaves room for the 4 full runs,
+            // so writing the final 40 items would fail the sort.
+            let config = ExtsortConfig::with_buffer_size(256 * 8)
+                .temp_file_folder(std::env::temp_dir())
+                .parallelism(mode)
+                .max_temp_bytes(4 * run_len * 8);
+            let len = 4 * run_len + 40;
+            let sorted = (0..len).rev().external_sort(config).unwrap();
+            assert!(sorted.eq(0..len), "{mode:?}");
+        }
+    }
 }
@@ -1,5 +1,70 @@
-use std::io::Read;
+use crate::{merge::LoserTree, orderer::Orderer, run::TapeRun};
 
-use crate::{merge::LoserTree, run::file_run::ExternalRun};
+use super::distribution::Buckets;
 
-pub type ResultIterator<T, O> = LoserTree<T, ExternalRun<T, Box<dyn Read + Send>>, O>;
+/// Returns the sorted items.
+pub struct ResultIterator<T, O> {
+    inner: Inner<T, O>,
+}
+
+enum Inner<T, O> {
+    /// merges the sorted runs
+    Merge(LoserTree<T, TapeRun<T>, O>),
+    /// concatenates the sorted buckets of a distribution sort
+    Buckets(Buckets<T, O>),
+}
+
+impl<T, O> ResultIterator<T, O>
+where
+    O: Orderer<T>,
+{
+    /// returns the items of the sorted runs, merging them
+    /// using the provided ordering instruction.
+    pub(crate) fn new(tapes: Vec<TapeRun<T>>, orderer: O) -> Self {
+        Self {
+            inner: Inner::Merge(LoserTree::new(tapes, orderer)),
+        }
+    }
+
+    /// returns the items of the buckets in order.
+    pub(crate) fn from_buckets(buckets: Buckets<T, O>) -> Self {
+        Self {
+            inner: Inner::Buckets(buckets),
+        }
+    }
+
+    /// returns the number of items that have not been returned yet.
+    pub fn remaining_items(&self) -> usize {
+        match &self.inner {
+            Inner::Merge(merger) => merger.remaining_items(),
+            Inner::Buckets(buckets) => buckets.remaining_items(),
+        }
+    }
+}
+
+impl<T, O> Iterator for ResultIterator<T, O>
+where
+    O: Orderer<T>,
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            Inner::Merge(merger) => merger.next(),
+            Inner::Buckets(buckets) => buckets.next(),
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.remaining_items();
+        (remaining, Some(remaining))
+    }
+}
+
+impl<T, O> ExactSizeIterator for ResultIterator<T, O>
+where
+    O: Orderer<T>,
+{
+    fn len(&self) -> usize {
+        self.remaining_items()
+    }
+}
@@ -0,0 +1,221 @@
+//! Compresses runs as a sequence of independent lz4 blocks.
+//!
+//! Each block is preceded by a header holding its uncompressed and compressed length
+//! as little endian u32 values. As the blocks do not depend on each other,
+//! they can be compressed in parallel.
+
+use std::io::{self, Read, Write};
+
+use super::CompressionPool;
+
+/// the number of uncompressed bytes in each block
+const BLOCK_SIZE: usize = 256 * 1024;
+const HEADER_SIZE: usize = 8;
+
+/// compresses the data in blocks and writes them to the writer.
+pub fn write_blocks(
+    writer: &mut impl Write,
+    data: &[u8],
+    pool: &CompressionPool,
+) -> io::Result<()> {
+    match pool {
+        CompressionPool::CurrentThread => {
+            let mut compressed = Vec::new();
+            for block in data.chunks(BLOCK_SIZE) {
+                compress_block(block, &mut compressed);
+                writer.write_all(&compressed)?;
+            }
+            Ok(())
+        }
+        #[cfg(feature = "parallel_sort")]
+        CompressionPool::Rayon(pool) => write_blocks_parallel(writer, data, pool.as_deref()),
+    }
+}
+
+/// compresses the data in blocks on the rayon pool and writes them to the writer.
+/// Without a pool, the global one is used.
+#[cfg(feature = "parallel_sort")]
+fn write_blocks_parallel(
+    writer: &mut impl Write,
+    data: &[u8],
+    pool: Option<&rayon::ThreadPool>,
+) -> io::Result<()> {
+    use rayon::prelude::*;
+
+    let num_threads = match pool {
+        Some(pool) => pool.current_num_threads(),
+        None => rayon::current_num_threads(),
+    };
+    // we compress a few blocks per thread at a time,
+    // so that we do not have to keep the entire compressed run in memory.
+    let blocks_per_batch = num_threads * 2;
+    let mut compressed = vec![Vec::new(); blocks_per_batch];
+    for batch in data.chunks(BLOCK_SIZE * blocks_per_batch) {
+        let num_blocks = batch.len().div_ceil(BLOCK_SIZE);
+        let targets = &mut compressed[..num_blocks];
+        let compress = || {
+            batch
+                .par_chunks(BLOCK_SIZE)
+                .zip(targets)
+                .for_each(|(block, target)| compress_block(block, target))
+        };
+        match pool {
+            Some(pool) => pool.install(compress),
+            None => compress(),
+        }
+        for block in &compressed[..num_blocks] {
+            writer.write_all(block)?;
+        }
+    }
+    Ok(())
+}
+
+/// compresses the block into the target, including the header.
+fn compress_block(block: &[u8], target: &mut Vec<u8>) {
+    target.resize(
+        HEADER_SIZE + lz4_flex::block::get_maximum_output_size(block.len()),
+        0,
+    );
+    let compressed_len = lz4_flex::block::compress_into(block, &mut target[HEADER_SIZE..])
+        .expect("the target is large enough for any input");
+    target.truncate(HEADER_SIZE + compressed_len);
+    target[..4].copy_from_slice(&(block.len() as u32).to_le_bytes());
+    target[4..HEADER_SIZE].copy_from_slice(&(compressed_len as u32).to_le_bytes());
+}
+
+/// Decompresses the blocks written by write_blocks.
+pub struct BlockReader<R> {
+    inner: R,
+    compressed: Vec<u8>,
+    /// the decompressed current block
+    block: Vec<u8>,
+    /// the number of bytes of the current block that were already read
+    position: usize,
+}
+
+impl<R: Read> BlockReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            compressed: Vec::new(),
+            block: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// reads the header of the next block.
+    /// returns None if the end of the data has been reached.
+    fn read_header(&mut self) -> io::Result<Option<(usize, usize)>> {
+        let mut header = [0; HEADER_SIZE];
+        let mut filled = 0;
+        while filled < HEADER_SIZE {
+            match self.inner.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let uncompressed_len = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let compressed_len = u32::from_le_bytes(header[4..].try_into().unwrap());
+        Ok(Some((uncompressed_len as usize, compressed_len as usize)))
+    }
+
+    /// decompresses the next block.
+    /// returns false if there are no more blocks.
+    fn next_block(&mut self) -> io::Result<bool> {
+        let Some((uncompressed_len, compressed_len)) = self.read_header()? else {
+            return Ok(false);
+        };
+        self.compressed.resize(compressed_len, 0);
+        self.inner.read_exact(&mut self.compressed)?;
+
+        self.block.resize(uncompressed_len, 0);
+        let decompressed_len = lz4_flex::block::decompress_into(&self.compressed, &mut self.block)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if decompressed_len != uncompressed_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the decompressed block has an unexpected length",
+            ));
+        }
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.block.len() {
+            if !self.next_block()? {
+                return Ok(0);
+            }
+        }
+        let available = &self.block[self.position..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read};
+
+    use super::{write_blocks, BlockReader, CompressionPool, BLOCK_SIZE};
+
+    /// a reader returning at most 3 bytes per call
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(3);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    fn roundtrip(data: &[u8]) {
+        roundtrip_on(data, &CompressionPool::CurrentThread);
+        #[cfg(feature = "parallel_sort")]
+        roundtrip_on(data, &CompressionPool::Rayon(None));
+    }
+
+    fn roundtrip_on(data: &[u8], pool: &CompressionPool) {
+        let mut compressed = Vec::new();
+        write_blocks(&mut compressed, data, pool).unwrap();
+
+        let mut decompressed = Vec::new();
+        BlockReader::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(data, decompressed);
+
+        let mut decompressed = Vec::new();
+        BlockReader::new(Trickle(&compressed))
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(data, decompressed);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        roundtrip(&[]);
+        roundtrip(b"hello world");
+        let data: Vec<u8> = (0..BLOCK_SIZE * 5 + 17).map(|i| (i % 7) as u8).collect();
+        roundtrip(&data);
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut compressed = Vec::new();
+        write_blocks(&mut compressed, &[1; 1000], &Default::default()).unwrap();
+        compressed.pop();
+        let mut target = Vec::new();
+        let result = BlockReader::new(compressed.as_slice()).read_to_end(&mut target);
+        assert!(result.is_err());
+    }
+}
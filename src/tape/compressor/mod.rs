@@ -0,0 +1,46 @@
+use std::io::{Read, Write};
+
+#[cfg(feature = "compression_lz4_flex")]
+mod lz4_block;
+
+/// Where runs are compressed.
+#[derive(Clone, Default)]
+pub enum CompressionPool {
+    /// compress on the thread writing the run
+    #[default]
+    CurrentThread,
+    /// compress in parallel using rayon, on the provided pool or the global one.
+    #[cfg(all(feature = "parallel_sort", feature = "compression_lz4_flex"))]
+    Rayon(Option<std::sync::Arc<rayon::ThreadPool>>),
+}
+
+#[derive(Clone, Copy, Default)]
+pub enum CompressionCodec {
+    #[default]
+    NoCompression,
+    #[cfg(feature = "compression_lz4_flex")]
+    Lz4Flex,
+}
+
+impl CompressionCodec {
+    #[cfg_attr(not(feature = "compression_lz4_flex"), allow(unused_variables))]
+    pub fn write_all(
+        self,
+        writer: &mut impl Write,
+        data: &[u8],
+        pool: &CompressionPool,
+    ) -> std::io::Result<()> {
+        match self {
+            CompressionCodec::NoCompression => writer.write_all(data),
+            #[cfg(feature = "compression_lz4_flex")]
+            CompressionCodec::Lz4Flex => lz4_block::write_blocks(writer, data, pool),
+        }
+    }
+    pub fn get_reader(self, inner: impl Read + Send + 'static) -> Box<dyn Read + Send> {
+        match self {
+            CompressionCodec::NoCompression => Box::new(inner),
+            #[cfg(feature = "compression_lz4_flex")]
+            CompressionCodec::Lz4Flex => Box::new(lz4_block::BlockReader::new(inner)),
+        }
+    }
+}
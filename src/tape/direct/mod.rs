@@ -0,0 +1,453 @@
+//! Unbuffered IO for sort files (linux only).
+//!
+//! When direct IO is enabled, sort files are opened with `O_DIRECT`, bypassing the page cache.
+//! This requires all reads and writes to use buffers, offsets and lengths aligned to the
+//! block size of the device, so runs are written in aligned blocks with the final block
+//! zero-padded. The logical length of the run is stored in the tape.
+//!
+//! During the merge, runs read ahead one chunk while the previous one is consumed.
+//! These reads are submitted through a shared io_uring so that the refills of all runs
+//! can be in flight at the same time. If io_uring is unavailable, we fall back to
+//! blocking positional reads.
+
+use std::{
+    alloc::{self, Layout},
+    fs::File,
+    io::{self, Read, Write},
+    mem::ManuallyDrop,
+    os::fd::AsRawFd,
+    ptr::NonNull,
+    sync::{Arc, Mutex},
+};
+
+use super::page_cache::FileRegion;
+
+use self::uring::Ring;
+
+mod uring;
+
+/// the alignment used for all direct IO.
+/// This is the page size on most systems and a multiple of the
+/// logical block size of virtually all devices.
+pub const ALIGNMENT: usize = 4096;
+
+/// the size of the buffer used to write runs
+const WRITE_BUFFER_SIZE: usize = 256 * ALIGNMENT;
+
+/// rounds up the provided size to the next multiple of the alignment
+pub fn align_up(size: usize) -> usize {
+    size.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+/// Switches the file to unbuffered IO.
+///
+/// Filesystems that do not support `O_DIRECT` reject the flag with EINVAL.
+/// The file then stays buffered, which the aligned readers and writers
+/// handle just as well, so this is not an error.
+pub fn enable_direct_io(file: &File) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    // SAFETY: we only query and update the status flags of a file descriptor we own.
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT) < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EINVAL) {
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A zero-initialized heap buffer aligned for direct IO.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+// SAFETY: the buffer is uniquely owned, just like a Vec<u8>.
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// allocates a new buffer. The length is rounded up to a multiple of the alignment.
+    pub fn new(len: usize) -> Self {
+        let len = align_up(len.max(1));
+        let layout = Self::layout(len);
+        // SAFETY: the layout has a nonzero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, ALIGNMENT).expect("direct io buffer size overflow")
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: we own len initialized bytes at ptr.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: we own len initialized bytes at ptr.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        // SAFETY: the pointer was allocated with the same layout in new.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+/// A writer that only passes aligned blocks to the inner writer.
+///
+/// The final partial block is padded with zeroes when the writer is flushed,
+/// so flush must only be called once all data has been written.
+pub struct DirectWriter<W> {
+    inner: W,
+    buffer: AlignedBuffer,
+    filled: usize,
+}
+
+impl<W: Write> DirectWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            buffer: AlignedBuffer::new(WRITE_BUFFER_SIZE),
+            filled: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for DirectWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.filled == self.buffer.len() {
+            self.inner.write_all(self.buffer.as_slice())?;
+            self.filled = 0;
+        }
+        let target = &mut self.buffer.as_mut_slice()[self.filled..];
+        let len = target.len().min(buf.len());
+        target[..len].copy_from_slice(&buf[..len]);
+        self.filled += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.filled > 0 {
+            let padded_len = align_up(self.filled);
+            let buffer = self.buffer.as_mut_slice();
+            buffer[self.filled..padded_len].fill(0);
+            self.inner.write_all(&buffer[..padded_len])?;
+            self.filled = 0;
+        }
+        self.inner.flush()
+    }
+}
+
+/// A handle to the io_uring shared by all readers of a merge.
+#[derive(Clone)]
+pub struct SharedRing {
+    ring: Arc<Mutex<Ring>>,
+}
+
+impl SharedRing {
+    /// tries to set up a new ring.
+    /// returns None if io_uring is not available.
+    pub fn new(entries: u32) -> Option<Self> {
+        let ring = Ring::new(entries).ok()?;
+        Some(Self {
+            ring: Arc::new(Mutex::new(ring)),
+        })
+    }
+}
+
+/// A read that has been submitted to the ring but not yet been waited for.
+struct PendingRead {
+    token: u64,
+    /// the file offset the read was issued for
+    offset: u64,
+}
+
+/// Reads a region of a file opened for direct IO in aligned chunks.
+///
+/// While the current chunk is consumed, the next one is read ahead
+/// into a second buffer.
+pub struct DirectReader<R: FileRegion> {
+    region: R,
+    ring: Option<SharedRing>,
+    /// the chunk currently being consumed and the one being read ahead.
+    /// These are leaked if we can not make sure that the kernel is done writing to them.
+    buffers: ManuallyDrop<[AlignedBuffer; 2]>,
+    current: usize,
+    /// the range of valid data in the current buffer
+    consumed: usize,
+    valid: usize,
+    /// the file offset of the next chunk that has not been requested yet
+    next_offset: u64,
+    /// the past-the-end file offset of the logical data in the region
+    end: u64,
+    pending: Option<PendingRead>,
+}
+
+impl<R: FileRegion> DirectReader<R> {
+    /// creates a new reader for the first len bytes of the region.
+    /// The chunk size is rounded up to a multiple of the alignment
+    pub fn new(region: R, len: u64, chunk_size: usize, ring: Option<SharedRing>) -> Self {
+        let start = region.start();
+        debug_assert_eq!(0, start % ALIGNMENT as u64);
+        let mut reader = Self {
+            region,
+            ring,
+            buffers: ManuallyDrop::new([
+                AlignedBuffer::new(chunk_size),
+                AlignedBuffer::new(chunk_size),
+            ]),
+            current: 0,
+            consumed: 0,
+            valid: 0,
+            next_offset: start,
+            end: start + len,
+            pending: None,
+        };
+        // start reading the first chunk right away, so that all runs
+        // load their initial data concurrently.
+        // If this fails, we will retry with a blocking read later.
+        reader.submit_read_ahead().ok();
+        reader
+    }
+
+    /// submits a read for the next chunk into the buffer not currently consumed.
+    fn submit_read_ahead(&mut self) -> io::Result<()> {
+        let Some(ring) = &self.ring else {
+            return Ok(());
+        };
+        if self.pending.is_some() || self.next_offset >= self.end {
+            return Ok(());
+        }
+        let buffer = &mut self.buffers[1 - self.current];
+        let offset = self.next_offset;
+        // SAFETY: the buffer stays alive and is not touched until the read
+        // has completed, as we always wait for pending reads before
+        // accessing the buffer, and leak it if waiting fails on drop.
+        let token = unsafe {
+            ring.ring.lock().unwrap().submit_read(
+                self.region.file(),
+                buffer.as_mut_slice(),
+                offset,
+            )?
+        };
+        self.pending = Some(PendingRead { token, offset });
+        self.next_offset += buffer.len() as u64;
+        Ok(())
+    }
+
+    /// waits for the pending read to complete, if there is one.
+    /// If waiting fails, the read stays pending.
+    fn wait_pending(&mut self) -> io::Result<Option<(u64, usize)>> {
+        match (&self.pending, &self.ring) {
+            (Some(pending), Some(ring)) => {
+                let bytes_read = ring.ring.lock().unwrap().wait(pending.token)?;
+                let offset = pending.offset;
+                self.pending = None;
+                Ok(Some((offset, bytes_read)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// makes the next chunk the current one.
+    /// returns false if there is no more data.
+    fn advance_chunk(&mut self) -> io::Result<bool> {
+        let (offset, bytes_read) = match self.wait_pending()? {
+            Some(completed) => completed,
+            None => {
+                if self.next_offset >= self.end {
+                    return Ok(false);
+                }
+                let offset = self.next_offset;
+                let buffer = &mut self.buffers[1 - self.current];
+                let bytes_read = read_at_full(&self.region, buffer.as_mut_slice(), offset)?;
+                self.next_offset += buffer.len() as u64;
+                (offset, bytes_read)
+            }
+        };
+        self.current = 1 - self.current;
+        self.consumed = 0;
+        // the chunk may contain the padding of the final block,
+        // which is not part of our data.
+        let logical_len = (self.end - offset).min(bytes_read as u64) as usize;
+        self.valid = logical_len;
+        if logical_len == 0 {
+            return Ok(false);
+        }
+        self.submit_read_ahead()?;
+        Ok(true)
+    }
+}
+
+/// keeps reading at the offset until the buffer is full or we reach the end of the file.
+fn read_at_full(region: &impl FileRegion, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+    use crate::run::split_backing::PositionalBacking;
+
+    let mut total = 0;
+    while total < buffer.len() {
+        match region
+            .file()
+            .read_at(offset + total as u64, &mut buffer[total..])
+        {
+            Ok(0) => break,
+            Ok(read) => total += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+        // with O_DIRECT, a short read means that we have reached the end of the file
+        // (or the read was interrupted at an unaligned position, which can not be continued).
+        if total % ALIGNMENT != 0 {
+            break;
+        }
+    }
+    Ok(total)
+}
+
+impl<R: FileRegion> Read for DirectReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.consumed == self.valid && !self.advance_chunk()? {
+            return Ok(0);
+        }
+        let available = &self.buffers[self.current].as_slice()[self.consumed..self.valid];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consumed += len;
+        Ok(len)
+    }
+}
+
+impl<R: FileRegion> Drop for DirectReader<R> {
+    fn drop(&mut self) {
+        // the kernel may still be writing into our buffer,
+        // so we must not release it before the read has completed.
+        if self.wait_pending().is_ok() {
+            // SAFETY: no read is in flight anymore and the buffers are not used after this.
+            unsafe { ManuallyDrop::drop(&mut self.buffers) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use crate::{ExtSortOrdExtension, ExtsortConfig};
+
+    use super::{align_up, enable_direct_io, DirectReader, DirectWriter, SharedRing, ALIGNMENT};
+
+    #[test]
+    fn test_align_up() {
+        assert_eq!(0, align_up(0));
+        assert_eq!(ALIGNMENT, align_up(1));
+        assert_eq!(ALIGNMENT, align_up(ALIGNMENT));
+        assert_eq!(2 * ALIGNMENT, align_up(ALIGNMENT + 1));
+    }
+
+    #[test]
+    fn test_writer_pads() {
+        let mut target = Vec::new();
+        let mut writer = DirectWriter::new(&mut target);
+        writer.write_all(&[1; 5000]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(2 * ALIGNMENT, target.len());
+        assert!(target[..5000].iter().all(|b| *b == 1));
+        assert!(target[5000..].iter().all(|b| *b == 0));
+    }
+
+    /// returns true if the folder is on an in memory file system,
+    /// which does not support direct IO.
+    #[cfg(not(miri))]
+    fn is_tmpfs(folder: &std::path::Path) -> bool {
+        let path = std::ffi::CString::new(folder.to_str().unwrap()).unwrap();
+        // SAFETY: statfs only writes to the provided struct.
+        let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+        let res = unsafe { libc::statfs(path.as_ptr(), &mut stats) };
+        res != 0 || stats.f_type == libc::TMPFS_MAGIC
+    }
+
+    #[cfg(not(miri))]
+    fn roundtrip(ring: Option<SharedRing>) {
+        use std::os::fd::AsRawFd;
+
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let folder = std::env::temp_dir();
+        let mut file = crate::tape::file::create_file(&folder, 0).unwrap();
+        enable_direct_io(&file).unwrap();
+        if !is_tmpfs(&folder) {
+            // make sure we actually exercise unbuffered IO where it is supported
+            // SAFETY: we only query the status flags of a file descriptor we own.
+            let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+            assert_ne!(0, flags & libc::O_DIRECT);
+        }
+        let mut writer = DirectWriter::new(&mut file);
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut reader = DirectReader::new(file, data.len() as u64, 3 * ALIGNMENT, ring);
+        let mut read_back = Vec::new();
+        reader.read_to_end(&mut read_back).unwrap();
+        assert_eq!(data, read_back);
+    }
+
+    #[test]
+    #[cfg(not(miri))]
+    fn test_roundtrip_blocking() {
+        roundtrip(None);
+    }
+
+    #[test]
+    #[cfg(not(miri))]
+    fn test_roundtrip_uring() {
+        // io_uring may be disabled in the environment, which the reader handles gracefully.
+        roundtrip(SharedRing::new(4));
+    }
+
+    #[test]
+    #[cfg(not(miri))]
+    fn test_drop_with_pending_read() {
+        let Some(ring) = SharedRing::new(4) else {
+            return;
+        };
+        let mut file = crate::tape::file::create_file(&std::env::temp_dir(), 0).unwrap();
+        enable_direct_io(&file).unwrap();
+        let mut writer = DirectWriter::new(&mut file);
+        writer.write_all(&[7; 3 * ALIGNMENT]).unwrap();
+        writer.flush().unwrap();
+
+        let mut reader = DirectReader::new(file, 3 * ALIGNMENT as u64, ALIGNMENT, Some(ring));
+        let mut buf = [0; 10];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!([7; 10], buf);
+        // the read ahead of the second chunk is still in flight
+        assert!(reader.pending.is_some());
+        drop(reader);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_direct_io() {
+        // enough runs to make them share files. If the temp folder is on tmpfs,
+        // which does not support O_DIRECT, the files fall back to buffered IO.
+        let config = ExtsortConfig::with_buffer_size(16)
+            .temp_file_folder(std::env::temp_dir())
+            .direct_io(true);
+        let sorted = (0..2000).rev().external_sort(config).unwrap();
+        assert!(sorted.eq(0..2000));
+    }
+}
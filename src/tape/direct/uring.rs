@@ -0,0 +1,106 @@
+//! Submitting reads to an io_uring and waiting for their completion.
+
+use std::{collections::HashMap, fs::File, io, os::fd::AsRawFd};
+
+use io_uring::{opcode, types, IoUring};
+
+pub struct Ring {
+    ring: IoUring,
+    /// the number of queued operations whose completion has not been reaped yet
+    in_flight: u32,
+    next_token: u64,
+    /// completions that have been reaped, but not been waited for yet.
+    completed: HashMap<u64, i32>,
+}
+
+impl Ring {
+    pub fn new(entries: u32) -> io::Result<Self> {
+        Ok(Self {
+            ring: IoUring::new(entries)?,
+            in_flight: 0,
+            next_token: 0,
+            completed: HashMap::new(),
+        })
+    }
+
+    /// Queues a read of the file at the provided offset into the buffer.
+    /// returns a token to wait for the completion with.
+    ///
+    /// Once a token is returned, the read may be carried out by the kernel at any time,
+    /// even if passing it to the kernel right away failed. It is passed on again
+    /// with the next submission or wait.
+    ///
+    /// # Safety
+    /// The buffer must not be accessed or released until the read has been waited for successfully.
+    pub unsafe fn submit_read(
+        &mut self,
+        file: &File,
+        buffer: &mut [u8],
+        offset: u64,
+    ) -> io::Result<u64> {
+        // we never have more operations in flight than there are submission entries,
+        // which guarantees that neither queue can overflow.
+        while self.in_flight >= self.ring.params().sq_entries() {
+            self.submit_and_wait()?;
+            self.reap();
+        }
+
+        let token = self.next_token;
+        self.next_token += 1;
+        let entry = opcode::Read::new(
+            types::Fd(file.as_raw_fd()),
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+        )
+        .offset(offset)
+        .build()
+        .user_data(token);
+        self.ring
+            .submission()
+            .push(&entry)
+            .map_err(|_| io::Error::other("the io_uring submission queue is full"))?;
+        self.in_flight += 1;
+
+        // the read is queued, so it counts as in flight no matter what happens here.
+        // If the kernel did not take it, it is submitted again once we wait.
+        self.ring.submit().ok();
+        Ok(token)
+    }
+
+    /// waits for the operation with the provided token to complete
+    /// and returns the number of bytes read.
+    ///
+    /// If this fails, the operation may still be in flight.
+    pub fn wait(&mut self, token: u64) -> io::Result<usize> {
+        loop {
+            self.reap();
+            if let Some(res) = self.completed.remove(&token) {
+                return if res < 0 {
+                    Err(io::Error::from_raw_os_error(-res))
+                } else {
+                    Ok(res as usize)
+                };
+            }
+            self.submit_and_wait()?;
+        }
+    }
+
+    /// submits all queued operations and waits for at least one completion.
+    fn submit_and_wait(&mut self) -> io::Result<()> {
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// moves all available completions into the completed map.
+    fn reap(&mut self) {
+        for entry in self.ring.completion() {
+            self.completed.insert(entry.user_data(), entry.result());
+            self.in_flight -= 1;
+        }
+    }
+}
@@ -1,25 +1,116 @@
 use std::fs;
 use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
 use std::{io, path::Path};
 
+/// the number of times we try to find an unused file name before giving up.
+const MAX_NAME_ATTEMPTS: usize = 32;
+
+/// Generates a file name in the provided folder that is hard to guess.
+fn random_file_name(folder: &Path, attempt: usize) -> PathBuf {
+    // RandomState is seeded randomly per process and incremented per instance,
+    // which is all the randomness we need to avoid accidental collisions
+    // and make the name hard to predict.
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_usize(attempt);
+    let suffix = hasher.finish();
+    folder.join(format!(
+        "extsort_{}_{:016x}.run",
+        std::process::id(),
+        suffix
+    ))
+}
+
+/// Creates a new file with a random name in the provided folder, retrying on collisions.
+fn create_named_file(
+    folder: &Path,
+    open: impl Fn(&Path) -> io::Result<File>,
+) -> io::Result<(File, PathBuf)> {
+    let mut attempt = 0;
+    loop {
+        let filename = random_file_name(folder, attempt);
+        match open(&filename) {
+            Ok(file) => return Ok((file, filename)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempt < MAX_NAME_ATTEMPTS => {
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 #[cfg(windows)]
-pub fn create_file(filename: &Path) -> io::Result<File> {
-    fs::OpenOptions::new()
-        .create_new(true)
-        .write(true)
-        .read(true)
-        .custom_flags(winapi::FILE_FLAG_DELETE_ON_CLOSE)
-        .open(filename)
+pub fn create_file(folder: &Path, _preallocate_bytes: u64) -> io::Result<File> {
+    let (file, _) = create_named_file(folder, |filename| {
+        fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .read(true)
+            .custom_flags(winapi::FILE_FLAG_DELETE_ON_CLOSE)
+            .open(filename)
+    })?;
+    Ok(file)
 }
 
 #[cfg(not(windows))]
-/// Creates the file that we want to use for the run later.
-pub fn create_file(filename: &Path) -> io::Result<File> {
-    let file = fs::OpenOptions::new()
-        .create_new(true)
+/// Creates the file that we want to use for the run later in the provided folder.
+/// Disk space for the expected size of the run is reserved up front
+/// where the platform supports it.
+///
+/// The file is never visible in the folder where the platform supports anonymous files,
+/// and is only visible to the current user for a brief moment otherwise.
+pub fn create_file(folder: &Path, preallocate_bytes: u64) -> io::Result<File> {
+    let file = match create_anonymous_file(folder)? {
+        Some(file) => file,
+        None => create_unlinked_file(folder)?,
+    };
+
+    preallocate(&file, preallocate_bytes)?;
+
+    Ok(file)
+}
+
+/// Creates an unnamed file in the provided folder using O_TMPFILE.
+/// returns None if the kernel or the filesystem do not support it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn create_anonymous_file(folder: &Path) -> io::Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let res = fs::OpenOptions::new()
         .write(true)
         .read(true)
-        .open(filename)?;
+        .mode(0o600)
+        .custom_flags(libc::O_TMPFILE)
+        .open(folder);
+    match res {
+        Ok(file) => Ok(Some(file)),
+        // kernels without O_TMPFILE support treat the flag as O_DIRECTORY
+        // and fail with EISDIR, filesystems without support fail with EOPNOTSUPP.
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EISDIR | libc::EOPNOTSUPP)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn create_anonymous_file(_folder: &Path) -> io::Result<Option<File>> {
+    Ok(None)
+}
+
+/// Creates a file only readable by us in the provided folder
+/// and immediately removes it from the folder.
+#[cfg(not(windows))]
+fn create_unlinked_file(folder: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let (file, filename) = create_named_file(folder, |filename| {
+        fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .read(true)
+            .mode(0o600)
+            .open(filename)
+    })?;
 
     // we immediately delete the file, but keep the handle open
     // this has 2 advantages:
@@ -31,3 +122,110 @@ pub fn create_file(filename: &Path) -> io::Result<File> {
 
     Ok(file)
 }
+
+/// Reserves disk space for the first len bytes of the file without changing its size.
+/// This way, the file is less likely to be fragmented and we will notice
+/// a full disk before we have written anything.
+///
+/// Filesystems that do not support preallocation are silently ignored.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+    let len = libc::off_t::try_from(len).unwrap_or(libc::off_t::MAX);
+    // SAFETY: fallocate does not touch any memory owned by us and the fd
+    // is kept alive by the file reference for the duration of the call.
+    let res = unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) };
+    if res == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP | libc::ENOSYS) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Returns the number of bytes available to unprivileged users
+/// on the filesystem containing the provided folder.
+/// Returns None if the platform does not support the query.
+#[cfg(unix)]
+pub fn available_space(folder: &Path) -> io::Result<Option<u64>> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(folder.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stats = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid nul terminated string and statvfs
+    // only writes to the provided stats struct.
+    let res = unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statvfs returned successfully, so the struct has been filled.
+    let stats = unsafe { stats.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    let available = stats.f_bavail as u64 * stats.f_frsize as u64;
+    Ok(Some(available))
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_folder: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Seek, SeekFrom, Write},
+        path::Path,
+    };
+
+    #[test]
+    #[cfg(all(unix, not(miri)))]
+    fn test_create_file() {
+        let folder = std::env::temp_dir().join(format!("extsort_file_test_{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+
+        let mut file = super::create_file(&folder, 4096).unwrap();
+        file.write_all(b"hello").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!("hello", content);
+
+        // the file should never show up in the folder
+        assert_eq!(0, std::fs::read_dir(&folder).unwrap().count());
+
+        // the fallback should leave the folder empty as well
+        let _unlinked = super::create_unlinked_file(&folder).unwrap();
+        assert_eq!(0, std::fs::read_dir(&folder).unwrap().count());
+
+        std::fs::remove_dir(&folder).unwrap();
+    }
+
+    #[test]
+    fn test_random_names() {
+        let folder = Path::new("/tmp");
+        assert_ne!(
+            super::random_file_name(folder, 0),
+            super::random_file_name(folder, 0)
+        );
+    }
+
+    #[test]
+    #[cfg(all(unix, not(miri)))]
+    fn test_available_space() {
+        let available = super::available_space(Path::new("/tmp")).unwrap();
+        assert!(available.is_some());
+        assert!(super::available_space(Path::new("/does/not/exist")).is_err());
+    }
+}
@@ -4,163 +4,792 @@ use std::{
     marker::PhantomData,
     num::NonZeroUsize,
     path::PathBuf,
-    process,
 };
 
-use crate::run::{file_run::ExternalRun, split_backing::SplitView};
+use crate::{
+    merge::LoserTree,
+    orderer::Orderer,
+    run::{file_run::ExternalRun, read_budget::ReadBudget, split_backing::SplitView, Run, TapeRun},
+    ExtsortConfig,
+};
+
+#[cfg(unix)]
+use crate::run::mapped_run::MappedRun;
 
-use self::compressor::CompressionCodec;
+use self::{
+    compressor::{CompressionCodec, CompressionPool},
+    page_cache::FileRegion,
+    quota::{TempLimitExceeded, TempQuota},
+    throttle::IoRateLimiter,
+};
 
 pub mod compressor;
-mod file;
+#[cfg(all(target_os = "linux", feature = "direct_io"))]
+mod direct;
+pub(crate) mod file;
+mod page_cache;
+pub mod quota;
+pub mod throttle;
 
 pub struct TapeCollection<T> {
-    next_file_name: PathBuf,
+    /// the folders to place the sort files in, in order of preference.
+    sort_folders: Vec<PathBuf>,
+    /// the index of the folder new files are created in
+    current_folder: usize,
+    /// after failing over to another folder with all file slots in use,
+    /// this file receives all further shared runs.
+    failover_backing: Option<SplitView<File>>,
     max_files: usize,
     phantom: PhantomData<T>,
     plain_tapes: Vec<Tape<File>>,
     shared_tapes: Vec<Tape<SplitView<File>>>,
+    /// the runs kept in memory instead of on disk
+    memory_tapes: Vec<Tape<io::Cursor<Vec<u8>>>>,
+    /// whether to keep all runs in memory
+    spill_to_memory: bool,
     next_tape_idx: usize,
     compression_choice: CompressionCodec,
+    /// the threads to compress runs on
+    compression_pool: CompressionPool,
+    quota: TempQuota,
+    /// limits the bandwidth used for writing and reading the sort files
+    rate_limiter: Option<IoRateLimiter>,
+    /// whether to tell the kernel about our access patterns
+    page_cache_hints: bool,
+    /// whether to read uncompressed runs through memory mappings
+    memory_map_runs: bool,
+    /// whether to bypass the page cache
+    #[cfg(all(target_os = "linux", feature = "direct_io"))]
+    direct_io: bool,
+    /// the simulated free space of each sort folder
+    #[cfg(all(test, not(miri)))]
+    free_space: Vec<u64>,
 }
 
 impl<T> TapeCollection<T> {
-    pub fn into_tapes(
-        self,
-        read_buffer_size: NonZeroUsize,
-    ) -> Vec<ExternalRun<T, Box<dyn Read + Send>>> {
-        let num_tapes = self.plain_tapes.len() + self.shared_tapes.len();
+    pub fn into_tapes(self, read_buffer_size: NonZeroUsize) -> Vec<TapeRun<T>> {
+        let num_tapes = self.plain_tapes.len() + self.shared_tapes.len() + self.memory_tapes.len();
 
         if num_tapes == 0 {
             return Vec::new();
         }
 
-        let read_buffer_items = usize::from(read_buffer_size) / num_tapes;
-        let one = NonZeroUsize::new(1).unwrap();
-        let read_buffer_items = NonZeroUsize::new(read_buffer_items).unwrap_or(one);
+        let reader = self.reader(read_buffer_size, num_tapes);
+        let read_buffer_items = reader.read_buffer_items;
 
-        self.plain_tapes
+        let mut runs: Vec<TapeRun<T>> = self
+            .plain_tapes
             .into_iter()
-            .map(|t| t.box_backing(self.compression_choice))
+            .map(|t| reader.open(t))
+            .chain(self.shared_tapes.into_iter().map(|t| reader.open(t)))
             .chain(
-                self.shared_tapes
+                self.memory_tapes
                     .into_iter()
-                    .map(|t| t.box_backing(self.compression_choice)),
+                    .map(|t| reader.open_in_memory(t)),
             )
-            .map(|t| ExternalRun::from_tape(t, read_buffer_items))
-            .collect()
+            .collect();
+
+        // the buffered runs take over the read buffers of those that finish first,
+        // so the last runs of the merge are read in large chunks.
+        let num_buffered = runs
+            .iter()
+            .filter(|run| matches!(run, TapeRun::Buffered(_)))
+            .count();
+        let budget = ReadBudget::new(read_buffer_items.get() * num_buffered, num_buffered);
+        for run in &mut runs {
+            if let TapeRun::Buffered(run) = run {
+                run.share_read_budget(budget.clone());
+            }
+        }
+        runs
     }
-    pub fn new(
-        sort_folder: PathBuf,
-        max_files: NonZeroUsize,
-        compression_choice: CompressionCodec,
-    ) -> Self {
-        let mut next_file_name = sort_folder;
-        next_file_name.push("dummy");
+    pub fn new(config: &ExtsortConfig, max_files: NonZeroUsize) -> Self {
+        let sort_folders = std::iter::once(&config.temp_file_folder)
+            .chain(&config.fallback_temp_file_folders)
+            .cloned()
+            .collect();
         Self {
             max_files: max_files.into(),
-            next_file_name,
+            sort_folders,
+            current_folder: 0,
+            failover_backing: None,
             next_tape_idx: 0,
             phantom: PhantomData,
             plain_tapes: Vec::new(),
             shared_tapes: Vec::new(),
-            compression_choice,
+            memory_tapes: Vec::new(),
+            spill_to_memory: config.spill_to_memory.is_some(),
+            compression_choice: config.compression_choice(),
+            compression_pool: config.compression_pool(),
+            quota: TempQuota::new(config.temp_limit()),
+            rate_limiter: config.io_rate_limiter.clone(),
+            page_cache_hints: config.page_cache_hints,
+            memory_map_runs: config.memory_map_runs,
+            #[cfg(all(target_os = "linux", feature = "direct_io"))]
+            direct_io: config.direct_io,
+            #[cfg(all(test, not(miri)))]
+            free_space: Vec::new(),
         }
     }
-    pub fn add_run(&mut self, source: &mut Vec<T>) -> io::Result<()> {
-        if self.next_tape_idx < self.max_files {
-            self.add_run_simple(source)?;
+
+    /// prepares reading num_tapes tapes at once, with read buffers
+    /// holding read_buffer_size items in total.
+    fn reader(&self, read_buffer_size: NonZeroUsize, num_tapes: usize) -> TapeReader {
+        let read_buffer_items = usize::from(read_buffer_size) / num_tapes;
+        let one = NonZeroUsize::new(1).unwrap();
+        let read_buffer_items = NonZeroUsize::new(read_buffer_items).unwrap_or(one);
+
+        #[cfg(all(target_os = "linux", feature = "direct_io"))]
+        let (read_buffer_items, direct) = if self.direct_io {
+            // every run reads ahead into two chunks, so the budget
+            // is split evenly between those and the buffer of the run.
+            let read_buffer_items = NonZeroUsize::new(read_buffer_items.get() / 3).unwrap_or(one);
+            let chunk_size = read_buffer_items.get() * std::mem::size_of::<T>();
+            let ring = direct::SharedRing::new(RING_ENTRIES);
+            (read_buffer_items, Some((chunk_size, ring)))
         } else {
-            self.add_run_shared(source)?;
+            (read_buffer_items, None)
+        };
+
+        TapeReader {
+            read_buffer_items,
+            page_cache_hints: self.page_cache_hints,
+            // memory mapped runs are read by the kernel behind our back,
+            // so we can not hold them to the limit.
+            memory_map: self.memory_map_runs && self.rate_limiter.is_none(),
+            rate_limiter: self.rate_limiter.clone(),
+            #[cfg(all(target_os = "linux", feature = "direct_io"))]
+            direct,
+        }
+    }
+
+    /// Creates collections for several writers.
+    /// They share the temp storage limit and split the maximum number of files between them.
+    pub fn new_group(
+        config: &ExtsortConfig,
+        max_files: NonZeroUsize,
+        count: NonZeroUsize,
+    ) -> Vec<Self> {
+        let quota = TempQuota::new(config.temp_limit());
+        Self::new_group_sharing(config, max_files, count, &quota)
+    }
+
+    /// Creates collections for several writers like [`new_group`](Self::new_group),
+    /// but counts their writes against the provided quota.
+    pub(crate) fn new_group_sharing(
+        config: &ExtsortConfig,
+        max_files: NonZeroUsize,
+        count: NonZeroUsize,
+        quota: &TempQuota,
+    ) -> Vec<Self> {
+        let one = NonZeroUsize::new(1).unwrap();
+        let files_each = NonZeroUsize::new(max_files.get() / count.get()).unwrap_or(one);
+        (0..count.get())
+            .map(|_| Self {
+                quota: quota.clone(),
+                ..Self::new(config, files_each)
+            })
+            .collect()
+    }
+
+    /// the number of bytes all runs of the collection take up.
+    pub fn len_bytes(&self) -> u64 {
+        let plain = self.plain_tapes.iter().map(|t| t.len_bytes);
+        let shared = self.shared_tapes.iter().map(|t| t.len_bytes);
+        let memory = self.memory_tapes.iter().map(|t| t.len_bytes);
+        plain.chain(shared).chain(memory).sum()
+    }
+
+    /// takes over all runs written to the other collection.
+    pub fn absorb(&mut self, other: Self) {
+        self.plain_tapes.extend(other.plain_tapes);
+        self.shared_tapes.extend(other.shared_tapes);
+        self.memory_tapes.extend(other.memory_tapes);
+    }
+
+    /// Moves the provided run to disk.
+    ///
+    /// If the disk runs out of space, the run is retried in the next configured
+    /// fallback folder, or with compression enabled if that is possible.
+    /// When the call fails, source remains untouched.
+    pub fn add_run(&mut self, source: &mut Vec<T>) -> io::Result<()> {
+        self.add_run_from(source, 0)
+    }
+
+    /// Moves the items of the source starting at the provided index to disk as a run,
+    /// leaving the items before it in the source.
+    /// When the call fails, source remains untouched.
+    pub fn add_run_tail(&mut self, source: &mut Vec<T>, start: usize) -> io::Result<()> {
+        self.add_run_from(
+            &mut VecTail {
+                items: source,
+                start,
+            },
+            0,
+        )
+    }
+
+    /// Moves the run provided by the source to disk, on the provided level.
+    /// Runs are only retried after a full disk if the source allows it.
+    fn add_run_from(&mut self, source: &mut impl RunSource<T>, level: u32) -> io::Result<()> {
+        if let Err(e) = self.quota.check() {
+            // another collection sharing our quota has hit the limit
+            self.clear();
+            return Err(e);
+        }
+        loop {
+            let result = if self.spill_to_memory {
+                self.add_run_in_memory(source, level)
+            } else if self.next_tape_idx < self.max_files
+                || (self.plain_tapes.is_empty() && self.shared_tapes.is_empty())
+            {
+                self.add_run_simple(source, level)
+            } else {
+                self.add_run_shared(source, level)
+            };
+            match result {
+                Ok(()) => break,
+                Err(e)
+                    if e.kind() == io::ErrorKind::StorageFull
+                        && source.retryable()
+                        && self.fail_over() => {}
+                Err(e) => {
+                    if TempLimitExceeded::is_cause_of(&e) {
+                        self.clear();
+                    }
+                    return Err(e);
+                }
+            }
         }
         self.next_tape_idx += 1;
         Ok(())
     }
-    fn add_run_shared(&mut self, source: &mut Vec<T>) -> io::Result<()> {
-        let selected_tape_idx = if let Some(tape) = self.plain_tapes.pop() {
-            let shared_tape = Tape {
-                backing: SplitView::new(tape.backing)?,
-                num_entries: tape.num_entries,
+
+    /// Removes all runs after the sort has failed.
+    ///
+    /// The sort can not complete anymore, so we release the space taken up
+    /// by the runs that were already written instead of waiting for the collection to be dropped.
+    /// The other collections sharing our quota do the same once they add their next run.
+    fn clear(&mut self) {
+        self.plain_tapes.clear();
+        self.shared_tapes.clear();
+        self.memory_tapes.clear();
+        self.failover_backing = None;
+    }
+
+    /// Merges the runs of a level into a single run of the next level
+    /// as long as there are fan_in runs of the same level.
+    /// This keeps the number of runs logarithmic in the number of runs added.
+    ///
+    /// The buffers of each merge hold up to memory_items items together.
+    /// If a merge fails, the runs it was merging are lost.
+    ///
+    /// The runs that had a file or memory to themselves are refunded to the quota once merged.
+    /// Runs stored as segments of a shared file keep counting against it, as their
+    /// space is only freed once all runs of that file are gone.
+    pub fn compact<O: Orderer<T>>(
+        &mut self,
+        orderer: &O,
+        fan_in: usize,
+        memory_items: NonZeroUsize,
+    ) -> io::Result<()> {
+        let fan_in = fan_in.max(2);
+        // half of the memory is used to read the runs, the other half to collect the output.
+        let one = NonZeroUsize::new(1).unwrap();
+        let half = NonZeroUsize::new(memory_items.get() / 2).unwrap_or(one);
+        while let Some(level) = self.level_to_compact(fan_in) {
+            let reader = self.reader(half, fan_in);
+            let mut runs: Vec<TapeRun<T>> = Vec::with_capacity(fan_in);
+            let mut freed_bytes = 0;
+            runs.extend(
+                take_level(&mut self.plain_tapes, level, fan_in)
+                    .into_iter()
+                    .inspect(|t| freed_bytes += t.len_bytes)
+                    .map(|t| reader.open(t)),
+            );
+            runs.extend(
+                take_level(&mut self.shared_tapes, level, fan_in - runs.len())
+                    .into_iter()
+                    .map(|t| reader.open(t)),
+            );
+            runs.extend(
+                take_level(&mut self.memory_tapes, level, fan_in - runs.len())
+                    .into_iter()
+                    .inspect(|t| freed_bytes += t.len_bytes)
+                    .map(|t| reader.open_in_memory(t)),
+            );
+
+            let num_entries = runs.iter().map(|run| run.remaining_items()).sum();
+            let mut source = MergeSource {
+                merger: LoserTree::new(runs, orderer),
+                num_entries,
+                chunk: Vec::with_capacity(half.get()),
+            };
+            let result = self.add_run_from(&mut source, level + 1);
+            // the merged runs are gone either way.
+            drop(source);
+            self.quota.release(freed_bytes);
+            result?;
+        }
+        Ok(())
+    }
+
+    /// returns the lowest level with at least fan_in runs, if any.
+    fn level_to_compact(&self, fan_in: usize) -> Option<u32> {
+        let mut runs_per_level = std::collections::BTreeMap::new();
+        let levels = self
+            .plain_tapes
+            .iter()
+            .map(|t| t.level)
+            .chain(self.shared_tapes.iter().map(|t| t.level))
+            .chain(self.memory_tapes.iter().map(|t| t.level));
+        for level in levels {
+            *runs_per_level.entry(level).or_insert(0) += 1;
+        }
+        runs_per_level
+            .into_iter()
+            .find(|(_, count)| *count >= fan_in)
+            .map(|(level, _)| level)
+    }
+
+    /// Adjusts the collection so that retrying a run that failed with
+    /// a full disk has a chance of succeeding.
+    /// returns false if there is nothing left to try.
+    fn fail_over(&mut self) -> bool {
+        if self.current_folder + 1 < self.sort_folders.len() {
+            self.current_folder += 1;
+            // the shared files we have are all located in the full folders,
+            // so a new one will be created on the next shared run.
+            self.failover_backing = None;
+            return true;
+        }
+
+        #[cfg(feature = "compression_lz4_flex")]
+        if matches!(self.compression_choice, CompressionCodec::NoCompression) {
+            self.compression_choice = CompressionCodec::Lz4Flex;
+            return true;
+        }
+
+        false
+    }
+
+    /// keeps the run in memory, compressed with the configured codec.
+    fn add_run_in_memory(&mut self, source: &mut impl RunSource<T>, level: u32) -> io::Result<()> {
+        let num_entries = source.num_entries();
+        let mut backing = Vec::new();
+        let len_bytes = fill_backing(
+            source,
+            &mut backing,
+            self.compression_choice,
+            &self.compression_pool,
+            &self.quota,
+            // memory is not subject to the IO limit
+            None,
+        )?;
+        // the run is kept until the merge, so we do not want to hold on to spare capacity.
+        backing.shrink_to_fit();
+
+        self.memory_tapes.push(Tape {
+            num_entries,
+            len_bytes,
+            backing: io::Cursor::new(backing),
+            compression: self.compression_choice,
+            level,
+        });
+        Ok(())
+    }
+
+    fn add_run_shared(&mut self, source: &mut impl RunSource<T>, level: u32) -> io::Result<()> {
+        let num_entries = source.num_entries();
+
+        let mut new_backing = if self.current_folder > 0 {
+            // we have failed over to another folder, so all further shared
+            // runs need to end up there. This means that we may have one file
+            // more open than max_files per failover.
+            let backing = match &mut self.failover_backing {
+                Some(backing) => backing,
+                None => {
+                    let file = self.create_run_file(num_entries)?;
+                    self.failover_backing.insert(SplitView::new(file)?)
+                }
             };
-            self.shared_tapes.push(shared_tape);
-            self.shared_tapes.len() - 1
+            backing.add_segment()?
         } else {
-            self.next_tape_idx % self.max_files
+            let selected_tape_idx = if let Some(tape) = self.plain_tapes.pop() {
+                let shared_tape = Tape {
+                    backing: SplitView::new(tape.backing)?,
+                    num_entries: tape.num_entries,
+                    len_bytes: tape.len_bytes,
+                    compression: tape.compression,
+                    level: tape.level,
+                };
+                self.shared_tapes.push(shared_tape);
+                self.shared_tapes.len() - 1
+            } else {
+                // merged runs may have been taken out, so there can be fewer shared tapes than files.
+                self.next_tape_idx % self.max_files.min(self.shared_tapes.len())
+            };
+            self.shared_tapes[selected_tape_idx].backing.add_segment()?
         };
-        let mut new_backing = self.shared_tapes[selected_tape_idx].backing.add_segment()?;
 
-        let num_entries = source.len();
-        fill_backing(source, &mut new_backing, self.compression_choice)?;
+        let len_bytes = self.write_run(source, &mut new_backing)?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.page_cache_hints {
+            let segment = new_backing.segment();
+            page_cache::evict_written(
+                new_backing.backing(),
+                segment.start,
+                segment.end - segment.start,
+            );
+        }
         self.shared_tapes.push(Tape {
             backing: new_backing.into(),
             num_entries,
+            len_bytes,
+            compression: self.compression_choice,
+            level,
         });
 
         Ok(())
     }
 
-    fn add_run_simple(&mut self, source: &mut Vec<T>) -> io::Result<()> {
-        let pid = process::id();
-        let self_addr = self as *const Self as usize;
-        self.next_file_name.set_file_name(format!(
-            "{}_{}_sort_file_{}",
-            pid, self_addr, self.next_tape_idx
-        ));
-        let mut file = file::create_file(&self.next_file_name)?;
-        let num_entries = source.len();
-        fill_backing(source, &mut file, self.compression_choice)?;
+    /// creates a new, empty sort file in the current sort folder
+    /// with room for the provided number of entries.
+    fn create_run_file(&self, num_entries: usize) -> io::Result<File> {
+        // we only know the size of the run on disk if it is not compressed.
+        // Preallocating the uncompressed size would otherwise keep
+        // the surplus space reserved until the file is closed.
+        let expected_size = match self.compression_choice {
+            CompressionCodec::NoCompression => (num_entries * std::mem::size_of::<T>()) as u64,
+            #[allow(unreachable_patterns)]
+            _ => 0,
+        };
+        // space beyond the temp storage limit would never be written.
+        let expected_size = expected_size.min(self.quota.remaining());
+        let file = file::create_file(&self.sort_folders[self.current_folder], expected_size)?;
+        #[cfg(all(target_os = "linux", feature = "direct_io"))]
+        if self.direct_io {
+            direct::enable_direct_io(&file)?;
+        }
+        Ok(file)
+    }
+
+    /// writes the run to the provided backing.
+    /// returns the number of bytes the run takes up, excluding any padding.
+    fn write_run(
+        &mut self,
+        source: &mut impl RunSource<T>,
+        backing: &mut impl Write,
+    ) -> io::Result<u64> {
+        #[cfg(all(test, not(miri)))]
+        if let Some(space) = self.free_space.get_mut(self.current_folder) {
+            return fill_backing(
+                source,
+                &mut test::LimitedWriter {
+                    inner: backing,
+                    space,
+                },
+                self.compression_choice,
+                &self.compression_pool,
+                &self.quota,
+                self.rate_limiter.as_ref(),
+            );
+        }
+        #[cfg(all(target_os = "linux", feature = "direct_io"))]
+        if self.direct_io {
+            return fill_backing(
+                source,
+                &mut direct::DirectWriter::new(backing),
+                self.compression_choice,
+                &self.compression_pool,
+                &self.quota,
+                self.rate_limiter.as_ref(),
+            );
+        }
+        fill_backing(
+            source,
+            backing,
+            self.compression_choice,
+            &self.compression_pool,
+            &self.quota,
+            self.rate_limiter.as_ref(),
+        )
+    }
+
+    fn add_run_simple(&mut self, source: &mut impl RunSource<T>, level: u32) -> io::Result<()> {
+        let num_entries = source.num_entries();
+        let mut file = self.create_run_file(num_entries)?;
+        let len_bytes = self.write_run(source, &mut file)?;
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if self.page_cache_hints {
+            page_cache::evict_written(&file, 0, 0);
+        }
 
         // seek to the beginning of the file to ensure that we will actually read its contents
         file.seek(io::SeekFrom::Start(0))?;
 
         self.plain_tapes.push(Tape {
             num_entries,
+            len_bytes,
             backing: file,
+            compression: self.compression_choice,
+            level,
         });
         Ok(())
     }
 }
 
+/// takes up to max tapes of the provided level out of the tapes.
+fn take_level<B>(tapes: &mut Vec<Tape<B>>, level: u32, max: usize) -> Vec<Tape<B>> {
+    let mut taken = Vec::new();
+    let mut idx = 0;
+    while idx < tapes.len() && taken.len() < max {
+        if tapes[idx].level == level {
+            taken.push(tapes.remove(idx));
+        } else {
+            idx += 1;
+        }
+    }
+    taken
+}
+
 /// Fills the provided file with the values drained from source.
 /// When the call completes successfully, source will be empty.
-/// If it fails, source will remain untouched.
+/// If it fails, the source decides what remains of it.
+///
+/// All bytes written are counted against the provided quota
+/// and no faster than the rate limiter allows, if any.
+/// returns the number of bytes written.
 fn fill_backing<T, TBacking>(
-    source: &mut Vec<T>,
+    source: &mut impl RunSource<T>,
     file: &mut TBacking,
     compress_choice: CompressionCodec,
-) -> io::Result<()>
+    pool: &CompressionPool,
+    quota: &TempQuota,
+    rate_limiter: Option<&IoRateLimiter>,
+) -> io::Result<u64>
 where
     TBacking: Write,
 {
-    // we create a byteslice view into the vec
+    let mut throttled = IoRateLimiter::wrap(rate_limiter, file);
+    let mut writer = quota.writer(&mut throttled);
+    let written = source
+        .write_to(&mut writer, compress_choice, pool)
+        .and_then(|()| writer.flush());
+    match written {
+        Ok(()) => Ok(writer.bytes_written()),
+        Err(e) => {
+            // the partially written run is discarded, so its bytes do not count.
+            quota.release(writer.bytes_written());
+            Err(e)
+        }
+    }
+}
+
+/// Provides the items of a run to write to a tape.
+trait RunSource<T> {
+    /// the number of items in the run
+    fn num_entries(&self) -> usize;
+
+    /// writes all items of the run to the writer.
+    fn write_to(
+        &mut self,
+        writer: &mut impl Write,
+        compress_choice: CompressionCodec,
+        pool: &CompressionPool,
+    ) -> io::Result<()>;
+
+    /// whether the run can be written again after a failed write
+    fn retryable(&self) -> bool;
+}
+
+impl<T> RunSource<T> for Vec<T> {
+    fn num_entries(&self) -> usize {
+        self.len()
+    }
+
+    /// If the write fails, the vec remains untouched.
+    fn write_to(
+        &mut self,
+        writer: &mut impl Write,
+        compress_choice: CompressionCodec,
+        pool: &CompressionPool,
+    ) -> io::Result<()> {
+        // move the contents of the vec to the file.
+        compress_choice.write_all(writer, as_bytes(self), pool)?;
+
+        // we have conceptually moved all the data that our vec used to contain to disk.
+        // in order to make sure that the drop functions are not called twice,
+        // we will leak the content of the vec (this is conceptually the same calling mem::forget)
+        // on every item in the vec.
+        // SAFETY:
+        // this is safe because the vec is now empty after this and we no longer refer to
+        // any of the elements inside.
+        unsafe {
+            self.set_len(0);
+        }
+        Ok(())
+    }
+
+    fn retryable(&self) -> bool {
+        true
+    }
+}
+
+/// The items at the end of a vec, from start on.
+struct VecTail<'a, T> {
+    items: &'a mut Vec<T>,
+    start: usize,
+}
+
+impl<T> RunSource<T> for VecTail<'_, T> {
+    fn num_entries(&self) -> usize {
+        self.items.len() - self.start
+    }
+
+    /// If the write fails, the vec remains untouched.
+    fn write_to(
+        &mut self,
+        writer: &mut impl Write,
+        compress_choice: CompressionCodec,
+        pool: &CompressionPool,
+    ) -> io::Result<()> {
+        compress_choice.write_all(writer, as_bytes(&self.items[self.start..]), pool)?;
+        // SAFETY: the items have been moved to disk, see the Vec implementation.
+        unsafe {
+            self.items.set_len(self.start);
+        }
+        Ok(())
+    }
+
+    fn retryable(&self) -> bool {
+        true
+    }
+}
+
+/// creates a byteslice view into the items
+fn as_bytes<T>(items: &[T]) -> &[u8] {
     // SAFETY:
     // this is safe because the alignment restrictions of the byteslice are loose enough to allow this
     // and because if T is zero-sized, we will create an empty slice over T.
-    let slice = unsafe {
-        let num_bytes = source.len() * std::mem::size_of::<T>();
-        std::slice::from_raw_parts(source.as_ptr() as *const u8, num_bytes)
-    };
+    unsafe {
+        let num_bytes = std::mem::size_of_val(items);
+        std::slice::from_raw_parts(items.as_ptr() as *const u8, num_bytes)
+    }
+}
 
-    // move the contents of the vec to the file.
-    compress_choice.write_all(file, slice)?;
+/// The merged items of several runs, written in chunks.
+struct MergeSource<'a, T, O> {
+    merger: LoserTree<T, TapeRun<T>, &'a O>,
+    num_entries: usize,
+    /// holds the items between the merge and the write
+    chunk: Vec<T>,
+}
 
-    // we have conceptually moved all the data that our vec used to contain to disk.
-    // in order to make sure that the drop functions are not called twice,
-    // we will leak the content of the vec (this is conceptually the same calling mem::forget)
-    // on every item in the vec.
-    // SAFETY:
-    // this is safe because the vec is now empty after this and we no longer refer to
-    // any of the elements inside.
-    unsafe {
-        source.set_len(0);
+impl<T, O: Orderer<T>> RunSource<T> for MergeSource<'_, T, O> {
+    fn num_entries(&self) -> usize {
+        self.num_entries
+    }
+
+    /// If the write fails, the items of the runs are lost.
+    fn write_to(
+        &mut self,
+        writer: &mut impl Write,
+        compress_choice: CompressionCodec,
+        pool: &CompressionPool,
+    ) -> io::Result<()> {
+        let chunk_size = self.chunk.capacity().max(1);
+        loop {
+            self.merger.fill(&mut self.chunk, chunk_size);
+            if self.chunk.is_empty() {
+                return Ok(());
+            }
+            // the chunks are written one after another, which results in the
+            // same data as writing all items at once for all codecs.
+            compress_choice.write_all(writer, as_bytes(&self.chunk), pool)?;
+            // SAFETY: the items have been moved to disk, see the Vec implementation.
+            unsafe {
+                self.chunk.set_len(0);
+            }
+        }
     }
 
-    Ok(())
+    fn retryable(&self) -> bool {
+        false
+    }
+}
+
+/// decides how the tapes are read back during the merge
+struct TapeReader {
+    /// the size of the read buffer of each run
+    read_buffer_items: NonZeroUsize,
+    page_cache_hints: bool,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    memory_map: bool,
+    /// limits the bandwidth used for reading the runs
+    rate_limiter: Option<IoRateLimiter>,
+    /// the chunk size and ring to use for direct IO, if enabled
+    #[cfg(all(target_os = "linux", feature = "direct_io"))]
+    direct: Option<(usize, Option<direct::SharedRing>)>,
 }
 
+impl TapeReader {
+    fn open<T, B>(&self, tape: Tape<B>) -> TapeRun<T>
+    where
+        B: Read + FileRegion + 'static + Send,
+    {
+        #[cfg(all(target_os = "linux", feature = "direct_io"))]
+        if let Some((chunk_size, ring)) = &self.direct {
+            let tape = tape.box_direct(*chunk_size, ring.clone(), self.rate_limiter.as_ref());
+            return ExternalRun::from_tape(tape, self.read_buffer_items).into();
+        }
+
+        #[cfg(unix)]
+        let tape = if self.memory_map {
+            match tape.try_map() {
+                Ok(run) => return TapeRun::Mapped(run),
+                Err(tape) => tape,
+            }
+        } else {
+            tape
+        };
+
+        let read_ahead_bytes = (self.read_buffer_items.get() * std::mem::size_of::<T>()) as u64;
+        let tape = tape.box_backing(
+            self.page_cache_hints,
+            read_ahead_bytes,
+            self.rate_limiter.as_ref(),
+        );
+        ExternalRun::from_tape(tape, self.read_buffer_items).into()
+    }
+
+    fn open_in_memory<T>(&self, tape: Tape<io::Cursor<Vec<u8>>>) -> TapeRun<T> {
+        let tape = Tape {
+            backing: tape.compression.get_reader(tape.backing),
+            num_entries: tape.num_entries,
+            len_bytes: tape.len_bytes,
+            compression: tape.compression,
+            level: tape.level,
+        };
+        ExternalRun::from_tape(tape, self.read_buffer_items).into()
+    }
+}
+
+/// the number of reads the merge may have in flight at once
+#[cfg(all(target_os = "linux", feature = "direct_io"))]
+const RING_ENTRIES: u32 = 256;
+
 pub struct Tape<T> {
     num_entries: usize,
+    /// the length of the data on this tape in bytes.
+    /// The backing may be longer due to padding.
+    #[cfg_attr(not(all(target_os = "linux", feature = "direct_io")), allow(dead_code))]
+    len_bytes: u64,
     backing: T,
+    /// the codec the data on this tape was written with
+    compression: CompressionCodec,
+    /// how many times the data on this tape has been merged from other tapes.
+    level: u32,
 }
 
 impl<T> Tape<T> {
@@ -177,19 +806,338 @@ pub(crate) fn vec_to_tape<T>(mut data: Vec<T>) -> Tape<std::io::Cursor<Vec<u8>>>
     let mut backing = Vec::new();
     let num_entries = data.len();
 
-    fill_backing(&mut data, &mut backing, CompressionCodec::NoCompression).unwrap();
+    let len_bytes = fill_backing(
+        &mut data,
+        &mut backing,
+        CompressionCodec::NoCompression,
+        &Default::default(),
+        &TempQuota::unlimited(),
+        None,
+    )
+    .unwrap();
 
     Tape {
         backing: io::Cursor::new(backing),
         num_entries,
+        len_bytes,
+        compression: CompressionCodec::NoCompression,
+        level: 0,
     }
 }
 
-impl<T: Read + 'static + Send> Tape<T> {
-    fn box_backing(self, compression_choice: CompressionCodec) -> Tape<Box<dyn Read + Send>> {
+impl<T: Read + FileRegion + 'static + Send> Tape<T> {
+    /// prepares the tape for reading.
+    /// If enabled, the kernel is asked to read ahead the first
+    /// read_ahead_bytes of the tape, and to drop everything from the page cache
+    /// once it has been read.
+    /// All reads are subject to the rate limiter, if any.
+    #[cfg_attr(
+        not(any(target_os = "linux", target_os = "android")),
+        allow(unused_variables)
+    )]
+    fn box_backing(
+        self,
+        page_cache_hints: bool,
+        read_ahead_bytes: u64,
+        rate_limiter: Option<&IoRateLimiter>,
+    ) -> Tape<Box<dyn Read + Send>> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if page_cache_hints {
+            let file = self.backing.file();
+            let start = self.backing.start();
+            page_cache::advise(file, start, 0, page_cache::Advice::Sequential);
+            // requesting the whole tape at once would read all runs into memory,
+            // so we only ask for what the first read will need.
+            page_cache::advise(file, start, read_ahead_bytes, page_cache::Advice::WillNeed);
+            let reader = page_cache::EvictingReader::new(self.backing);
+            let reader = IoRateLimiter::wrap(rate_limiter, reader);
+            return Tape {
+                backing: self.compression.get_reader(reader),
+                num_entries: self.num_entries,
+                len_bytes: self.len_bytes,
+                compression: self.compression,
+                level: self.level,
+            };
+        }
+
+        let reader = IoRateLimiter::wrap(rate_limiter, self.backing);
         Tape {
-            backing: compression_choice.get_reader(self.backing),
+            backing: self.compression.get_reader(reader),
             num_entries: self.num_entries,
+            len_bytes: self.len_bytes,
+            compression: self.compression,
+            level: self.level,
         }
     }
+
+    /// maps the tape into memory to read its items in place.
+    /// returns the tape if it can not be mapped.
+    #[cfg(unix)]
+    fn try_map<I>(self) -> Result<MappedRun<I>, Self> {
+        // only uncompressed data of properly aligned,
+        // non-zero sized items can be read in place.
+        let mappable = matches!(self.compression, CompressionCodec::NoCompression)
+            && std::mem::size_of::<I>() > 0
+            && self
+                .backing
+                .start()
+                .is_multiple_of(std::mem::align_of::<I>() as u64);
+        if !mappable {
+            return Err(self);
+        }
+        // the mapping keeps the data alive, so the file can be closed afterwards.
+        MappedRun::new(self.backing.file(), self.backing.start(), self.num_entries)
+            .map_err(|_| self)
+    }
+
+    /// prepares the tape for reading, bypassing the page cache.
+    #[cfg(all(target_os = "linux", feature = "direct_io"))]
+    fn box_direct(
+        self,
+        chunk_size: usize,
+        ring: Option<direct::SharedRing>,
+        rate_limiter: Option<&IoRateLimiter>,
+    ) -> Tape<Box<dyn Read + Send>> {
+        let reader = direct::DirectReader::new(self.backing, self.len_bytes, chunk_size, ring);
+        let reader = IoRateLimiter::wrap(rate_limiter, reader);
+        Tape {
+            backing: self.compression.get_reader(reader),
+            num_entries: self.num_entries,
+            len_bytes: self.len_bytes,
+            compression: self.compression,
+            level: self.level,
+        }
+    }
+}
+
+#[cfg(all(test, not(miri)))]
+mod test {
+    use std::{
+        io::{self, Write},
+        num::NonZeroUsize,
+    };
+
+    use crate::{merge::LoserTree, orderer::OrdOrderer, ExtSortOrdExtension, ExtsortConfig};
+
+    use super::{compressor::CompressionCodec, fill_backing, quota::TempQuota, TapeCollection};
+
+    #[test]
+    fn test_compact() {
+        let config = ExtsortConfig::default().temp_file_folder(std::env::temp_dir());
+        let mut collection = TapeCollection::<u32>::new(&config, NonZeroUsize::new(4).unwrap());
+        let memory = NonZeroUsize::new(16).unwrap();
+        for run in 0..10u32 {
+            let mut items: Vec<u32> = (0..100).map(|i| i * 10 + run).collect();
+            collection.add_run(&mut items).unwrap();
+            collection.compact(&OrdOrderer::new(), 3, memory).unwrap();
+        }
+        // 9 runs were merged into one of level 2, the last one is on its own.
+        assert_eq!(None, collection.level_to_compact(2));
+        assert_eq!(Some(0), collection.level_to_compact(1));
+        let runs = collection.into_tapes(memory);
+        assert_eq!(2, runs.len());
+
+        let merged = LoserTree::new(runs, OrdOrderer::new());
+        assert!(merged.eq(0..1000));
+    }
+
+    #[test]
+    fn test_compact_refunds_quota() {
+        // the runs are merged 3 times, which would write 11200 bytes without refunds.
+        let config = ExtsortConfig::default()
+            .temp_file_folder(std::env::temp_dir())
+            .max_temp_bytes(8000);
+        let mut collection = TapeCollection::<u32>::new(&config, NonZeroUsize::new(16).unwrap());
+        let memory = NonZeroUsize::new(16).unwrap();
+        for run in 0..10u32 {
+            let mut items: Vec<u32> = (0..100).map(|i| i * 10 + run).collect();
+            collection.add_run(&mut items).unwrap();
+            collection.compact(&OrdOrderer::new(), 3, memory).unwrap();
+        }
+        assert_eq!(4000, collection.quota.remaining());
+        let runs = collection.into_tapes(memory);
+        assert!(LoserTree::new(runs, OrdOrderer::new()).eq(0..1000));
+    }
+
+    /// accepts a number of bytes, then fails all writes
+    struct FailingWriter {
+        capacity: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            let written = buf.len().min(self.capacity);
+            self.capacity -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// a writer on a disk with limited free space
+    pub(super) struct LimitedWriter<'a, W> {
+        pub inner: &'a mut W,
+        pub space: &'a mut u64,
+    }
+
+    impl<W: Write> Write for LimitedWriter<'_, W> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.len() as u64 > *self.space {
+                return Err(io::ErrorKind::StorageFull.into());
+            }
+            let written = self.inner.write(buf)?;
+            *self.space -= written as u64;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_fail_over_to_fallback_folder() {
+        let config = ExtsortConfig::default()
+            .temp_file_folder(std::env::temp_dir())
+            .fallback_temp_file_folder(std::env::temp_dir());
+        let mut collection = TapeCollection::<u64>::new(&config, NonZeroUsize::new(4).unwrap());
+        // the first folder only has room for one run
+        collection.free_space = vec![1000, u64::MAX];
+        for run in 0..3u64 {
+            let mut items: Vec<u64> = (0..100).map(|i| i * 3 + run).collect();
+            collection.add_run(&mut items).unwrap();
+        }
+        assert_eq!(1, collection.current_folder);
+        assert_eq!(vec![200, u64::MAX - 1600], collection.free_space);
+
+        let runs = collection.into_tapes(NonZeroUsize::new(64).unwrap());
+        let merged = LoserTree::new(runs, OrdOrderer::new());
+        assert!(merged.eq(0..300));
+    }
+
+    #[test]
+    fn test_disk_full() {
+        let config = ExtsortConfig::default().temp_file_folder(std::env::temp_dir());
+        let mut collection = TapeCollection::<u64>::new(&config, NonZeroUsize::new(4).unwrap());
+        collection.free_space = vec![1000];
+        collection.add_run(&mut vec![7; 100]).unwrap();
+
+        let mut items = vec![7; 100];
+        let result = collection.add_run(&mut items);
+        #[cfg(feature = "compression_lz4_flex")]
+        {
+            // the run is retried compressed, which fits into the remaining space
+            result.unwrap();
+            assert!(matches!(
+                collection.compression_choice,
+                CompressionCodec::Lz4Flex
+            ));
+            let runs = collection.into_tapes(NonZeroUsize::new(64).unwrap());
+            let merged = LoserTree::new(runs, OrdOrderer::new());
+            assert!(merged.eq(std::iter::repeat_n(7, 200)));
+        }
+        #[cfg(not(feature = "compression_lz4_flex"))]
+        {
+            assert_eq!(io::ErrorKind::StorageFull, result.unwrap_err().kind());
+            // the failed run is left untouched
+            assert_eq!(100, items.len());
+        }
+    }
+
+    #[test]
+    fn test_failed_write_is_released() {
+        let quota = TempQuota::new(Some(800));
+        let mut items: Vec<u64> = (0..100).collect();
+        let mut writer = FailingWriter { capacity: 500 };
+        let config = ExtsortConfig::default();
+        let codec = CompressionCodec::NoCompression;
+        fill_backing(
+            &mut items,
+            &mut writer,
+            codec,
+            &config.compression_pool(),
+            &quota,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(100, items.len());
+
+        // the whole limit is available again
+        let mut target = Vec::new();
+        fill_backing(
+            &mut items,
+            &mut target,
+            codec,
+            &config.compression_pool(),
+            &quota,
+            None,
+        )
+        .unwrap();
+        assert_eq!(800, target.len());
+    }
+
+    #[test]
+    fn test_group_clears_on_limit() {
+        let config = ExtsortConfig::default()
+            .temp_file_folder(std::env::temp_dir())
+            .max_temp_bytes(1000);
+        let count = NonZeroUsize::new(2).unwrap();
+        let mut group =
+            TapeCollection::<u64>::new_group(&config, NonZeroUsize::new(4).unwrap(), count);
+        let (first, second) = group.split_at_mut(1);
+        first[0].add_run(&mut vec![1; 100]).unwrap();
+        let err = second[0].add_run(&mut vec![2; 100]).unwrap_err();
+        assert_eq!(io::ErrorKind::QuotaExceeded, err.kind());
+
+        // the other collection releases its runs as soon as it tries to add another one
+        let mut items = vec![3; 10];
+        let err = first[0].add_run(&mut items).unwrap_err();
+        assert_eq!(io::ErrorKind::QuotaExceeded, err.kind());
+        assert_eq!(10, items.len());
+        assert!(first[0].plain_tapes.is_empty());
+    }
+
+    #[test]
+    fn test_spill_to_memory() {
+        // the folder does not exist, so any attempt to write a file would fail
+        let config = ExtsortConfig::with_buffer_size(64)
+            .temp_file_folder("/nonexistent")
+            .spill_to_memory(100_000);
+        let sorted = (0..1000).rev().external_sort(config).unwrap();
+        assert!(sorted.eq(0..1000));
+    }
+
+    #[test]
+    fn test_spill_to_memory_limit() {
+        let config = ExtsortConfig::with_buffer_size(64)
+            .temp_file_folder("/nonexistent")
+            .spill_to_memory(1000);
+        let err = (0..1000)
+            .rev()
+            .external_sort(config)
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(std::io::ErrorKind::QuotaExceeded, err.kind());
+    }
+
+    #[test]
+    #[cfg(feature = "compression_lz4_flex")]
+    fn test_spill_to_memory_compressed() {
+        let config = ExtsortConfig::with_buffer_size(4096)
+            .temp_file_folder("/nonexistent")
+            .compress_lz4_flex()
+            .spill_to_memory(100_000);
+        // highly compressible data, 4 times as large as the limit
+        let sorted = (0..50_000u64)
+            .rev()
+            .map(|i| i / 1000)
+            .external_sort(config)
+            .unwrap();
+        assert!(sorted.eq((0..50_000u64).map(|i| i / 1000)));
+    }
 }
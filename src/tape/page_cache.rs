@@ -0,0 +1,248 @@
+//! Hints to the kernel about how we access our sort files.
+//!
+//! Sort files are written once and then read once sequentially,
+//! so there is little point in keeping their contents in the page cache.
+
+use std::fs::File;
+
+use crate::run::split_backing::SplitView;
+
+/// A part of a file holding the data of a single tape
+pub trait FileRegion {
+    /// the file containing the region
+    fn file(&self) -> &File;
+    /// the offset of the first byte of the region in the file
+    fn start(&self) -> u64;
+}
+
+impl FileRegion for File {
+    fn file(&self) -> &File {
+        self
+    }
+    fn start(&self) -> u64 {
+        0
+    }
+}
+
+impl FileRegion for SplitView<File> {
+    fn file(&self) -> &File {
+        self.backing()
+    }
+    fn start(&self) -> u64 {
+        self.segment().start
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::{
+        fs::File,
+        io::{self, Read},
+        os::fd::AsRawFd,
+    };
+
+    use super::FileRegion;
+
+    /// do not bother the kernel for every small read.
+    const EVICTION_GRANULARITY: u64 = 1 << 20;
+
+    #[derive(Clone, Copy)]
+    pub enum Advice {
+        Sequential,
+        WillNeed,
+        DontNeed,
+    }
+
+    /// passes the advice for the provided range to the kernel.
+    /// a length of 0 extends the range to the end of the file.
+    ///
+    /// As these are just hints, failures are ignored.
+    pub fn advise(file: &File, offset: u64, len: u64, advice: Advice) {
+        let advice = match advice {
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+        };
+        let offset = libc::off_t::try_from(offset).unwrap_or(libc::off_t::MAX);
+        let len = libc::off_t::try_from(len).unwrap_or(libc::off_t::MAX);
+        // SAFETY: fadvise does not touch our memory and the fd is kept
+        // alive by the reference for the duration of the call.
+        unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), offset, len, advice);
+        }
+    }
+
+    /// Drops freshly written data from the page cache.
+    ///
+    /// Only clean pages can be dropped, so on linux we write the range back
+    /// and wait for it to reach the disk first. Elsewhere, only the pages
+    /// that were already written back are dropped.
+    /// a length of 0 extends the range to the end of the file.
+    pub fn evict_written(file: &File, offset: u64, len: u64) {
+        #[cfg(target_os = "linux")]
+        {
+            let start = libc::off64_t::try_from(offset).unwrap_or(libc::off64_t::MAX);
+            let num_bytes = libc::off64_t::try_from(len).unwrap_or(libc::off64_t::MAX);
+            // SAFETY: see advise
+            unsafe {
+                libc::sync_file_range(
+                    file.as_raw_fd(),
+                    start,
+                    num_bytes,
+                    libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                        | libc::SYNC_FILE_RANGE_WRITE
+                        | libc::SYNC_FILE_RANGE_WAIT_AFTER,
+                );
+            }
+        }
+        advise(file, offset, len, Advice::DontNeed);
+    }
+
+    /// A reader that drops the data it has read from the page cache.
+    pub struct EvictingReader<R: FileRegion> {
+        inner: R,
+        /// the position of the next byte to read in the file
+        position: u64,
+        /// everything before this offset has already been dropped
+        evicted_until: u64,
+    }
+
+    impl<R: FileRegion> EvictingReader<R> {
+        pub fn new(inner: R) -> Self {
+            let start = inner.start();
+            Self {
+                inner,
+                position: start,
+                evicted_until: start,
+            }
+        }
+
+        /// returns the range that was read but not evicted yet, if there is one.
+        /// The range is never empty, as an empty range would extend to the end of the file
+        /// and evict the data of the other runs in a shared file.
+        fn consumed_range(&self) -> Option<(u64, u64)> {
+            (self.position > self.evicted_until)
+                .then(|| (self.evicted_until, self.position - self.evicted_until))
+        }
+
+        fn evict_consumed(&mut self) {
+            if let Some((offset, len)) = self.consumed_range() {
+                advise(self.inner.file(), offset, len, Advice::DontNeed);
+                self.evicted_until = self.position;
+            }
+        }
+    }
+
+    impl<R: FileRegion + Read> Read for EvictingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let bytes_read = self.inner.read(buf)?;
+            self.position += bytes_read as u64;
+            if bytes_read == 0 || self.position - self.evicted_until >= EVICTION_GRANULARITY {
+                self.evict_consumed();
+            }
+            Ok(bytes_read)
+        }
+    }
+
+    impl<R: FileRegion> Drop for EvictingReader<R> {
+        fn drop(&mut self) {
+            // plain files have all their pages released once they are closed,
+            // but shared files stay open until all their runs are done.
+            self.evict_consumed();
+        }
+    }
+
+    #[cfg(all(test, not(miri)))]
+    mod test {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        use crate::{
+            run::split_backing::SplitView, tape::file, ExtSortOrdExtension, ExtsortConfig,
+        };
+
+        use super::{evict_written, EvictingReader};
+
+        #[test]
+        fn test_consumed_range() {
+            let mut file = file::create_file(&std::env::temp_dir(), 0).unwrap();
+            file.write_all(&[1; 300]).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let mut view = SplitView::new(file).unwrap();
+            let mut segment = view.add_segment().unwrap();
+            segment.write_all(&[2; 100]).unwrap();
+            let segment = SplitView::from(segment);
+
+            let mut reader = EvictingReader::new(segment);
+            assert_eq!(None, reader.consumed_range());
+            let mut buf = [0; 60];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(Some((300, 60)), reader.consumed_range());
+
+            let mut rest = Vec::new();
+            reader.read_to_end(&mut rest).unwrap();
+            assert_eq!(40, rest.len());
+            // everything read was evicted when the end was reached,
+            // and reading past the end does not evict the rest of the file.
+            assert_eq!(None, reader.consumed_range());
+            assert_eq!(0, reader.read(&mut buf).unwrap());
+            assert_eq!(None, reader.consumed_range());
+        }
+
+        /// the number of pages of the file that are in the page cache
+        fn resident_pages(file: &std::fs::File, len: usize) -> usize {
+            use std::os::fd::AsRawFd;
+
+            let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            let num_pages = len.div_ceil(page_size);
+            let mut residency = vec![0u8; num_pages];
+            // SAFETY: the mapping is only used for mincore and unmapped right after,
+            // residency holds one byte for every page of the mapping.
+            unsafe {
+                let addr = libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    libc::PROT_READ,
+                    libc::MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                );
+                assert_ne!(libc::MAP_FAILED, addr);
+                assert_eq!(0, libc::mincore(addr, len, residency.as_mut_ptr()));
+                libc::munmap(addr, len);
+            }
+            residency.iter().filter(|page| *page & 1 != 0).count()
+        }
+
+        #[test]
+        fn test_evict_written() {
+            let folder = std::env::temp_dir();
+            // the pages of in memory file systems can not be dropped
+            // SAFETY: statfs only writes to the provided struct.
+            let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+            let path = std::ffi::CString::new(folder.to_str().unwrap()).unwrap();
+            let res = unsafe { libc::statfs(path.as_ptr(), &mut stats) };
+            if res != 0 || stats.f_type == libc::TMPFS_MAGIC {
+                return;
+            }
+
+            let len = 4 << 20;
+            let mut file = file::create_file(&folder, 0).unwrap();
+            file.write_all(&vec![3; len]).unwrap();
+            assert!(resident_pages(&file, len) > 0);
+            evict_written(&file, 0, 0);
+            assert_eq!(0, resident_pages(&file, len));
+        }
+
+        #[test]
+        fn test_page_cache_hints() {
+            let config = ExtsortConfig::with_buffer_size(16)
+                .temp_file_folder(std::env::temp_dir())
+                .page_cache_hints(true);
+            let sorted = (0..2000).rev().external_sort(config).unwrap();
+            assert!(sorted.eq(0..2000));
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use linux::*;
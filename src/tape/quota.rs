@@ -0,0 +1,261 @@
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The error payload returned when a sort tries to write more data
+/// to its temp storage than configured using [`ExtsortConfig::max_temp_bytes`](crate::ExtsortConfig::max_temp_bytes).
+///
+/// It is wrapped in an [`io::Error`] of kind [`io::ErrorKind::QuotaExceeded`]
+/// and can be retrieved by downcasting the inner error:
+/// ```
+/// # use extsort_iter::TempLimitExceeded;
+/// fn spilled_bytes(err: &std::io::Error) -> Option<u64> {
+///     err.get_ref()?
+///         .downcast_ref::<TempLimitExceeded>()
+///         .map(|e| e.bytes_written)
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TempLimitExceeded {
+    /// the number of bytes that were written to temp storage before the limit was hit.
+    /// All of them have been released again by the time the error is returned.
+    pub bytes_written: u64,
+    /// the configured limit
+    pub limit: u64,
+}
+
+impl Display for TempLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the temp storage limit of {} bytes was exceeded after writing {} bytes",
+            self.limit, self.bytes_written
+        )
+    }
+}
+
+impl std::error::Error for TempLimitExceeded {}
+
+impl TempLimitExceeded {
+    /// checks if the provided error was caused by exceeding the temp storage limit
+    pub(crate) fn is_cause_of(err: &io::Error) -> bool {
+        err.get_ref()
+            .is_some_and(|inner| inner.is::<TempLimitExceeded>())
+    }
+}
+
+/// Keeps track of the number of bytes written to temp storage.
+///
+/// Clones share the count, so that several writers can be held to a common limit.
+#[derive(Clone)]
+pub(crate) struct TempQuota {
+    bytes_written: Arc<AtomicU64>,
+    /// set once any writer has hit the limit
+    exceeded: Arc<AtomicBool>,
+    limit: Option<u64>,
+}
+
+impl TempQuota {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            bytes_written: Arc::new(AtomicU64::new(0)),
+            exceeded: Arc::new(AtomicBool::new(false)),
+            limit,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn unlimited() -> Self {
+        Self::new(None)
+    }
+
+    /// wraps the provided writer so that all writes through it are
+    /// counted against this quota.
+    pub fn writer<'a, W: Write>(&'a self, inner: &'a mut W) -> QuotaWriter<'a, W> {
+        QuotaWriter {
+            inner,
+            quota: self,
+            bytes_written: 0,
+        }
+    }
+
+    fn charge(&self, num_bytes: usize) -> io::Result<()> {
+        let num_bytes = num_bytes as u64;
+        self.bytes_written
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                let new_total = current + num_bytes;
+                match self.limit {
+                    Some(limit) if new_total > limit => None,
+                    _ => Some(new_total),
+                }
+            })
+            .map(|_| ())
+            .map_err(|current| {
+                self.exceeded.store(true, Ordering::Relaxed);
+                self.limit_error(current)
+            })
+    }
+
+    /// fails if any writer sharing this quota has hit the limit,
+    /// after which the sort can not complete anymore.
+    pub fn check(&self) -> io::Result<()> {
+        if self.exceeded.load(Ordering::Relaxed) {
+            Err(self.limit_error(self.bytes_written.load(Ordering::Relaxed)))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn limit_error(&self, bytes_written: u64) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::QuotaExceeded,
+            TempLimitExceeded {
+                bytes_written,
+                limit: self.limit.unwrap_or(u64::MAX),
+            },
+        )
+    }
+
+    /// the number of bytes that may still be written before the limit is hit
+    pub fn remaining(&self) -> u64 {
+        let written = self.bytes_written.load(Ordering::Relaxed);
+        self.limit
+            .map_or(u64::MAX, |limit| limit.saturating_sub(written))
+    }
+
+    /// returns a charge that was not used
+    fn refund(&self, num_bytes: usize) {
+        self.release(num_bytes as u64);
+    }
+
+    /// returns the charge for bytes that were removed from temp storage again.
+    pub fn release(&self, num_bytes: u64) {
+        self.bytes_written.fetch_sub(num_bytes, Ordering::Relaxed);
+    }
+}
+
+/// A writer that refuses all writes that would take its quota over the limit.
+pub(crate) struct QuotaWriter<'a, W> {
+    inner: &'a mut W,
+    quota: &'a TempQuota,
+    /// the number of bytes written through this writer
+    bytes_written: u64,
+}
+
+impl<W> QuotaWriter<'_, W> {
+    /// the number of bytes written through this writer
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+}
+
+impl<W: Write> Write for QuotaWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.quota.charge(buf.len())?;
+        let written = self.inner.write(buf);
+        match written {
+            Ok(num_bytes) => {
+                // return the part of the charge that was not actually used.
+                self.quota.refund(buf.len() - num_bytes);
+                self.bytes_written += num_bytes as u64;
+            }
+            Err(_) => self.quota.refund(buf.len()),
+        }
+        written
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+
+    use crate::{ExtSortOrdExtension, ExtsortConfig};
+
+    use super::{TempLimitExceeded, TempQuota};
+
+    #[test]
+    fn test_limit() {
+        let quota = TempQuota::new(Some(10));
+        let mut target = Vec::new();
+
+        quota.writer(&mut target).write_all(&[0; 8]).unwrap();
+        let err = quota.writer(&mut target).write_all(&[0; 3]).unwrap_err();
+
+        assert_eq!(io::ErrorKind::QuotaExceeded, err.kind());
+        assert!(TempLimitExceeded::is_cause_of(&err));
+        let payload = err.into_inner().unwrap();
+        let payload = payload.downcast_ref::<TempLimitExceeded>().unwrap();
+        assert_eq!(8, payload.bytes_written);
+        assert_eq!(10, payload.limit);
+        assert_eq!(8, target.len());
+    }
+
+    #[test]
+    fn test_shared() {
+        let quota = TempQuota::new(Some(10));
+        let other = quota.clone();
+        let mut target = Vec::new();
+
+        let mut writer = quota.writer(&mut target);
+        writer.write_all(&[0; 6]).unwrap();
+        assert_eq!(6, writer.bytes_written());
+        let err = other.writer(&mut target).write_all(&[0; 6]).unwrap_err();
+        assert!(TempLimitExceeded::is_cause_of(&err));
+    }
+
+    #[test]
+    fn test_release() {
+        let quota = TempQuota::new(Some(10));
+        let mut target = Vec::new();
+        quota.writer(&mut target).write_all(&[0; 8]).unwrap();
+        assert_eq!(2, quota.remaining());
+        quota.release(8);
+        assert_eq!(10, quota.remaining());
+        quota.writer(&mut target).write_all(&[0; 10]).unwrap();
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let quota = TempQuota::unlimited();
+        let mut target = Vec::new();
+        quota.writer(&mut target).write_all(&[0; 1024]).unwrap();
+        assert_eq!(1024, target.len());
+        assert_eq!(u64::MAX, quota.remaining());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_temp_limit() {
+        let result = (0..1000)
+            .external_sort(
+                ExtsortConfig::with_buffer_size(64)
+                    .temp_file_folder(std::env::temp_dir())
+                    .max_temp_bytes(1024),
+            )
+            .map(|_| ());
+        let err = result.unwrap_err();
+        assert_eq!(io::ErrorKind::QuotaExceeded, err.kind());
+        let payload = err.get_ref().unwrap().downcast_ref::<TempLimitExceeded>();
+        assert_eq!(1024, payload.unwrap().bytes_written);
+
+        let sorted = (0..1000)
+            .rev()
+            .external_sort(
+                ExtsortConfig::with_buffer_size(64)
+                    .temp_file_folder(std::env::temp_dir())
+                    .max_temp_bytes(4000),
+            )
+            .unwrap();
+        assert!(sorted.eq(0..1000));
+    }
+}
@@ -0,0 +1,287 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// the largest amount of data we pass through at once, so that
+/// a single large write does not put us in debt for a long time.
+const MAX_CHUNK_BYTES: usize = 256 * 1024;
+
+/// the longest we sleep before checking if the rate has been changed.
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+/// Limits the bandwidth the sort uses for its temp storage.
+///
+/// The limit applies to the runs written to disk while sorting and
+/// to the runs read back during the merge, and is shared between both.
+/// It is implemented as a token bucket that allows bursts of up to one second worth of data.
+///
+/// Clones share the limit, so a clone can be kept to adjust
+/// the rate while the sort is running:
+/// ```
+/// # use extsort_iter::*;
+/// let limiter = IoRateLimiter::new(64 * 1024 * 1024);
+/// let config = ExtsortConfig::default().io_rate_limit(limiter.clone());
+/// // later, when the machine is less busy
+/// limiter.set_bytes_per_second(0);
+/// ```
+#[derive(Clone, Debug)]
+pub struct IoRateLimiter {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// the number of bytes we may transfer per second, 0 if unlimited
+    bytes_per_second: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// the number of bytes that can be transferred right now.
+    /// Negative while we are transferring faster than allowed.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl IoRateLimiter {
+    /// Creates a limiter allowing the provided number of bytes per second.
+    /// A rate of 0 does not limit the bandwidth at all.
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                bytes_per_second: AtomicU64::new(bytes_per_second),
+                bucket: Mutex::new(Bucket {
+                    tokens: 0.0,
+                    last_refill: Instant::now(),
+                }),
+            }),
+        }
+    }
+
+    /// Changes the number of bytes allowed per second.
+    /// This takes effect immediately, even for a sort that is currently running.
+    /// A rate of 0 removes the limit.
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        self.inner
+            .bytes_per_second
+            .store(bytes_per_second, Ordering::Relaxed);
+    }
+
+    /// The number of bytes currently allowed per second, 0 if unlimited.
+    pub fn bytes_per_second(&self) -> u64 {
+        self.inner.bytes_per_second.load(Ordering::Relaxed)
+    }
+
+    /// Takes the provided number of bytes from the bucket,
+    /// blocking until the bucket is no longer in debt.
+    fn acquire(&self, num_bytes: usize) {
+        let mut num_bytes = num_bytes;
+        loop {
+            let rate = self.bytes_per_second();
+            let wait = self
+                .inner
+                .bucket
+                .lock()
+                .unwrap()
+                .take(Instant::now(), rate, num_bytes);
+            // the transfer is only paid for once
+            num_bytes = 0;
+            let Some(wait) = wait else {
+                return;
+            };
+            // the rate may change while we wait, so we check back regularly.
+            std::thread::sleep(wait.min(MAX_SLEEP));
+        }
+    }
+
+    /// wraps the provided reader or writer so that all transfers
+    /// through it are subject to the limit.
+    pub(crate) fn wrap<T>(limiter: Option<&IoRateLimiter>, inner: T) -> Throttled<T> {
+        Throttled {
+            inner,
+            limiter: limiter.cloned(),
+        }
+    }
+}
+
+impl Bucket {
+    /// refills the bucket for the time passed since the last refill
+    /// and takes the provided number of bytes from it.
+    /// Returns how long to wait until the bucket is no longer in debt, if it is.
+    fn take(&mut self, now: Instant, rate: u64, num_bytes: usize) -> Option<Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.last_refill = now;
+        if rate == 0 {
+            // the debt does not carry over to a later limit.
+            self.tokens = 0.0;
+            return None;
+        }
+        // at most one second worth of transfers can be saved up.
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        self.tokens -= num_bytes as f64;
+        if self.tokens >= 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(-self.tokens / rate as f64))
+        }
+    }
+}
+
+/// A reader or writer that transfers data no faster than its limiter allows.
+pub(crate) struct Throttled<T> {
+    inner: T,
+    limiter: Option<IoRateLimiter>,
+}
+
+impl<T> Throttled<T> {
+    /// the largest transfer allowed at once
+    fn chunk_len(&self, len: usize) -> usize {
+        match &self.limiter {
+            Some(_) => len.min(MAX_CHUNK_BYTES),
+            None => len,
+        }
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.chunk_len(buf.len());
+        let bytes_read = self.inner.read(&mut buf[..len])?;
+        if let Some(limiter) = &self.limiter {
+            // we only know how much was read afterwards, so the
+            // read is paid for before the next one.
+            limiter.acquire(bytes_read);
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.chunk_len(buf.len());
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(len);
+        }
+        self.inner.write(&buf[..len])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        time::{Duration, Instant},
+    };
+
+    use crate::{ExtSortOrdExtension, ExtsortConfig};
+
+    use super::{Bucket, IoRateLimiter};
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            last_refill: start,
+        };
+        // the transfer puts us in debt for half a second
+        assert_eq!(
+            Some(Duration::from_millis(500)),
+            bucket.take(at(0), 1000, 500)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(250)),
+            bucket.take(at(250), 1000, 0)
+        );
+        assert_eq!(None, bucket.take(at(500), 1000, 0));
+
+        // at most one second worth of transfers is saved up
+        assert_eq!(None, bucket.take(at(10_000), 1000, 0));
+        assert_eq!(1000.0, bucket.tokens);
+        assert_eq!(None, bucket.take(at(10_000), 1000, 1000));
+        assert_eq!(
+            Some(Duration::from_millis(100)),
+            bucket.take(at(10_000), 1000, 100)
+        );
+
+        // the rate applies from the next refill on
+        assert_eq!(
+            Some(Duration::from_millis(50)),
+            bucket.take(at(10_000), 2000, 0)
+        );
+
+        // lifting the limit clears the debt
+        assert_eq!(None, bucket.take(at(10_000), 0, 1_000_000));
+        assert_eq!(None, bucket.take(at(10_000), 1000, 0));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        // a rate high enough to not slow the test down
+        let limiter = IoRateLimiter::new(1 << 40);
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| i as u8).collect();
+        let mut target = Vec::new();
+        IoRateLimiter::wrap(Some(&limiter), &mut target)
+            .write_all(&data)
+            .unwrap();
+        assert_eq!(data, target);
+
+        let mut read_back = Vec::new();
+        IoRateLimiter::wrap(Some(&limiter), &target[..])
+            .read_to_end(&mut read_back)
+            .unwrap();
+        assert_eq!(data, read_back);
+    }
+
+    #[test]
+    fn test_adjust() {
+        let limiter = IoRateLimiter::new(1);
+        let handle = limiter.clone();
+        let writer = std::thread::spawn(move || {
+            let mut target = Vec::new();
+            IoRateLimiter::wrap(Some(&limiter), &mut target)
+                .write_all(&[0; 1_000_000])
+                .unwrap();
+            target.len()
+        });
+        // without lifting the limit, this would take days
+        handle.set_bytes_per_second(0);
+        assert_eq!(1_000_000, writer.join().unwrap());
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut target = Vec::new();
+        let mut writer = IoRateLimiter::wrap(None, &mut target);
+        assert_eq!(1_000_000, writer.write(&[0; 1_000_000]).unwrap());
+        drop(writer);
+        assert_eq!(1_000_000, target.len());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn test_io_rate_limit() {
+        // the timing is covered by the tests of the limiter itself
+        let limiter = IoRateLimiter::new(1 << 40);
+        let config = ExtsortConfig::with_buffer_size(4096)
+            .temp_file_folder(std::env::temp_dir())
+            .memory_map_runs(true)
+            .io_rate_limit(limiter.clone());
+        let sorted = (0..10_000u64).rev().external_sort(config).unwrap();
+        assert!(sorted.eq(0..10_000));
+        assert_eq!(1 << 40, limiter.bytes_per_second());
+    }
+}